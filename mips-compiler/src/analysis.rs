@@ -0,0 +1,526 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{is_logic_type, strip_comment, tokenize, unknown_name_message, ChipProfile, Diagnostic, InstructionPointer, RegisterOrDevice, Span, Token, LOGIC_TYPES};
+
+//
+
+/// What an operand of an opcode is expected to be
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum OperandKind
+{
+    /// a register to write, or an alias of one
+    LValue,
+    /// a number, register, define, label, or alias of a register
+    RValue,
+    /// a device pin, or an alias of one
+    Device,
+    /// the name of a logic type such as `Pressure`
+    LogicType,
+    /// a line number or label
+    Target,
+    /// an offset from the current line
+    RelativeTarget,
+    /// the name declared by `alias` or `define`
+    Name,
+    /// the register or device pin named by `alias`
+    RegisterOrDevice,
+    /// the value given by `define`
+    Number,
+}
+
+/// Where control goes after an opcode
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Flow
+{
+    /// on to the next line
+    Next,
+    /// always to the target
+    Jump,
+    /// to the target or the next line
+    Branch,
+    /// to the current line plus an offset, or the next line
+    RelativeBranch,
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct OpcodeInfo
+{
+    pub operands: &'static [OperandKind],
+    pub flow: Flow,
+}
+
+/// the operands and control flow of each opcode `parse_one_line` understands
+pub fn opcode_info(opcode:&str) -> Option<OpcodeInfo>
+{
+    use self::OperandKind::*;
+    let (operands, flow): (&'static [OperandKind], Flow) = match opcode {
+        "j" => (&[Target], Flow::Jump),
+        // the subroutine comes back to the next line
+        "jal" => (&[Target], Flow::Branch),
+        "alias" => (&[Name, RegisterOrDevice], Flow::Next),
+        "define" => (&[Name, Number], Flow::Next),
+        "s" => (&[Device, LogicType, RValue], Flow::Next),
+        "l" => (&[LValue, Device, LogicType], Flow::Next),
+        "yield" => (&[], Flow::Next),
+        "move" | "abs" | "ceil" | "exp" | "floor" | "log" | "round" | "sqrt" | "trunc" =>
+            (&[LValue, RValue], Flow::Next),
+        "add" | "sub" | "mul" | "div" | "mod" | "max" | "min" | "slt" | "sgt" | "and" | "nor" | "or" | "xor" =>
+            (&[LValue, RValue, RValue], Flow::Next),
+        "rand" => (&[LValue], Flow::Next),
+        "select" => (&[LValue, RValue, RValue, RValue], Flow::Next),
+        "bap" | "bapal" => (&[RValue, RValue, RValue, Target], Flow::Branch),
+        "beq" | "beqal" | "bgt" => (&[RValue, RValue, Target], Flow::Branch),
+        "bdns" | "bdnsal" | "bdse" | "bdseal" => (&[Device, Target], Flow::Branch),
+        "brdns" | "brdse" => (&[Device, RelativeTarget], Flow::RelativeBranch),
+        _ => return None,
+    };
+    Some(OpcodeInfo { operands, flow })
+}
+
+//
+
+/// one line of code, split into its opcode and operands
+pub struct Statement<'a>
+{
+    pub opcode: Token<'a>,
+    pub operands: Vec<Token<'a>>,
+    pub info: OpcodeInfo,
+}
+
+impl<'a> Statement<'a>
+{
+    pub fn parse(line:&'a str) -> Option<Statement<'a>>
+    {
+        if strip_comment(line).contains(':') {
+            return None;
+        }
+        let mut tokens = tokenize(line).into_iter();
+        let opcode = tokens.next()?;
+        let info = opcode_info(opcode.text)?;
+        let operands: Vec<Token> = tokens.collect();
+        if operands.len() != info.operands.len() {
+            return None;
+        }
+        Some(Statement { opcode, operands, info })
+    }
+
+    /// each operand paired with what it is expected to be
+    pub fn typed_operands(&self) -> impl Iterator<Item=(OperandKind, &Token<'a>)>
+    {
+        self.info.operands.iter().copied().zip(self.operands.iter())
+    }
+
+    pub fn is(&self, opcode:&str) -> bool
+    {
+        self.opcode.text == opcode
+    }
+
+    /// whether the opcode stores the next line in `ra` when it jumps
+    pub fn links(&self) -> bool
+    {
+        matches!(self.opcode.text, "jal" | "bapal" | "beqal" | "bdnsal" | "bdseal")
+    }
+}
+
+//
+
+/// A line-by-line view of the source, for analyses that need to know what each operand is
+/// and where control can go.
+pub struct SourceModel<'a>
+{
+    pub lines: Vec<&'a str>,
+    /// `None` for blank lines, labels, and lines that failed to compile
+    pub statements: Vec<Option<Statement<'a>>>,
+    pub labels: HashMap<String, InstructionPointer>,
+    /// every name declared by `alias` or `define` somewhere in the program
+    pub declared_names: HashSet<String>,
+}
+
+impl<'a> SourceModel<'a>
+{
+    pub fn new(lines: &[&'a str], labels: &HashMap<String, InstructionPointer>, failed_lines: &HashSet<usize>) -> SourceModel<'a>
+    {
+        let statements: Vec<Option<Statement>> = lines.iter().enumerate()
+            .map(|(idx, line)| if failed_lines.contains(&idx) { None } else { Statement::parse(line) })
+            .collect();
+        let declared_names = statements.iter().flatten()
+            .filter(|st| st.is("alias") || st.is("define"))
+            .map(|st| st.operands[0].text.to_string())
+            .collect();
+        SourceModel {
+            lines: lines.to_vec(),
+            statements,
+            labels: labels.clone(),
+            declared_names,
+        }
+    }
+
+    /// a name that the compiler turns into a line number
+    pub fn is_label(&self, name:&str) -> bool
+    {
+        self.labels.contains_key(name) && !self.declared_names.contains(name)
+    }
+
+    fn target_value(&self, token:&Token) -> Option<usize>
+    {
+        match token.text.parse::<InstructionPointer>() {
+            Ok(number) => Some(number as usize),
+            Err(_) => self.labels.get(token.text).map(|&n| n as usize),
+        }
+    }
+
+    /// the register a jump on `line` takes its target from, as in `j ra`
+    pub fn register_target(&self, line:usize) -> Option<&Token<'a>>
+    {
+        let statement = self.statements[line].as_ref()?;
+        let token = statement.operands.last()?;
+        let is_target = statement.info.operands.last() == Some(&OperandKind::Target);
+        (is_target && (token.text == "ra" || register_index(token.text).is_some())).then_some(token)
+    }
+
+    /// whether any jump goes to a line held in a register, so that `successors` has to guess where
+    pub fn has_register_jumps(&self) -> bool
+    {
+        (0..self.lines.len()).any(|line| self.register_target(line).is_some())
+    }
+
+    /// the line a branch on `line` goes to when it is taken, if it is known before the program runs
+    pub fn branch_target(&self, line:usize) -> Option<usize>
+    {
+        let statement = self.statements[line].as_ref()?;
+        let token = statement.operands.last()?;
+        if self.register_target(line).is_some() {
+            return None;
+        }
+        match statement.info.flow {
+            Flow::Next => None,
+            Flow::Jump | Flow::Branch => self.target_value(token),
+            Flow::RelativeBranch => self.target_value(token).map(|offset| line+offset),
+        }
+    }
+
+    /// the lines control can reach directly from `line`
+    pub fn successors(&self, line:usize) -> Vec<usize>
+    {
+        let flow = match &self.statements[line] {
+            Some(statement) => statement.info.flow,
+            None => Flow::Next,
+        };
+        let mut rval = Vec::new();
+        if flow != Flow::Jump {
+            rval.push(line+1);
+        }
+        rval.extend(self.branch_target(line));
+        if let Some(register) = self.register_target(line) {
+            // a register can hold any label, and `ra` the line after any jump that links
+            rval.extend(self.labels.iter().filter(|(name, _)| self.is_label(name)).map(|(_, &target)| target as usize));
+            if register.text == "ra" {
+                rval.extend(self.statements.iter().enumerate()
+                    .filter(|(_, statement)| statement.as_ref().is_some_and(|st| st.links()))
+                    .map(|(l, _)| l+1));
+            }
+        }
+        rval.sort_unstable();
+        rval.dedup();
+        rval.retain(|&l| l < self.lines.len());
+        rval
+    }
+
+    /// which lines can be reached by starting at line 0
+    pub fn reachable(&self) -> Vec<bool>
+    {
+        let mut rval = vec![false; self.lines.len()];
+        let mut work = vec![0];
+        while let Some(line) = work.pop() {
+            if line >= rval.len() || rval[line] {
+                continue;
+            }
+            rval[line] = true;
+            work.extend(self.successors(line));
+        }
+        rval
+    }
+
+    pub fn span(&self, line:usize, token:&Token) -> Span
+    {
+        Span::new(line, token.start, token.end)
+    }
+}
+
+//
+
+/// what a name may refer to at some point in the program, over all the paths that reach it
+#[derive(Copy,Clone,Debug,Default,PartialEq,Eq)]
+pub struct Binding
+{
+    pub register: bool,
+    pub device: bool,
+    pub value: bool,
+    /// there is a path on which the name has not been declared yet
+    pub unbound: bool,
+}
+
+impl Binding
+{
+    fn union(self, other:Binding) -> Binding
+    {
+        Binding {
+            register: self.register || other.register,
+            device: self.device || other.device,
+            value: self.value || other.value,
+            unbound: self.unbound || other.unbound,
+        }
+    }
+}
+
+pub type Bindings = HashMap<String, Binding>;
+
+/// merge the bindings from another path into `dst`, returning true if `dst` changed
+fn join_into(dst:&mut Bindings, src:&Bindings) -> bool
+{
+    let mut changed = false;
+    for (name, binding) in dst.iter_mut() {
+        if !binding.unbound && !src.contains_key(name) {
+            binding.unbound = true;
+            changed = true;
+        }
+    }
+    for (name, binding) in src {
+        match dst.get_mut(name) {
+            Some(old) => {
+                let merged = old.union(*binding);
+                if merged != *old {
+                    *old = merged;
+                    changed = true;
+                }
+            },
+            None => {
+                dst.insert(name.clone(), Binding { unbound: true, ..*binding });
+                changed = true;
+            },
+        }
+    }
+    changed
+}
+
+fn apply_declaration(statement:&Statement, state:&mut Bindings)
+{
+    let binding = if statement.is("alias") {
+        match RegisterOrDevice::parse(statement.operands[1].text) {
+            Ok(RegisterOrDevice::Register(_)) => Binding { register: true, ..Binding::default() },
+            Ok(RegisterOrDevice::Device(_)) => Binding { device: true, ..Binding::default() },
+            Err(_) => return,
+        }
+    } else if statement.is("define") {
+        Binding { value: true, ..Binding::default() }
+    } else {
+        return;
+    };
+    state.insert(statement.operands[0].text.to_string(), binding);
+}
+
+/// The aliases and defines in effect at the start of each line, following the control flow from line 0.
+/// Unreachable lines get `None`.
+pub fn binding_states(model:&SourceModel) -> Vec<Option<Bindings>>
+{
+    let mut states: Vec<Option<Bindings>> = vec![None; model.lines.len()];
+    if states.is_empty() {
+        return states;
+    }
+    states[0] = Some(Bindings::new());
+    let mut work = vec![0];
+    while let Some(line) = work.pop() {
+        let mut out = states[line].clone().unwrap_or_default();
+        if let Some(statement) = &model.statements[line] {
+            apply_declaration(statement, &mut out);
+        }
+        for next in model.successors(line) {
+            let changed = match &mut states[next] {
+                Some(old) => join_into(old, &out),
+                slot => {
+                    *slot = Some(out.clone());
+                    true
+                },
+            };
+            if changed {
+                work.push(next);
+            }
+        }
+    }
+    states
+}
+
+//
+
+#[derive(Copy,Clone,PartialEq)]
+enum Expect
+{
+    Value,
+    Register,
+    Device,
+}
+
+impl Expect
+{
+    fn description(self) -> &'static str
+    {
+        match self {
+            Expect::Value => "a value",
+            Expect::Register => "a register to write",
+            Expect::Device => "a device",
+        }
+    }
+
+    fn accepts(self, binding:&Binding) -> bool
+    {
+        match self {
+            Expect::Value => binding.register || binding.value,
+            Expect::Register => binding.register,
+            Expect::Device => binding.device,
+        }
+    }
+
+    /// what else `binding` might be, that this position does not accept
+    fn rejected(self, binding:&Binding) -> Vec<&'static str>
+    {
+        let mut rval = Vec::new();
+        if binding.register && self == Expect::Device {
+            rval.push("register alias");
+        }
+        if binding.device && self != Expect::Device {
+            rval.push("device alias");
+        }
+        if binding.value && self != Expect::Value {
+            rval.push("define");
+        }
+        rval
+    }
+}
+
+fn register_index(text:&str) -> Option<u8>
+{
+    text.strip_prefix('r')?.parse::<u8>().ok()
+}
+
+struct Checker<'m, 'a>
+{
+    model: &'m SourceModel<'a>,
+    profile: &'m ChipProfile,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'m, 'a> Checker<'m, 'a>
+{
+    fn check_register(&mut self, span:Span, idx:u8)
+    {
+        if !self.profile.has_register(idx) {
+            self.diagnostics.push(Diagnostic::error(span, &format!("register r{} does not exist; this chip has r0 to r{}",
+                                                                   idx, self.profile.registers as i32 - 1)));
+        }
+    }
+
+    fn check_device(&mut self, span:Span, dev:crate::Device)
+    {
+        if let crate::Device::Regular(idx) = dev {
+            if !self.profile.has_device_pin(idx) {
+                self.diagnostics.push(Diagnostic::error(span, &format!("device d{} does not exist; this chip has d0 to d{}",
+                                                                       idx, self.profile.device_pins as i32 - 1)));
+            }
+        }
+    }
+
+    fn check_name(&mut self, span:Span, name:&str, state:&Bindings, expect:Expect)
+    {
+        let binding = match state.get(name) {
+            Some(binding) => binding,
+            None => {
+                let msg = if self.model.declared_names.contains(name) {
+                    format!("'{}' is used before it is defined", name)
+                } else {
+                    format!("'{}' is not an alias, define or label", name)
+                };
+                self.diagnostics.push(Diagnostic::error(span, &msg));
+                return;
+            }
+        };
+        let rejected = expect.rejected(binding);
+        if !expect.accepts(binding) {
+            self.diagnostics.push(Diagnostic::error(span, &format!("'{}' is a {} and can not be used as {}",
+                                                                   name, rejected.join(" or "), expect.description())));
+        } else if !rejected.is_empty() {
+            self.diagnostics.push(Diagnostic::warning(span, &format!("'{}' may be a {} here, which can not be used as {}",
+                                                                     name, rejected.join(" or "), expect.description())));
+        }
+        if binding.unbound {
+            self.diagnostics.push(Diagnostic::warning(span, &format!("'{}' may be used before it is defined", name)));
+        }
+    }
+
+    fn check_operand(&mut self, line:usize, kind:OperandKind, token:&Token, state:Option<&Bindings>)
+    {
+        let span = self.model.span(line, token);
+        let text = token.text;
+        match kind {
+            OperandKind::RValue => {
+                if text.parse::<f32>().is_ok() || self.model.is_label(text) {
+                } else if let Some(idx) = register_index(text) {
+                    self.check_register(span, idx);
+                } else if crate::Device::parse(text).is_ok() {
+                    self.diagnostics.push(Diagnostic::error(span, &format!("device {} can not be used as a value", text)));
+                } else if let Some(state) = state {
+                    self.check_name(span, text, state, Expect::Value);
+                }
+            },
+            OperandKind::LValue => {
+                if let Some(idx) = register_index(text) {
+                    self.check_register(span, idx);
+                } else if self.model.is_label(text) {
+                    self.diagnostics.push(Diagnostic::error(span, &format!("label '{}' can not be written", text)));
+                } else if let Some(state) = state {
+                    self.check_name(span, text, state, Expect::Register);
+                }
+            },
+            OperandKind::Device => {
+                if let Ok(dev) = crate::Device::parse(text) {
+                    self.check_device(span, dev);
+                } else if self.model.is_label(text) {
+                    self.diagnostics.push(Diagnostic::error(span, &format!("label '{}' is not a device", text)));
+                } else if let Some(state) = state {
+                    self.check_name(span, text, state, Expect::Device);
+                }
+            },
+            OperandKind::LogicType if !is_logic_type(text) => {
+                self.diagnostics.push(Diagnostic::error(span, &unknown_name_message("logic type", text, LOGIC_TYPES)));
+            },
+            OperandKind::RegisterOrDevice => {
+                match RegisterOrDevice::parse(text) {
+                    Ok(RegisterOrDevice::Register(_)) => {
+                        if let Some(idx) = register_index(text) {
+                            self.check_register(span, idx);
+                        }
+                    },
+                    Ok(RegisterOrDevice::Device(dev)) => self.check_device(span, dev),
+                    Err(_) => {},
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Check that every operand is the right sort of thing: registers and device pins exist on the chip,
+/// logic types are ones the game knows, and aliases and defines refer to a register, device or value as the opcode requires
+/// on every path that reaches them.
+pub fn check_operand_types(model:&SourceModel, profile:&ChipProfile) -> Vec<Diagnostic>
+{
+    let states = binding_states(model);
+    let mut checker = Checker { model, profile, diagnostics: Vec::new() };
+    for (line, statement) in model.statements.iter().enumerate() {
+        if let Some(statement) = statement {
+            for (kind, token) in statement.typed_operands() {
+                checker.check_operand(line, kind, token, states[line].as_ref());
+            }
+        }
+    }
+    checker.diagnostics
+}
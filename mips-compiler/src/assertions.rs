@@ -0,0 +1,238 @@
+use std::fmt::{Formatter, Error};
+
+use crate::{CPUContext, Device, ExecutionError, Register, RegisterOrDevice};
+
+/// How close a value has to be to the one expected.  NaN only matches NaN.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct Tolerance
+{
+    pub absolute: f32,
+    /// as a fraction of the expected value
+    pub relative: f32,
+}
+
+impl Tolerance
+{
+    pub const EXACT: Tolerance = Tolerance { absolute: 0.0, relative: 0.0 };
+
+    pub fn absolute(absolute:f32) -> Tolerance
+    {
+        Tolerance { absolute, relative: 0.0 }
+    }
+
+    pub fn relative(relative:f32) -> Tolerance
+    {
+        Tolerance { absolute: 0.0, relative }
+    }
+
+    /// within either tolerance
+    pub fn accepts(&self, expected:f32, actual:f32) -> bool
+    {
+        if expected.is_nan() || actual.is_nan() {
+            return expected.is_nan() && actual.is_nan();
+        }
+        expected == actual
+            || (actual - expected).abs() <= self.absolute.max(self.relative * expected.abs())
+    }
+}
+
+/// a bare number is an absolute tolerance
+impl From<f32> for Tolerance
+{
+    fn from(absolute: f32) -> Self {
+        Tolerance::absolute(absolute)
+    }
+}
+
+impl std::fmt::Display for Tolerance
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let (a, r) = (self.absolute, self.relative);
+        if a == 0.0 && r == 0.0 {
+            write!(f, "exactly")
+        } else if r == 0.0 {
+            write!(f, "within {}", a)
+        } else if a == 0.0 {
+            write!(f, "within {}%", r * 100.0)
+        } else {
+            write!(f, "within {} or {}%", a, r * 100.0)
+        }
+    }
+}
+
+//
+
+/// Checks for tests, failing with where the program is and what it last wrote to its devices.
+/// Devices are named by alias, or as `d0`..`d5` and `db`.
+/// The `assert_*!` macros call these and panic with the message.
+impl CPUContext
+{
+    pub fn check_register<T:Into<Tolerance>>(&self, reg:Register, expected:f32, tolerance:T) -> Result<(), ExecutionError>
+    {
+        let actual = self.register_reference(reg)?;
+        self.check(&reg.to_string(), expected, actual, tolerance.into())
+    }
+
+    /// the register alias or define `name`
+    pub fn check_alias<T:Into<Tolerance>>(&self, name:&str, expected:f32, tolerance:T) -> Result<(), ExecutionError>
+    {
+        let actual = self.alias_value(name)?;
+        let what = match self.aliases.get(name) {
+            Some(reg) => format!("{} ({})", name, reg),
+            None => name.to_string(),
+        };
+        self.check(&what, expected, actual, tolerance.into())
+    }
+
+    pub fn check_device_field<T:Into<Tolerance>>(&self, device:&str, field:&str, expected:f32, tolerance:T) -> Result<(), ExecutionError>
+    {
+        let (what, actual) = self.device_field_for_check(device, field)?;
+        self.check(&what, expected, actual, tolerance.into())
+    }
+
+    /// the field is non-zero (and not NaN) when `expected` is true, or zero when it is false
+    pub fn check_device_flag(&self, device:&str, field:&str, expected:bool) -> Result<(), ExecutionError>
+    {
+        let (what, actual) = self.device_field_for_check(device, field)?;
+        if (actual != 0.0 && !actual.is_nan()) == expected {
+            Ok(())
+        } else {
+            Err(self.check_failure(&format!("{} is {}, expected {}", what, actual, if expected { "non-zero" } else { "0" })))
+        }
+    }
+
+    /// the value stored in a device field, with the device named as for `check_device_field`
+    pub fn device_field(&self, device:&str, field:&str) -> Result<f32, ExecutionError>
+    {
+        self.device_field_for_check(device, field).map(|(_, val)| val)
+    }
+
+    fn device_field_for_check(&self, device:&str, field:&str) -> Result<(String, f32), ExecutionError>
+    {
+        let (dev, what) = self.device_for_check(device)?;
+        let state = match dev {
+            Device::Regular(idx) => self.devices.get(idx as usize).and_then(|d| d.as_ref()),
+            Device::SpecialB => Some(&self.device_b),
+        };
+        match state.map(|s| s.get(field)) {
+            None => Err(self.check_failure(&format!("no device attached to {}", what))),
+            Some(None) => Err(self.check_failure(&format!("{} has no {}", what, field))),
+            Some(Some(&val)) => Ok((format!("{} {}", what, field), val)),
+        }
+    }
+
+    /// the value stored in a field of one of a device's slots, with the device named as for `check_device_field`
+    pub fn slot_field(&self, device:&str, slot:usize, field:&str) -> Result<f32, ExecutionError>
+    {
+        let (dev, what) = self.device_for_check(device)?;
+        let idx = match dev {
+            Device::Regular(idx) => idx as usize,
+            Device::SpecialB => return Err(self.check_failure("db has no slots")),
+        };
+        if !matches!(self.devices.get(idx), Some(Some(_))) {
+            return Err(self.check_failure(&format!("no device attached to {}", what)));
+        }
+        match self.slots[idx].get(slot).and_then(|s| s.get(field)) {
+            Some(&val) => Ok(val),
+            None => Err(self.check_failure(&format!("{} slot {} has no {}", what, slot, field))),
+        }
+    }
+
+    /// a device named by pin or alias, and how to describe it
+    fn device_for_check(&self, device:&str) -> Result<(Device, String), ExecutionError>
+    {
+        match Device::parse(device) {
+            Ok(dev) => Ok((dev, device.to_string())),
+            Err(_) => match self.aliases.get(device) {
+                Some(RegisterOrDevice::Device(dev)) => Ok((*dev, format!("{} ({})", device, dev))),
+                _ => Err(self.check_failure(&format!("{} is not a device", device))),
+            },
+        }
+    }
+
+    fn check(&self, what:&str, expected:f32, actual:f32, tolerance:Tolerance) -> Result<(), ExecutionError>
+    {
+        if tolerance.accepts(expected, actual) {
+            Ok(())
+        } else {
+            Err(self.check_failure(&format!("{} is {}, expected {} {}", what, actual, tolerance, expected)))
+        }
+    }
+
+    /// `message`, then the line the program is on and the writes that led up to it
+    fn check_failure(&self, message:&str) -> ExecutionError
+    {
+        let mut rval = message.to_string();
+        let ip = self.instruction_pointer;
+        match self.program.as_ref().and_then(|p| p.get_instruction(ip)) {
+            Some(op) => rval.push_str(&format!("\nat line {}: {}", ip, op)),
+            None => rval.push_str(&format!("\nat line {}", ip)),
+        }
+        if !self.device_writes.is_empty() {
+            rval.push_str("\nrecent device writes:");
+            for write in &self.device_writes {
+                rval.push_str(&format!("\n  {}", write));
+            }
+        }
+        ExecutionError::new(&rval)
+    }
+}
+
+/// `assert_register!(ctx, Register::new(3), 1.5)`, optionally with a tolerance after the value
+#[macro_export]
+macro_rules! assert_register {
+    ($ctx:expr, $reg:expr, $expected:expr) => {
+        $crate::assert_register!($ctx, $reg, $expected, $crate::Tolerance::EXACT)
+    };
+    ($ctx:expr, $reg:expr, $expected:expr, $tolerance:expr) => {
+        if let Err(e) = $ctx.check_register($reg, $expected, $tolerance) {
+            panic!("{}", e);
+        }
+    };
+}
+
+/// `assert_alias!(ctx, "wantGHPump", 1.0)`, optionally with a tolerance after the value
+#[macro_export]
+macro_rules! assert_alias {
+    ($ctx:expr, $name:expr, $expected:expr) => {
+        $crate::assert_alias!($ctx, $name, $expected, $crate::Tolerance::EXACT)
+    };
+    ($ctx:expr, $name:expr, $expected:expr, $tolerance:expr) => {
+        if let Err(e) = $ctx.check_alias($name, $expected, $tolerance) {
+            panic!("{}", e);
+        }
+    };
+}
+
+/// `assert_device!(ctx, "pumpGH", "PressureExternal", 108.0)`, optionally with a tolerance after the value
+#[macro_export]
+macro_rules! assert_device {
+    ($ctx:expr, $device:expr, $field:expr, $expected:expr) => {
+        $crate::assert_device!($ctx, $device, $field, $expected, $crate::Tolerance::EXACT)
+    };
+    ($ctx:expr, $device:expr, $field:expr, $expected:expr, $tolerance:expr) => {
+        if let Err(e) = $ctx.check_device_field($device, $field, $expected, $tolerance) {
+            panic!("{}", e);
+        }
+    };
+}
+
+/// `assert_on!(ctx, "pumpGH")`: the device's `On` is non-zero
+#[macro_export]
+macro_rules! assert_on {
+    ($ctx:expr, $device:expr) => {
+        if let Err(e) = $ctx.check_device_flag($device, "On", true) {
+            panic!("{}", e);
+        }
+    };
+}
+
+/// `assert_off!(ctx, "pumpGH")`: the device's `On` is 0
+#[macro_export]
+macro_rules! assert_off {
+    ($ctx:expr, $device:expr) => {
+        if let Err(e) = $ctx.check_device_flag($device, "On", false) {
+            panic!("{}", e);
+        }
+    };
+}
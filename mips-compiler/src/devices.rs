@@ -0,0 +1,162 @@
+use crate::DeviceState;
+
+/// What sort of device is plugged into a pin: which logic types an IC can read and write on it,
+/// and what they read before anything has been written.
+/// Reading or writing any other logic type faults the chip, as it does in the game.
+#[derive(Debug,PartialEq)]
+pub struct DeviceKind
+{
+    /// the game's prefab name, such as `StructureGasSensor`
+    pub prefab: &'static str,
+    pub readable: &'static [&'static str],
+    pub writable: &'static [&'static str],
+    /// values other than 0 for readable logic types of a freshly built device
+    pub defaults: &'static [(&'static str, f32)],
+}
+
+impl DeviceKind
+{
+    pub fn can_read(&self, field:&str) -> bool
+    {
+        self.readable.contains(&field)
+    }
+
+    pub fn can_write(&self, field:&str) -> bool
+    {
+        self.writable.contains(&field)
+    }
+
+    /// every readable logic type, at its default value
+    pub fn initial_state(&self) -> DeviceState
+    {
+        let mut rval: DeviceState = self.readable.iter().map(|&f| (f.to_string(), 0.0)).collect();
+        for &(field, value) in self.defaults {
+            rval.insert(field.to_string(), value);
+        }
+        rval
+    }
+
+    pub fn by_prefab(prefab:&str) -> Option<&'static DeviceKind>
+    {
+        DEVICE_KINDS.iter().copied().find(|k| k.prefab == prefab)
+    }
+}
+
+pub static GAS_SENSOR: DeviceKind = DeviceKind {
+    prefab: "StructureGasSensor",
+    readable: &["Pressure", "Temperature", "RatioOxygen", "RatioCarbonDioxide", "RatioNitrogen",
+        "RatioPollutant", "RatioVolatiles", "RatioWater", "RatioNitrousOxide", "TotalMoles",
+        "PrefabHash", "ReferenceId"],
+    writable: &[],
+    defaults: &[],
+};
+
+pub static PIPE_ANALYZER: DeviceKind = DeviceKind {
+    prefab: "StructurePipeAnalysizer",
+    readable: &["Pressure", "Temperature", "RatioOxygen", "RatioCarbonDioxide", "RatioNitrogen",
+        "RatioPollutant", "RatioVolatiles", "RatioWater", "RatioNitrousOxide", "TotalMoles",
+        "Lock", "PrefabHash", "ReferenceId"],
+    writable: &["Lock"],
+    defaults: &[],
+};
+
+pub static VOLUME_PUMP: DeviceKind = DeviceKind {
+    prefab: "StructureVolumePump",
+    readable: &["On", "Power", "Mode", "Error", "Lock", "Setting", "Maximum", "Ratio",
+        "RequiredPower", "PrefabHash", "ReferenceId"],
+    writable: &["On", "Mode", "Lock", "Setting"],
+    defaults: &[("Maximum", 10.0)],
+};
+
+pub static POWERED_VENT: DeviceKind = DeviceKind {
+    prefab: "StructurePoweredVent",
+    readable: &["On", "Power", "Mode", "Error", "Lock", "PressureExternal", "RequiredPower",
+        "PrefabHash", "ReferenceId"],
+    writable: &["On", "Mode", "Lock", "PressureExternal"],
+    defaults: &[("PressureExternal", 101.325)],
+};
+
+pub static ACTIVE_VENT: DeviceKind = DeviceKind {
+    prefab: "StructureActiveVent",
+    readable: &["On", "Power", "Mode", "Error", "Lock", "PressureExternal", "PressureInternal",
+        "RequiredPower", "PrefabHash", "ReferenceId"],
+    writable: &["On", "Mode", "Lock", "PressureExternal", "PressureInternal"],
+    defaults: &[("PressureExternal", 101.325)],
+};
+
+pub static FILTRATION: DeviceKind = DeviceKind {
+    prefab: "StructureFiltration",
+    readable: &["On", "Power", "Mode", "Error", "Lock", "PressureInput", "TemperatureInput",
+        "PressureOutput", "TemperatureOutput", "PressureOutput2", "TemperatureOutput2",
+        "RequiredPower", "PrefabHash", "ReferenceId"],
+    writable: &["On", "Mode", "Lock"],
+    defaults: &[],
+};
+
+pub static DAYLIGHT_SENSOR: DeviceKind = DeviceKind {
+    prefab: "StructureDaylightSensor",
+    readable: &["On", "Mode", "Activate", "Horizontal", "Vertical", "SolarAngle", "SolarIrradiance",
+        "PrefabHash", "ReferenceId"],
+    writable: &["On", "Mode", "Activate"],
+    defaults: &[],
+};
+
+pub static SOLAR_PANEL: DeviceKind = DeviceKind {
+    prefab: "StructureSolarPanel",
+    readable: &["Charge", "Horizontal", "Vertical", "Ratio", "Maximum", "PrefabHash", "ReferenceId"],
+    writable: &["Horizontal", "Vertical"],
+    defaults: &[],
+};
+
+pub static LOGIC_MEMORY: DeviceKind = DeviceKind {
+    prefab: "StructureLogicMemory",
+    readable: &["Setting", "PrefabHash", "ReferenceId"],
+    writable: &["Setting"],
+    defaults: &[],
+};
+
+pub static WALL_LIGHT: DeviceKind = DeviceKind {
+    prefab: "StructureWallLight",
+    readable: &["On", "Power", "Lock", "RequiredPower", "PrefabHash", "ReferenceId"],
+    writable: &["On", "Lock"],
+    defaults: &[],
+};
+
+/// every kind `DeviceKind::by_prefab` knows about
+pub static DEVICE_KINDS: &[&DeviceKind] = &[
+    &GAS_SENSOR, &PIPE_ANALYZER, &VOLUME_PUMP, &POWERED_VENT, &ACTIVE_VENT, &FILTRATION,
+    &DAYLIGHT_SENSOR, &SOLAR_PANEL, &LOGIC_MEMORY, &WALL_LIGHT,
+];
+
+//
+
+/// What `CPUContext::attach_device` plugs into a pin: a bare `DeviceState` that accepts any
+/// logic type, a `DeviceKind` with its default values, or a kind plus values that override the defaults.
+#[derive(Clone,Debug)]
+pub struct DeviceAttachment
+{
+    pub kind: Option<&'static DeviceKind>,
+    /// the values given explicitly; the kind's defaults are filled in when it is attached
+    pub state: DeviceState,
+}
+
+impl From<DeviceState> for DeviceAttachment
+{
+    fn from(state: DeviceState) -> Self {
+        DeviceAttachment { kind: None, state }
+    }
+}
+
+impl From<&'static DeviceKind> for DeviceAttachment
+{
+    fn from(kind: &'static DeviceKind) -> Self {
+        DeviceAttachment { kind: Some(kind), state: DeviceState::new() }
+    }
+}
+
+impl From<(&'static DeviceKind, DeviceState)> for DeviceAttachment
+{
+    fn from((kind, state): (&'static DeviceKind, DeviceState)) -> Self {
+        DeviceAttachment { kind: Some(kind), state }
+    }
+}
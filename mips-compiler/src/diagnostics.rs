@@ -0,0 +1,224 @@
+use std::fmt::{Formatter, Error};
+
+use crate::CompileError;
+
+//
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum Severity
+{
+    Error,
+    Warning,
+}
+
+impl std::fmt::Display for Severity
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+//
+
+/// A range of columns on one source line.
+/// Lines count from 0 (the same as the instruction pointer and the in-game editor).
+/// Columns are byte offsets into the line, counting from 0, with `end` exclusive.
+#[derive(Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub struct Span
+{
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span
+{
+    pub fn new(line:usize, start:usize, end:usize) -> Span
+    {
+        Span { line, start, end }
+    }
+}
+
+impl std::fmt::Display for Span
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}:{}-{}", self.line, self.start, self.end)
+    }
+}
+
+//
+
+/// A replacement for the text in `span`
+#[derive(Clone,Debug,PartialEq)]
+pub struct TextEdit
+{
+    pub span: Span,
+    pub replacement: String,
+}
+
+/// A mechanical change to the source that resolves a diagnostic without changing
+/// what the program does.  Fixes never add or remove lines, so line numbers stay put.
+#[derive(Clone,Debug,PartialEq)]
+pub struct Fix
+{
+    pub description: String,
+    pub edits: Vec<TextEdit>,
+}
+
+impl Fix
+{
+    pub fn new(description:&str, edits:Vec<TextEdit>) -> Fix
+    {
+        Fix { description: description.to_string(), edits }
+    }
+}
+
+/// Apply fixes to the source.  Each fix is applied whole or not at all: one with an edit that
+/// overlaps an edit of a fix already taken, or that is out of range, is skipped.
+pub fn apply_fixes<'f, I>(src:&str, fixes:I) -> String
+    where I:IntoIterator<Item=&'f Fix>
+{
+    let mut lines: Vec<String> = src.lines().map(|l| l.to_string()).collect();
+    let mut edits: Vec<&TextEdit> = Vec::new();
+    for fix in fixes {
+        let fits = fix.edits.iter().all(|edit| {
+            let span = edit.span;
+            let overlaps = edits.iter().any(|a| a.span.line == span.line && a.span.start < span.end && span.start < a.span.end);
+            !overlaps && span.line < lines.len() && span.end <= lines[span.line].len()
+        });
+        if fits {
+            edits.extend(&fix.edits);
+        }
+    }
+    edits.sort_by_key(|e| std::cmp::Reverse(e.span));
+    for edit in edits {
+        lines[edit.span.line].replace_range(edit.span.start..edit.span.end, &edit.replacement);
+    }
+
+    let mut rval = lines.join("\n");
+    if src.ends_with('\n') {
+        rval.push('\n');
+    }
+    rval
+}
+
+//
+
+#[derive(Clone,Debug)]
+pub struct Diagnostic
+{
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+    /// the name of the lint that produced this, if any
+    pub code: Option<&'static str>,
+    pub fix: Option<Fix>,
+}
+
+impl Diagnostic
+{
+    pub fn error(span:Span, message:&str) -> Diagnostic
+    {
+        Diagnostic { severity: Severity::Error, span, message: message.to_string(), code: None, fix: None }
+    }
+
+    pub fn warning(span:Span, message:&str) -> Diagnostic
+    {
+        Diagnostic { severity: Severity::Warning, span, message: message.to_string(), code: None, fix: None }
+    }
+
+    pub fn with_code(mut self, code:&'static str) -> Diagnostic
+    {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_fix(mut self, fix:Fix) -> Diagnostic
+    {
+        self.fix = Some(fix);
+        self
+    }
+
+    /// place a `CompileError` from line `line_number` of the source.
+    /// If the error names a token we can find on the line, the span covers that token,
+    /// otherwise it covers the whole statement.
+    pub fn from_compile_error(line_number:usize, line:&str, err:&CompileError) -> Diagnostic
+    {
+        Diagnostic::error(token_span(line_number, line, err.token()), &err.message)
+    }
+
+    pub fn is_error(&self) -> bool
+    {
+        self.severity == Severity::Error
+    }
+}
+
+impl std::fmt::Display for Diagnostic
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self.code {
+            Some(code) => write!(f, "{}: {}[{}]: {}", self.span, self.severity, code, self.message),
+            None => write!(f, "{}: {}: {}", self.span, self.severity, self.message),
+        }
+    }
+}
+
+//
+
+/// a whitespace-separated word from a source line, with its column range
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Token<'a>
+{
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// the part of the line before any `#` comment
+pub fn strip_comment(line:&str) -> &str
+{
+    match line.find('#') {
+        Some(idx) => &line[..idx],
+        None => line,
+    }
+}
+
+/// split the code portion of a line into tokens, remembering where each one starts
+pub fn tokenize(line:&str) -> Vec<Token<'_>>
+{
+    let code = strip_comment(line);
+    let mut rval = Vec::new();
+    let mut start = None;
+    for (idx, ch) in code.char_indices() {
+        match (ch.is_whitespace(), start) {
+            (true, Some(s)) => {
+                rval.push(Token { text: &code[s..idx], start: s, end: idx });
+                start = None;
+            },
+            (false, None) => start = Some(idx),
+            _ => {},
+        }
+    }
+    if let Some(s) = start {
+        rval.push(Token { text: &code[s..], start: s, end: code.len() });
+    }
+    rval
+}
+
+/// the span of `token` on the line, or of the whole statement if `token` is `None` or absent
+pub fn token_span(line_number:usize, line:&str, token:Option<&str>) -> Span
+{
+    let tokens = tokenize(line);
+    if let Some(token) = token {
+        if let Some(tok) = tokens.iter().find(|t| t.text == token) {
+            return Span::new(line_number, tok.start, tok.end);
+        }
+    }
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => Span::new(line_number, first.start, last.end),
+        _ => Span::new(line_number, 0, line.len()),
+    }
+}
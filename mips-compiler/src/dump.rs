@@ -0,0 +1,199 @@
+use std::collections::BTreeMap;
+use std::fmt::{Formatter, Error};
+
+use crate::{ContextSnapshot, Value};
+
+impl ContextSnapshot
+{
+    /// `r9` or `d3` followed by the aliases the program gave it, as in `r9 wantGHPump`
+    fn name_with_aliases(&self, place:&str) -> String
+    {
+        let mut rval = place.to_string();
+        for (name, target) in &self.aliases {
+            if target == place {
+                rval.push(' ');
+                rval.push_str(name);
+            }
+        }
+        rval
+    }
+
+    fn device_names(&self) -> Vec<(String, Option<&BTreeMap<String, Value>>)>
+    {
+        let mut rval: Vec<_> = self.devices.iter().enumerate()
+            .map(|(idx, dev)| (format!("d{}", idx), dev.as_ref().map(|d| &d.fields)))
+            .collect();
+        rval.push(("db".to_string(), Some(&self.db)));
+        rval
+    }
+
+    /// every register, device field and stack slot whose value differs in `after`, in that order
+    pub fn diff(&self, after:&ContextSnapshot) -> StateDiff
+    {
+        let mut changes = Vec::new();
+        let mut change = |place:String, before:Option<&Value>, now:Option<&Value>| {
+            if before != now {
+                changes.push(StateChange { place, before: before.map(|v| v.0), after: now.map(|v| v.0) });
+            }
+        };
+
+        for idx in 0..self.registers.len().max(after.registers.len()) {
+            change(after.name_with_aliases(&format!("r{}", idx)), self.registers.get(idx), after.registers.get(idx));
+        }
+
+        let empty = BTreeMap::new();
+        let devices_before = self.device_names();
+        for (pin, fields_after) in after.device_names() {
+            let fields_before = devices_before.iter().find(|(p, _)| *p == pin).and_then(|(_, f)| *f);
+            let (fields_before, fields_after) = (fields_before.unwrap_or(&empty), fields_after.unwrap_or(&empty));
+            let device = after.name_with_aliases(&pin);
+            let mut names: Vec<&String> = fields_before.keys().chain(fields_after.keys()).collect();
+            names.sort();
+            names.dedup();
+            for field in names {
+                change(format!("{} {}", device, field), fields_before.get(field), fields_after.get(field));
+            }
+        }
+
+        let zero = Value(0.0);
+        for idx in 0..self.stack.len().max(after.stack.len()) {
+            change(format!("stack[{}]", idx),
+                   Some(self.stack.get(idx).unwrap_or(&zero)), Some(after.stack.get(idx).unwrap_or(&zero)));
+        }
+
+        StateDiff { changes }
+    }
+}
+
+/// A readable dump of the whole state: registers named by their aliases, then each device's
+/// fields in alphabetical order and its slots, any injected faults, the stack and the clock.
+/// Registers that have never been written and have no alias are left out.
+impl std::fmt::Display for ContextSnapshot
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "ip {}, tick {}, {} instructions run, {:?}",
+                 self.instruction_pointer, self.clock.ticks, self.clock.instructions, self.strictness)?;
+
+        writeln!(f, "registers:")?;
+        for (idx, val) in self.registers.iter().enumerate() {
+            let reg = format!("r{}", idx);
+            let name = self.name_with_aliases(&reg);
+            if self.registers_written.contains(&reg) {
+                writeln!(f, "  {} = {}", name, val.0)?;
+            } else if name != reg {
+                writeln!(f, "  {} = {} (never written)", name, val.0)?;
+            }
+        }
+
+        if !self.defines.is_empty() {
+            writeln!(f, "defines:")?;
+            for (name, val) in &self.defines {
+                writeln!(f, "  {} = {}", name, val.0)?;
+            }
+        }
+
+        for (idx, dev) in self.devices.iter().enumerate() {
+            let pin = format!("d{}", idx);
+            let name = self.name_with_aliases(&pin);
+            match dev {
+                None if name.contains(' ') || self.unplugged.contains_key(&pin) => writeln!(f, "{}: not attached", name)?,
+                None => {},
+                Some(dev) => {
+                    match &dev.kind {
+                        Some(kind) => writeln!(f, "{} ({}):", name, kind)?,
+                        None => writeln!(f, "{}:", name)?,
+                    }
+                    for (field, val) in &dev.fields {
+                        writeln!(f, "  {} = {}", field, val.0)?;
+                    }
+                }
+            }
+            for (slot, fields) in self.slots.get(&pin).into_iter().flatten().enumerate() {
+                if !fields.is_empty() {
+                    let fields: Vec<String> = fields.iter().map(|(field, val)| format!("{} = {}", field, val.0)).collect();
+                    writeln!(f, "  slot {}: {}", slot, fields.join(", "))?;
+                }
+            }
+            if let Some(dev) = self.unplugged.get(&pin) {
+                writeln!(f, "  to plug back in: {} with {} fields", dev.kind.as_deref().unwrap_or("a device"), dev.fields.len())?;
+            }
+        }
+        if !self.db.is_empty() {
+            writeln!(f, "db:")?;
+            for (field, val) in &self.db {
+                writeln!(f, "  {} = {}", field, val.0)?;
+            }
+        }
+
+        if !self.dropping_writes.is_empty() || !self.stuck_fields.is_empty() {
+            writeln!(f, "faults:")?;
+            for dev in &self.dropping_writes {
+                writeln!(f, "  {} ignores writes", self.name_with_aliases(dev))?;
+            }
+            for (location, val) in &self.stuck_fields {
+                writeln!(f, "  {} stuck at {}", location, val.0)?;
+            }
+        }
+
+        if !self.stack.is_empty() {
+            writeln!(f, "stack (the rest is 0):")?;
+            for (idx, val) in self.stack.iter().enumerate() {
+                writeln!(f, "  [{}] = {}", idx, val.0)?;
+            }
+        }
+        write!(f, "random: seed {}, {} drawn, {} scripted", self.random.seed, self.random.draws, self.random.scripted.len())
+    }
+}
+
+//
+
+/// One register, device field or stack slot that differs between two snapshots
+#[derive(Clone,Debug,PartialEq)]
+pub struct StateChange
+{
+    /// such as `r9 wantGHPump`, `d3 pumpGH On` or `stack[2]`
+    pub place: String,
+    /// `None` for a device field that did not exist
+    pub before: Option<f32>,
+    pub after: Option<f32>,
+}
+
+impl std::fmt::Display for StateChange
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let show = |val:Option<f32>| val.map_or("(none)".to_string(), |v| v.to_string());
+        write!(f, "{}: {} -> {}", self.place, show(self.before), show(self.after))
+    }
+}
+
+/// What `ContextSnapshot::diff` found
+#[derive(Clone,Debug,PartialEq)]
+pub struct StateDiff
+{
+    pub changes: Vec<StateChange>,
+}
+
+impl StateDiff
+{
+    pub fn is_empty(&self) -> bool
+    {
+        self.changes.is_empty()
+    }
+}
+
+/// one change per line
+impl std::fmt::Display for StateDiff
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        if self.changes.is_empty() {
+            return write!(f, "no changes");
+        }
+        for (i, change) in self.changes.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
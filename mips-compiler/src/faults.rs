@@ -0,0 +1,321 @@
+use std::fmt::{Formatter, Error};
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{CPUContext, CompiledProgram, ScheduledEvent, Timeline, TimelineError, TimelineEvent};
+
+/// what a broken sensor reports: not a number, infinite, or far outside anything a working one reads
+pub const GARBAGE_VALUES: &[f32] = &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -1.0, 1.0e9];
+
+/// The ways `FaultInjector` can break the world around a chip
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum FaultKind
+{
+    /// a device is unplugged, then plugged back in as it was
+    Unplug,
+    /// a field reads one of `GARBAGE_VALUES`
+    Garbage,
+    /// a field keeps reading the value it had when the fault started
+    Freeze,
+    /// a device ignores the program's writes
+    DropWrites,
+}
+
+impl FaultKind
+{
+    pub const ALL: &'static [FaultKind] = &[FaultKind::Unplug, FaultKind::Garbage, FaultKind::Freeze, FaultKind::DropWrites];
+
+    fn on_field(self) -> bool
+    {
+        matches!(self, FaultKind::Garbage | FaultKind::Freeze)
+    }
+}
+
+/// something that must hold of a chip's state, for `FaultInjector` and `Property`
+pub(crate) type Invariant = Arc<dyn Fn(&CPUContext) -> bool + Send + Sync>;
+
+/// Breaks devices at random, but repeatably for a given seed, while a program runs, to check that
+/// the program survives them:
+///
+/// ```ignore
+/// let report = FaultInjector::new(7)
+///     .device("pump")
+///     .field("sensor", "Pressure")
+///     .invariant("pump off when the pressure is high", |ctx| ...)
+///     .run(&program, &mut ctx, 1000);
+/// assert!(report.survived(), "{}", report);
+/// ```
+///
+/// Each tick a new fault starts with probability `rate`, on a device or field that has no fault
+/// already, and lasts from 1 to `longest` ticks.  The faults are events on a `Timeline`, see `plan`.
+#[derive(Clone)]
+pub struct FaultInjector
+{
+    seed: u64,
+    rate: f32,
+    longest: u64,
+    kinds: Vec<FaultKind>,
+    /// targets for `Unplug` and `DropWrites`
+    devices: Vec<String>,
+    /// targets for `Garbage` and `Freeze`
+    fields: Vec<(String, String)>,
+    invariants: Vec<(String, Invariant)>,
+}
+
+impl FaultInjector
+{
+    pub fn new(seed:u64) -> FaultInjector
+    {
+        FaultInjector {
+            seed,
+            rate: 0.05,
+            longest: 10,
+            kinds: FaultKind::ALL.to_vec(),
+            devices: Vec::new(),
+            fields: Vec::new(),
+            invariants: Vec::new(),
+        }
+    }
+
+    /// the chance each tick that a new fault starts
+    pub fn rate(mut self, rate:f32) -> FaultInjector
+    {
+        self.rate = rate;
+        self
+    }
+
+    /// the most ticks a fault lasts
+    pub fn longest(mut self, ticks:u64) -> FaultInjector
+    {
+        self.longest = ticks.max(1);
+        self
+    }
+
+    /// only inject these kinds of fault
+    pub fn kinds(mut self, kinds:&[FaultKind]) -> FaultInjector
+    {
+        self.kinds = kinds.to_vec();
+        self
+    }
+
+    /// a device that can be unplugged or stop taking writes, named as in a `Timeline`
+    pub fn device(mut self, name:&str) -> FaultInjector
+    {
+        self.devices.push(name.to_string());
+        self
+    }
+
+    /// a field that can read garbage or freeze
+    pub fn field(mut self, device:&str, field:&str) -> FaultInjector
+    {
+        self.fields.push((device.to_string(), field.to_string()));
+        self
+    }
+
+    /// something that must hold after every tick, whatever has broken
+    pub fn invariant<F>(mut self, name:&str, holds:F) -> FaultInjector
+        where F:Fn(&CPUContext) -> bool + Send + Sync + 'static
+    {
+        self.invariants.push((name.to_string(), Arc::new(holds)));
+        self
+    }
+
+    /// the faults this seed injects over the first `ticks` ticks, with the event that ends each one
+    pub fn plan(&self, ticks:u64) -> Timeline
+    {
+        self.plan_from(0, ticks)
+    }
+
+    /// the same faults as `plan`, starting once `start` ticks have run
+    fn plan_from(&self, start:u64, ticks:u64) -> Timeline
+    {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        let kinds: Vec<FaultKind> = self.kinds.iter().copied()
+            .filter(|k| if k.on_field() { !self.fields.is_empty() } else { !self.devices.is_empty() })
+            .collect();
+        // one fault per device at a time, so a field doesn't freeze on a device that isn't there
+        let mut busy: Vec<(String, u64)> = Vec::new();
+        let mut rval = Timeline::new();
+        if kinds.is_empty() {
+            return rval;
+        }
+        for tick in start..start+ticks {
+            if rng.gen::<f32>() >= self.rate {
+                continue;
+            }
+            let kind = kinds[rng.gen_range(0, kinds.len())];
+            let (device, field) = if kind.on_field() {
+                let (device, field) = &self.fields[rng.gen_range(0, self.fields.len())];
+                (device.as_str(), field.as_str())
+            } else {
+                (self.devices[rng.gen_range(0, self.devices.len())].as_str(), "")
+            };
+            let end = tick + rng.gen_range(1, self.longest + 1);
+            if busy.iter().any(|(d, until)| d == device && *until > tick) {
+                continue;
+            }
+            busy.retain(|(d, _)| d != device);
+            busy.push((device.to_string(), end));
+            rval = match kind {
+                FaultKind::Unplug => rval.detach(tick, device).reattach(end, device),
+                FaultKind::Garbage => {
+                    let value = GARBAGE_VALUES[rng.gen_range(0, GARBAGE_VALUES.len())];
+                    rval.stuck_at(tick, device, field, value).release(end, device, field)
+                },
+                FaultKind::Freeze => rval.freeze(tick, device, field).release(end, device, field),
+                FaultKind::DropWrites => rval.drop_writes(tick, device).keep_writes(end, device),
+            };
+        }
+        rval
+    }
+
+    /// Run the program on `ctx` for `ticks` ticks with the faults of `plan`, counted from the ticks `ctx`
+    /// has already run, checking the invariants after every tick.  Stops at the first chip fault or
+    /// broken invariant, leaving `ctx` as it was then, except that any fault still going is ended.
+    pub fn run(&self, program:&CompiledProgram, ctx:&mut CPUContext, ticks:u32) -> FaultReport
+    {
+        let plan = self.plan_from(ctx.ticks(), ticks as u64);
+        let mut next = 0;
+        let mut injected: Vec<ScheduledEvent> = Vec::new();
+        let mut failure: Option<FaultFailure> = None;
+        for _ in 0..ticks {
+            let before = ctx.ticks();
+            let mut failed = None;
+            let message = match plan.run(program, ctx, 1, &mut next, |_| {}, |e| injected.push(e.clone())) {
+                Err(TimelineError::Event(event, e)) => {
+                    failed = Some(event.clone());
+                    Some(format!("{} failed: {}", event, e))
+                },
+                Err(TimelineError::Fault(e)) => Some(format!("line {} faulted: {}", ctx.instruction_pointer(), e)),
+                Ok(()) => self.invariants.iter()
+                    .find(|(_, holds)| !holds(ctx))
+                    .map(|(name, _)| format!("invariant broken: {}", name)),
+            };
+            if let Some(message) = message {
+                let active = active_faults(&injected);
+                let cause = failed.clone().or_else(|| active.last().or_else(|| injected.last()).cloned());
+                injected.extend(failed);
+                failure = Some(FaultFailure { tick: ctx.ticks(), message, cause, active });
+                break;
+            }
+            if ctx.ticks() == before {
+                break; // ran off the end of the program
+            }
+        }
+
+        // leave the devices working, whether the faults ran their course or not
+        let still_going = match &failure {
+            Some(failure) => failure.active.clone(),
+            None => active_faults(&injected),
+        };
+        for start in still_going {
+            if let Some(event) = ending(&start.event) {
+                // ending a fault that is going can't fail: what it undoes is there to undo
+                let _ = event.apply(program, ctx);
+                injected.push(ScheduledEvent { tick: ctx.ticks(), event });
+            }
+        }
+        FaultReport { seed: self.seed, ticks: ctx.ticks(), injected, failure }
+    }
+}
+
+/// the event that ends the fault `start` starts
+fn ending(start:&TimelineEvent) -> Option<TimelineEvent>
+{
+    match start {
+        TimelineEvent::Detach { device } => Some(TimelineEvent::Reattach { device: device.clone() }),
+        TimelineEvent::Stuck { device, field, .. } => Some(TimelineEvent::Release { device: device.clone(), field: field.clone() }),
+        TimelineEvent::DropWrites { device } => Some(TimelineEvent::KeepWrites { device: device.clone() }),
+        _ => None,
+    }
+}
+
+/// the faults started in `events` that nothing after them has ended
+fn active_faults(events:&[ScheduledEvent]) -> Vec<ScheduledEvent>
+{
+    let mut rval: Vec<ScheduledEvent> = Vec::new();
+    for event in events {
+        let ends = |start:&ScheduledEvent| match (&start.event, &event.event) {
+            (TimelineEvent::Detach { device: a }, TimelineEvent::Reattach { device: b })
+            | (TimelineEvent::DropWrites { device: a }, TimelineEvent::KeepWrites { device: b }) => a == b,
+            (TimelineEvent::Stuck { device: a, field: f, .. }, TimelineEvent::Release { device: b, field: g }) => a == b && f == g,
+            _ => false,
+        };
+        match rval.iter().position(ends) {
+            Some(idx) => { rval.remove(idx); },
+            None => if let TimelineEvent::Detach { .. } | TimelineEvent::Stuck { .. } | TimelineEvent::DropWrites { .. } = event.event {
+                rval.push(event.clone());
+            },
+        }
+    }
+    rval
+}
+
+//
+
+/// How a run with injected faults went wrong
+#[derive(Clone,Debug)]
+pub struct FaultFailure
+{
+    /// how many ticks had run
+    pub tick: u64,
+    /// the chip fault or the invariant that was broken
+    pub message: String,
+    /// the fault most likely to blame: the event that could not be applied, or else the latest fault
+    /// still going, or else the latest one injected
+    pub cause: Option<ScheduledEvent>,
+    /// every fault still going when it went wrong
+    pub active: Vec<ScheduledEvent>,
+}
+
+/// What `FaultInjector::run` did.  `Display` says which seed to rerun, and which fault to look at.
+#[derive(Clone,Debug)]
+pub struct FaultReport
+{
+    pub seed: u64,
+    /// how many ticks the context had run when it stopped
+    pub ticks: u64,
+    /// every fault event tried, including the ones that ended faults and the ends of those still
+    /// going when the run stopped, in order
+    pub injected: Vec<ScheduledEvent>,
+    pub failure: Option<FaultFailure>,
+}
+
+impl FaultReport
+{
+    pub fn survived(&self) -> bool
+    {
+        self.failure.is_none()
+    }
+}
+
+impl std::fmt::Display for FaultReport
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match &self.failure {
+            None => write!(f, "seed {}: survived {} ticks", self.seed, self.ticks)?,
+            Some(failure) => {
+                write!(f, "seed {}: after tick {}: {}", self.seed, failure.tick, failure.message)?;
+                match &failure.cause {
+                    Some(cause) => write!(f, "\nlikely cause: {}", cause)?,
+                    None => write!(f, "\nno fault had been injected")?,
+                }
+                if failure.active.len() > 1 {
+                    write!(f, "\nfaults still going:")?;
+                    for event in &failure.active {
+                        write!(f, "\n  {}", event)?;
+                    }
+                }
+            },
+        }
+        if !self.injected.is_empty() {
+            write!(f, "\nfaults injected:")?;
+            for event in &self.injected {
+                write!(f, "\n  {}", event)?;
+            }
+        }
+        Ok(())
+    }
+}
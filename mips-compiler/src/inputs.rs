@@ -0,0 +1,71 @@
+use std::fmt::{Formatter, Error};
+use std::sync::Arc;
+
+use crate::{CPUContext, SECONDS_PER_TICK};
+
+type ValueFn<T> = Arc<dyn Fn(T) -> f32 + Send + Sync>;
+
+/// Where a device field gets its value when the program reads it, in place of a fixed number.
+/// See `CPUContext::attach_input`.
+#[derive(Clone)]
+pub enum InputSource
+{
+    /// one value per read; the last one repeats once they run out
+    Sequence { values: Vec<f32>, next: usize },
+    /// a function of how many ticks have run
+    PerTick(ValueFn<u64>),
+    /// a function of the rest of the chip's state
+    FromState(Arc<dyn Fn(&CPUContext) -> f32 + Send + Sync>),
+}
+
+impl InputSource
+{
+    pub fn sequence<I:IntoIterator<Item=f32>>(values:I) -> InputSource
+    {
+        InputSource::Sequence { values: values.into_iter().collect(), next: 0 }
+    }
+
+    pub fn per_tick<F>(f:F) -> InputSource
+        where F:Fn(u64) -> f32 + Send + Sync + 'static
+    {
+        InputSource::PerTick(Arc::new(f))
+    }
+
+    /// a function of game time in seconds, `SECONDS_PER_TICK` a tick
+    pub fn over_time<F>(f:F) -> InputSource
+        where F:Fn(f32) -> f32 + Send + Sync + 'static
+    {
+        InputSource::per_tick(move |tick| f(tick as f32 * SECONDS_PER_TICK))
+    }
+
+    pub fn from_state<F>(f:F) -> InputSource
+        where F:Fn(&CPUContext) -> f32 + Send + Sync + 'static
+    {
+        InputSource::FromState(Arc::new(f))
+    }
+
+    /// the value a read gets now, or `None` for a sequence with no values
+    pub(crate) fn read(&mut self, ctx:&CPUContext) -> Option<f32>
+    {
+        match self {
+            InputSource::Sequence { values, next } => {
+                let rval = values.get(*next).or_else(|| values.last()).copied();
+                *next += 1;
+                rval
+            },
+            InputSource::PerTick(f) => Some(f(ctx.ticks())),
+            InputSource::FromState(f) => Some(f(ctx)),
+        }
+    }
+}
+
+impl std::fmt::Debug for InputSource
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            InputSource::Sequence { values, next } => write!(f, "Sequence({:?}, next: {})", values, next),
+            InputSource::PerTick(_) => write!(f, "PerTick(..)"),
+            InputSource::FromState(_) => write!(f, "FromState(..)"),
+        }
+    }
+}
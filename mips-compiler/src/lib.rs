@@ -1,18 +1,65 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::Entry;
 use std::fmt::{Formatter, Error};
+use std::sync::Arc;
+
+use serde::{Serialize, Serializer};
 
 extern crate rand;
 extern crate math;
+extern crate serde;
+extern crate serde_json;
 
 pub type InstructionPointer = u16;
 
 pub type DeviceState = HashMap<String, f32>;
 
+mod analysis;
+pub use analysis::*;
+mod assertions;
+pub use assertions::*;
+mod devices;
+pub use devices::*;
+mod dump;
+pub use dump::*;
+mod faults;
+pub use faults::*;
+mod diagnostics;
+pub use diagnostics::*;
+mod inputs;
+pub use inputs::*;
+mod lint;
+pub use lint::*;
+mod logic_types;
+pub use logic_types::*;
+mod monitor;
+pub use monitor::*;
+mod profile;
+pub use profile::*;
+mod parallel;
+pub use parallel::*;
+mod property;
+pub use property::*;
+mod random;
+pub use random::*;
+mod resolve;
+pub use resolve::*;
+mod scenario;
+pub use scenario::*;
+mod snapshot;
+pub use snapshot::*;
+mod subroutine;
+pub use subroutine::*;
+mod timeline;
+pub use timeline::*;
+mod wiring;
+
 #[cfg(test)]
 mod tests;
 
 //
 
+#[derive(Clone,Debug)]
 pub struct CPUContext
 {
     labels: HashMap<String, InstructionPointer>,
@@ -20,18 +67,56 @@ pub struct CPUContext
     aliases: HashMap<String, RegisterOrDevice>,
     defines: HashMap<String, f32>,
     devices: Vec< Option<DeviceState> >,
+    /// parallel to `devices`; `None` for a device that accepts any logic type
+    device_kinds: Vec< Option<&'static DeviceKind> >,
     device_b: DeviceState,
     registers: Vec<f32>,
+    /// parallel to `registers`; whether anything has stored a value there
+    registers_written: Vec<bool>,
+    strictness: Strictness,
+    /// the first read of each location that nothing had written
+    uninitialized_reads: Vec<UninitializedRead>,
+    random: RandomSource,
+    stack: Vec<f32>,
+    /// how many ticks `execute_until_yields` has run on this context
+    ticks: u64,
+    instructions_executed: u64,
     saw_yield: bool,
+    /// the last `RECENT_DEVICE_WRITES` device writes, for failure messages
+    device_writes: VecDeque<DeviceWrite>,
+    program: Option<CompiledProgram>,
+    inputs: Vec<AttachedInput>,
+    /// parallel to `devices`; what `detach_device` last unplugged from each pin
+    unplugged: Vec< Option<(DeviceState, Option<&'static DeviceKind>)> >,
+    /// devices that ignore the program's writes
+    dropping_writes: Vec<Device>,
+    /// fields whose reads return a fixed value, whatever is stored in them
+    stuck_fields: Vec<(Device, String, f32)>,
+    /// parallel to `devices`; the fields of each slot of the device on that pin, by slot number
+    slots: Vec<Vec<DeviceState>>,
 }
 
 impl CPUContext
 {
     pub fn new_simple(program:&CompiledProgram) -> CPUContext
     {
-        CPUContext::new(program.labels(), HashMap::new(),
-                        (0..6).map(|_| None).collect(),
-                        (0..18).map(|_| std::f32::NAN).collect())
+        CPUContext::new_with_strictness(program, Strictness::default())
+    }
+
+    pub fn new_with_strictness(program:&CompiledProgram, strictness:Strictness) -> CPUContext
+    {
+        let profile = ChipProfile::ic10();
+        let initial = match strictness {
+            Strictness::Lenient => f32::NAN,
+            Strictness::GameAccurate | Strictness::Pedantic => 0.0,
+        };
+        let mut rval = CPUContext::new(program.labels(), HashMap::new(),
+                                       (0..profile.device_pins).map(|_| None).collect(),
+                                       (0..profile.registers).map(|_| initial).collect());
+        rval.strictness = strictness;
+        rval.stack = vec![0.0; profile.stack_size];
+        rval.program = Some(program.clone());
+        rval
     }
 
     pub fn new(labels: HashMap<String,InstructionPointer>, aliases: HashMap<String,RegisterOrDevice>,
@@ -39,14 +124,29 @@ impl CPUContext
     registers:Vec<f32>) ->CPUContext
     {
         CPUContext {
-            labels:labels,
+            labels,
             instruction_pointer: 0,
-            aliases:aliases,
+            aliases,
             defines: HashMap::new(),
-            devices: devices,
+            device_kinds: devices.iter().map(|_| None).collect(),
+            unplugged: devices.iter().map(|_| None).collect(),
+            slots: devices.iter().map(|_| Vec::new()).collect(),
+            devices,
             device_b: DeviceState::new(),
-            registers: registers,
+            registers_written: registers.iter().map(|_| false).collect(),
+            registers,
+            strictness: Strictness::default(),
+            uninitialized_reads: Vec::new(),
+            random: RandomSource::default(),
+            stack: vec![0.0; ChipProfile::ic10().stack_size],
+            ticks: 0,
+            instructions_executed: 0,
             saw_yield: false,
+            device_writes: VecDeque::with_capacity(RECENT_DEVICE_WRITES),
+            program: None,
+            inputs: Vec::new(),
+            dropping_writes: Vec::new(),
+            stuck_fields: Vec::new(),
         }
     }
 
@@ -60,8 +160,17 @@ impl CPUContext
                     Some(&number) => Ok(number)
                 }
             },
-            LineNumber::Number(number) => {
+            LineNumber::Number(number) | LineNumber::ResolvedLabel(_, number) => {
                 Ok(*number)
+            },
+            LineNumber::Register(reg) => {
+                // `ra` is the last register, where `set_ra` puts it, however many the chip has
+                let val = if *reg == Register::RA { self.get_ra() } else { self.register_reference(*reg)? };
+                if val >= 0.0 && val <= InstructionPointer::MAX as f32 && val.fract() == 0.0 {
+                    Ok(val as InstructionPointer)
+                } else {
+                    Err(ExecutionError::new(&format!("{} = {} is not a line number", reg, val)))
+                }
             }
         }
     }
@@ -89,26 +198,22 @@ impl CPUContext
 
             },
             AliasOrDevice::Device(dev) => Ok(*dev),
+            AliasOrDevice::ResolvedAlias(_, dev) => Ok(*dev),
         }
     }
 
-    pub fn resolve_r_value(&self, r_value: &RValue) -> Result<f32, ExecutionError>
+    pub fn resolve_r_value(&mut self, r_value: &RValue) -> Result<f32, ExecutionError>
     {
         match r_value {
             RValue::Number(val) => Ok(*val),
-            RValue::Register(reg) => {
-                let ridx = reg.idx as usize;
-                if ridx < self.registers.len() {
-                    Ok(self.registers[ridx])
-                } else {
-                    Err(ExecutionError::new(&format!("no register {}", reg)))
-                }
-            },
+            RValue::Register(reg) => self.read_register(*reg, reg),
+            RValue::ResolvedAlias(name, reg) => self.read_register(*reg, name),
+            RValue::ResolvedDefine(_, val) => Ok(*val),
             RValue::Name(tag) => {
-                if let Some(rod) = self.aliases.get(tag) {
+                if let Some(&rod) = self.aliases.get(tag) {
                     return match rod {
                         RegisterOrDevice::Device(dev) =>Err(ExecutionError::new(&format!("device alias {}={} can not be an rvalue", tag, dev))),
-                        RegisterOrDevice::Register(reg) => self.register_reference(*reg)
+                        RegisterOrDevice::Register(reg) => self.read_register(reg, tag)
                     };
                 }
                 match self.defines.get(tag) {
@@ -133,20 +238,171 @@ impl CPUContext
                         }
                     }
                 }
-            }
+            },
+            LValue::ResolvedAlias(_, reg) => Ok(*reg),
+        }
+    }
+
+    /// plug a device into pin `idx`.  `dev` is a `DeviceState`, a `&DeviceKind`, or a `(&DeviceKind, DeviceState)`
+    pub fn attach_device<D:Into<DeviceAttachment>>(&mut self, idx:usize , dev:D) -> Result<(), ExecutionError>
+    {
+        if idx < self.devices.len() {
+            let dev = dev.into();
+            let mut state = match dev.kind {
+                Some(kind) if self.strictness != Strictness::Pedantic => kind.initial_state(),
+                _ => DeviceState::new(),
+            };
+            state.extend(dev.state);
+            self.devices[idx] = Some(state);
+            self.device_kinds[idx] = dev.kind;
+            self.slots[idx].clear();
+            Ok(())
+        } else {
+            Err(ExecutionError::new(&format!("no device slot d{} on CPU", idx)))
         }
     }
 
-    pub fn attach_device(&mut self, idx:usize , dev:DeviceState) -> Result<(), ExecutionError>
+    /// unplug whatever is on pin `idx`, so the program's next access to it faults
+    pub fn detach_device(&mut self, idx:usize) -> Result<(), ExecutionError>
     {
         if idx < self.devices.len() {
-            self.devices[idx] = Some(dev);
+            if let Some(state) = self.devices[idx].take() {
+                self.unplugged[idx] = Some((state, self.device_kinds[idx].take()));
+            }
             Ok(())
         } else {
             Err(ExecutionError::new(&format!("no device slot d{} on CPU", idx)))
         }
     }
 
+    /// plug the device `detach_device` last took off pin `idx` back in, as it was
+    pub fn reattach_device(&mut self, idx:usize) -> Result<(), ExecutionError>
+    {
+        match self.unplugged.get_mut(idx).map(|u| u.take()) {
+            Some(Some((state, kind))) => {
+                self.devices[idx] = Some(state);
+                self.device_kinds[idx] = kind;
+                Ok(())
+            },
+            Some(None) => Err(ExecutionError::new(&format!("nothing has been unplugged from d{}", idx))),
+            None => Err(ExecutionError::new(&format!("no device slot d{} on CPU", idx))),
+        }
+    }
+
+    /// The fields of slot `slot` of the device on pin `dev`, which start out empty.  A device keeps what
+    /// is in its slots while it is unplugged, and plugging another device in empties them.
+    pub fn slot_reference(&mut self, dev:Device, slot:usize) -> Result<&mut DeviceState, ExecutionError>
+    {
+        let idx = match dev {
+            Device::Regular(idx) => idx as usize,
+            Device::SpecialB => return Err(ExecutionError::new("db has no slots")),
+        };
+        self.device_reference(dev)?;
+        let slots = &mut self.slots[idx];
+        if slots.len() <= slot {
+            slots.resize_with(slot + 1, DeviceState::new);
+        }
+        Ok(&mut slots[slot])
+    }
+
+    /// Make `dev` ignore the program's writes, as if the cable to it were faulty, or stop doing so.
+    /// The `s` instructions still run and are still checked against the device's kind.
+    pub fn drop_writes(&mut self, dev:Device, dropping:bool)
+    {
+        self.dropping_writes.retain(|&d| d != dev);
+        if dropping {
+            self.dropping_writes.push(dev);
+        }
+    }
+
+    /// the line that will run next; after a fault, the line that faulted
+    pub fn instruction_pointer(&self) -> InstructionPointer
+    {
+        self.instruction_pointer
+    }
+
+    pub fn labels(&self) -> &HashMap<String, InstructionPointer>
+    {
+        &self.labels
+    }
+
+    /// the aliases the program has declared so far
+    pub fn aliases(&self) -> &HashMap<String, RegisterOrDevice>
+    {
+        &self.aliases
+    }
+
+    /// the defines the program has declared so far
+    pub fn defines(&self) -> &HashMap<String, f32>
+    {
+        &self.defines
+    }
+
+    /// the value the program sees for `name`: the register it is an alias of, or its define
+    pub fn alias_value(&self, name:&str) -> Result<f32, ExecutionError>
+    {
+        match self.aliases.get(name) {
+            Some(RegisterOrDevice::Register(reg)) => self.register_reference(*reg),
+            Some(RegisterOrDevice::Device(dev)) =>
+                Err(ExecutionError::new(&format!("{} is a device ({}) when I need a register", name, dev))),
+            None => match self.defines.get(name) {
+                Some(&val) => Ok(val),
+                None => Err(ExecutionError::new(&format!("unable to evaluate {}", name))),
+            },
+        }
+    }
+
+    /// store `value` in the register `name` is an alias of
+    pub fn set_alias_value(&mut self, name:&str, value:f32) -> Result<(), ExecutionError>
+    {
+        let reg = self.resolve_l_value(&LValue::Alias(name.to_string()))?;
+        *self.register_reference_mut(reg)? = value;
+        Ok(())
+    }
+
+    /// the device `name` is an alias of
+    pub fn device_by_alias(&mut self, name:&str) -> Result<&mut DeviceState, ExecutionError>
+    {
+        let dev = self.resolve_device(&AliasOrDevice::Alias(name.to_string()))?;
+        self.device_reference(dev)
+    }
+
+    /// how many instructions `execute_until_yields` has run on this context
+    pub fn instructions_executed(&self) -> u64
+    {
+        self.instructions_executed
+    }
+
+    /// how many ticks `execute_until_yields` has run on this context
+    pub fn ticks(&self) -> u64
+    {
+        self.ticks
+    }
+
+    pub fn stack(&self) -> &[f32]
+    {
+        &self.stack
+    }
+
+    pub fn stack_mut(&mut self) -> &mut [f32]
+    {
+        &mut self.stack
+    }
+
+    /// everything about this context except the program's labels, to restore later or save as JSON
+    pub fn snapshot(&self) -> ContextSnapshot
+    {
+        ContextSnapshot::capture(self)
+    }
+
+    /// put the context back as it was when `snapshot` was taken, slots and injected faults included;
+    /// only the attached inputs stay as they are.
+    /// Fails, leaving the context alone, if the snapshot was taken on a chip of a different shape
+    pub fn restore(&mut self, snapshot:&ContextSnapshot) -> Result<(), ExecutionError>
+    {
+        snapshot.restore_into(self)
+    }
+
     pub fn ip_plus_one(&mut self)
     {
         self.instruction_pointer+=1;
@@ -161,6 +417,12 @@ impl CPUContext
     {
         let idx = self.registers.len() - 1;
         self.registers[idx] = ptr as f32;
+        self.registers_written[idx] = true;
+    }
+
+    pub fn strictness(&self) -> Strictness
+    {
+        self.strictness
     }
 
     pub fn jump(&mut self, line_number:InstructionPointer)
@@ -170,7 +432,10 @@ impl CPUContext
 
     pub fn set_alias(&mut self, handle: &str, d_line: &RegisterOrDevice, incr_ip: bool)
     {
-        self.aliases.insert(handle.to_string(), *d_line);
+        match self.aliases.get_mut(handle) {
+            Some(old) => *old = *d_line,
+            None => { self.aliases.insert(handle.to_string(), *d_line); },
+        }
         if incr_ip {
             self.instruction_pointer += 1;
         }
@@ -178,12 +443,23 @@ impl CPUContext
 
     pub fn set_define(&mut self, tag: &str, value: f32, incr_ip: bool)
     {
-        self.defines.insert(tag.to_string(), value);
+        match self.defines.get_mut(tag) {
+            Some(old) => *old = value,
+            None => { self.defines.insert(tag.to_string(), value); },
+        }
         if incr_ip {
             self.instruction_pointer += 1;
         }
     }
 
+    pub fn device_kind(&self, dev:Device) -> Option<&'static DeviceKind>
+    {
+        match dev {
+            Device::Regular(idx) => self.device_kinds.get(idx as usize).copied().flatten(),
+            Device::SpecialB => None,
+        }
+    }
+
     pub fn device_reference(&mut self, dev:Device) -> Result<&mut DeviceState, ExecutionError>
     {
         match dev {
@@ -208,17 +484,32 @@ impl CPUContext
         let val = self.device_reference(dev)?.get(field);
         match val {
             Some(&val) => Ok(val),
+            None if !is_logic_type(field) => Err(ExecutionError::new(&unknown_name_message("logic type", field, LOGIC_TYPES))),
             None => Err(ExecutionError::new(&format!("device {} has no field {}", dev, field))),
         }
     }
 
     pub fn set_device(&mut self, device:Device, field: &str, value: f32) -> Result<(), ExecutionError>
     {
+        let line = self.instruction_pointer;
         self.instruction_pointer+=1;
+        if let Some(kind) = self.device_kind(device) {
+            if !kind.can_write(field) {
+                return Err(ExecutionError::new(&format!("{} on {} can not write {}", kind.prefab, device, field)));
+            }
+        }
+        if self.dropping_writes.contains(&device) {
+            return self.device_reference(device).map(|_| ());
+        }
         self.device_reference(device)
             .map(|dev|  {
-                dev.insert(field.to_string(), value);
-            })
+                match dev.get_mut(field) {
+                    Some(old) => *old = value,
+                    None => { dev.insert(field.to_string(), value); },
+                }
+            })?;
+        self.note_device_write(line, device, field, value);
+        Ok(())
         /*
         match self.device_reference(device) {
             Ok(mut dev) => {
@@ -262,36 +553,120 @@ impl CPUContext
         if (reg.idx as usize) >= self.registers.len() {
             return Err(ExecutionError::new(&format!("no register {}", reg)))
         }
+        self.registers_written[reg.idx as usize] = true;
         Ok(&mut self.registers[reg.idx as usize])
     }
 
+    /// a register's value as the program sees it through `operand`.
+    /// In a pedantic context it must have been written first.
+    fn read_register(&mut self, reg:Register, operand:&dyn std::fmt::Display) -> Result<f32, ExecutionError>
+    {
+        let val = self.register_reference(reg)?;
+        if !self.registers_written[reg.idx as usize] {
+            self.note_uninitialized_read(operand, Location::Register(reg));
+            if self.strictness == Strictness::Pedantic {
+                return Err(ExecutionError::new(&format!("{} is read before anything was written to it", reg)));
+            }
+        }
+        Ok(val)
+    }
+
+    fn note_uninitialized_read(&mut self, operand:&dyn std::fmt::Display, location:Location)
+    {
+        if self.uninitialized_reads.iter().all(|r| r.location != location) {
+            self.uninitialized_reads.push(UninitializedRead {
+                ip: self.instruction_pointer,
+                operand: operand.to_string(),
+                location,
+            });
+        }
+    }
+
+    fn note_device_write(&mut self, ip:InstructionPointer, device:Device, field:&str, value:f32)
+    {
+        // reuse the oldest entry's string, so a long run doesn't allocate for every write
+        let mut write = match self.device_writes.len() {
+            RECENT_DEVICE_WRITES => self.device_writes.pop_front().unwrap(),
+            _ => DeviceWrite { ip, device, field: String::new(), value },
+        };
+        write.ip = ip;
+        write.device = device;
+        write.field.clear();
+        write.field.push_str(field);
+        write.value = value;
+        self.device_writes.push_back(write);
+    }
+
+    /// the last few values the program stored in device fields, oldest first
+    pub fn recent_device_writes(&self) -> impl Iterator<Item=&DeviceWrite>
+    {
+        self.device_writes.iter()
+    }
+
+    /// the program this context was made for, if it was made with `new_simple` or `new_with_strictness`
+    pub fn program(&self) -> Option<&CompiledProgram>
+    {
+        self.program.as_ref()
+    }
+
+    /// the first read of each register or device field that nothing had written, in the order they happened
+    pub fn uninitialized_reads(&self) -> &[UninitializedRead]
+    {
+        &self.uninitialized_reads
+    }
+
+    /// fails with the first read of a register or device field that nothing had written
+    pub fn assert_no_uninitialized_reads(&self) -> Result<(), ExecutionError>
+    {
+        match self.uninitialized_reads.first() {
+            None => Ok(()),
+            Some(read) => Err(ExecutionError::new(&read.to_string())),
+        }
+    }
+
     pub fn load_device(&mut self, reg:Register, dev: Device, tag: &str) -> Result<(), ExecutionError>
+    {
+        self.load_device_as(reg, dev, tag, &dev)
+    }
+
+    /// `load_device`, where the program names the device `operand`
+    fn load_device_as(&mut self, reg:Register, dev: Device, tag: &str, operand: &dyn std::fmt::Display) -> Result<(), ExecutionError>
     {
         if (reg.idx as usize) >= self.registers.len() {
             return Err(ExecutionError::new(&format!("no register {}", reg)))
         }
-        match self.device_reference(dev) {
-            Ok(dev_state) => {
-                let maybe_val = dev_state.get(tag);
-                if false {
-                    // the javascript simulator doesn't work like this.  It just loads 0
-                    match maybe_val {
-                        None => Err(ExecutionError::new(&format!("{}[{}] has no value", dev, tag))),
-                        Some(&val) => {
-                            self.registers[reg.idx as usize] = val;
-                            self.ip_plus_one();
-                            Ok(())
-                        }
-                    }
-                } else {
-                    let val = maybe_val.unwrap_or(&0_f32);
-                    self.registers[reg.idx as usize] = *val;
-                    self.ip_plus_one();
-                    Ok(())
-                }
-            },
-            Err(e) => Err(e),
+        if let Some(kind) = self.device_kind(dev) {
+            if !kind.can_read(tag) {
+                return Err(ExecutionError::new(&format!("{} on {} can not read {}", kind.prefab, dev, tag)));
+            }
+        }
+        let mut maybe_val = self.device_reference(dev)?.get(tag).copied();
+        if let Some(val) = self.read_input(dev, tag) {
+            // keep the last value read where dumps and assertions can see it
+            let state = self.device_reference(dev)?;
+            match state.get_mut(tag) {
+                Some(old) => *old = val,
+                None => { state.insert(tag.to_string(), val); },
+            }
+            maybe_val = Some(val);
+        }
+        if let Some((_, _, val)) = self.stuck_fields.iter().find(|(d, f, _)| *d == dev && f == tag) {
+            maybe_val = Some(*val);
+        }
+        if maybe_val.is_none() {
+            self.note_uninitialized_read(operand, Location::DeviceField(dev, tag.to_string()));
         }
+        let val = match (maybe_val, self.strictness) {
+            (Some(val), _) => val,
+            // the javascript simulator just loads 0
+            (None, Strictness::Lenient) => 0.0,
+            (None, Strictness::GameAccurate) => return Err(ExecutionError::new(&format!("{}[{}] has no value", dev, tag))),
+            (None, Strictness::Pedantic) => return Err(ExecutionError::new(&format!("{}[{}] is read before anything was written to it", dev, tag))),
+        };
+        self.registers[reg.idx as usize] = val;
+        self.registers_written[reg.idx as usize] = true;
+        self.ip_plus_one();
+        Ok(())
 /*
         if (dev.idx as usize) < self.devices.len() {
             let devo = &self.devices[dev.idx as usize];
@@ -307,6 +682,70 @@ impl CPUContext
         */
     }
 
+    /// Make every read of `field` on `dev` take its value from `source`, replacing any source
+    /// already attached there.  Sources are not part of a snapshot.
+    pub fn attach_input(&mut self, dev:Device, field:&str, source:InputSource)
+    {
+        match self.inputs.iter_mut().find(|i| i.device == dev && i.field == field) {
+            Some(input) => input.source = source,
+            None => self.inputs.push(AttachedInput { device: dev, field: field.to_string(), source }),
+        }
+    }
+
+    /// go back to reading whatever value is stored in the field
+    pub fn detach_input(&mut self, dev:Device, field:&str)
+    {
+        self.inputs.retain(|i| !(i.device == dev && i.field == field));
+    }
+
+    /// Make every read of `field` on `dev` return `value`, as a broken sensor would, until
+    /// `unstick_field`.  This wins over an attached input, and leaves the stored value alone.
+    pub fn stick_field(&mut self, dev:Device, field:&str, value:f32)
+    {
+        self.unstick_field(dev, field);
+        self.stuck_fields.push((dev, field.to_string(), value));
+    }
+
+    pub fn unstick_field(&mut self, dev:Device, field:&str)
+    {
+        self.stuck_fields.retain(|(d, f, _)| !(*d == dev && f == field));
+    }
+
+    fn read_input(&mut self, dev:Device, field:&str) -> Option<f32>
+    {
+        if self.inputs.is_empty() {
+            return None;
+        }
+        let idx = self.inputs.iter().position(|i| i.device == dev && i.field == field)?;
+        // take the source out for the read, so a source can look at the rest of the context
+        let mut source = std::mem::replace(&mut self.inputs[idx].source, InputSource::sequence(None));
+        let rval = source.read(self);
+        self.inputs[idx].source = source;
+        rval
+    }
+
+    /// restart the values `rand` produces from `seed`, discarding any scripted values
+    pub fn set_random_seed(&mut self, seed:u64)
+    {
+        self.random = RandomSource::new(seed);
+    }
+
+    /// make the next `rand` instructions produce exactly these values
+    pub fn script_random<I:IntoIterator<Item=f32>>(&mut self, values:I)
+    {
+        self.random.script(values);
+    }
+
+    pub fn random_source(&self) -> &RandomSource
+    {
+        &self.random
+    }
+
+    pub fn next_random(&mut self) -> f32
+    {
+        self.random.next_value()
+    }
+
     pub fn yield_(&mut self)
     {
         self.saw_yield = true;
@@ -317,26 +756,121 @@ impl CPUContext
     {
         let rval = self.saw_yield;
         self.saw_yield = false;
-        return rval;
+        rval
+    }
+
+    /// the whole state, with registers and devices named by their aliases and fields in order
+    pub fn dump(&self) -> String
+    {
+        self.snapshot().to_string()
     }
 
     pub fn debug_dump(&self)
     {
-        println!("IP = {}", self.instruction_pointer);
-        println!("labels = {:?}", self.labels);
-        println!("aliases = {:?}", self.aliases);
-        println!("devices = {:?} db={:?}", self.devices, self.device_b);
-        println!("registers = {:?}", self.registers);
+        println!("{}", self.dump());
+    }
+
+    /// fails, listing every register, device field and stack slot that differs from `expected`;
+    /// the IP, clock, aliases and defines are not compared, so values reached another way still match
+    pub fn expect_values(&self, expected:&ContextSnapshot) -> Result<(), ExecutionError>
+    {
+        let diff = expected.diff(&self.snapshot());
+        if diff.is_empty() {
+            Ok(())
+        } else {
+            Err(ExecutionError::new(&format!("values differ from what was expected:\n{}", diff)))
+        }
     }
 }
 
 //
 
-#[derive(Debug,Clone)]
+/// an `InputSource` feeding one device field
+#[derive(Clone,Debug)]
+struct AttachedInput
+{
+    device: Device,
+    field: String,
+    source: InputSource,
+}
+
+//
+
+/// how many device writes a `CPUContext` remembers
+pub const RECENT_DEVICE_WRITES: usize = 8;
+
+/// A value the program stored in a device field
+#[derive(Clone,Debug,PartialEq)]
+pub struct DeviceWrite
+{
+    /// the line that wrote it
+    pub ip: InstructionPointer,
+    pub device: Device,
+    pub field: String,
+    pub value: f32,
+}
+
+impl std::fmt::Display for DeviceWrite
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "line {}: {} {} = {}", self.ip, self.device, self.field, self.value)
+    }
+}
+
+//
+
+/// Somewhere a program reads a value from
+#[derive(Clone,Debug,PartialEq)]
+pub enum Location
+{
+    Register(Register),
+    DeviceField(Device, String),
+}
+
+impl std::fmt::Display for Location
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Location::Register(reg) => write!(f, "{}", reg),
+            Location::DeviceField(dev, field) => write!(f, "{}[{}]", dev, field),
+        }
+    }
+}
+
+/// A read of a register or device field that nothing had written
+#[derive(Clone,Debug,PartialEq)]
+pub struct UninitializedRead
+{
+    /// the line doing the reading
+    pub ip: InstructionPointer,
+    /// the operand as the program wrote it, which may be an alias
+    pub operand: String,
+    pub location: Location,
+}
+
+impl std::fmt::Display for UninitializedRead
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let location = self.location.to_string();
+        if self.operand == location {
+            write!(f, "line {}: {} is read before anything was written to it", self.ip, location)
+        } else {
+            write!(f, "line {}: '{}' ({}) is read before anything was written to it", self.ip, self.operand, location)
+        }
+    }
+}
+
+//
+
+#[derive(Debug,Clone,PartialEq,Serialize)]
 pub enum LineNumber
 {
     Number(InstructionPointer),  // this can't be negative.  Should we allow that for relative branching?
     Label(String),
+    /// a label the compiler has looked up, kept so the op still prints as it was written
+    ResolvedLabel(String, InstructionPointer),
+    /// the line number held in a register, as in `j ra`
+    Register(Register),
 }
 
 impl LineNumber
@@ -345,10 +879,71 @@ impl LineNumber
     {
         if let Ok(number) = text.parse::<InstructionPointer>() {
             Ok(LineNumber::Number(number))
-        }  else {
+        } else if "ra" == text {
+            Ok(LineNumber::Register(Register::RA))
+        } else if let Ok(LValue::Register(reg)) = LValue::parse(text) {
+            Ok(LineNumber::Register(reg))
+        } else {
             Ok(LineNumber::Label(text.to_string()))
         }
     }
+
+    /// replace a label with the line number it names
+    pub fn resolve_labels(&mut self, labels:&LabelTable) -> Result<(), CompileError>
+    {
+        if let LineNumber::Label(label) = self {
+            match labels.get(label) {
+                Some(number) => *self = LineNumber::ResolvedLabel(label.clone(), number),
+                None => return Err(CompileError::for_token(label, &format!("undefined label '{}'", label))),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for LineNumber
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            LineNumber::Number(number) => write!(f, "{}", number),
+            LineNumber::Label(label) | LineNumber::ResolvedLabel(label, _) => write!(f, "{}", label),
+            LineNumber::Register(reg) if *reg == Register::RA => write!(f, "ra"),
+            LineNumber::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+//
+
+/// The labels of a program, for the second compiler pass.
+/// Names that are also declared by `alias` or `define` are left for the CPU to resolve at runtime.
+pub struct LabelTable
+{
+    labels: HashMap<String, InstructionPointer>,
+    declared_names: HashSet<String>,
+}
+
+impl LabelTable
+{
+    pub fn new(labels: HashMap<String, InstructionPointer>, declared_names: HashSet<String>) -> LabelTable
+    {
+        LabelTable { labels, declared_names }
+    }
+
+    pub fn get(&self, label:&str) -> Option<InstructionPointer>
+    {
+        self.labels.get(label).copied()
+    }
+
+    /// the value of `name` when used as an rvalue, if it is a label and not an alias or define
+    pub fn value_of(&self, name:&str) -> Option<InstructionPointer>
+    {
+        if self.declared_names.contains(name) {
+            None
+        } else {
+            self.get(name)
+        }
+    }
 }
 
 //
@@ -357,6 +952,33 @@ impl LineNumber
 pub struct CompileError
 {
     pub message: String,
+    /// the source token the error is about, used to compute a column span
+    token: Option<String>,
+}
+
+impl CompileError
+{
+    pub fn new(msg:&str) -> CompileError
+    {
+        CompileError{message:String::from(msg), token: None}
+    }
+
+    pub fn for_token(token:&str, msg:&str) -> CompileError
+    {
+        CompileError{message:String::from(msg), token: Some(token.to_string())}
+    }
+
+    pub fn token(&self) -> Option<&str>
+    {
+        self.token.as_deref()
+    }
+}
+
+impl std::fmt::Display for CompileError
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}", self.message)
+    }
 }
 
 #[derive(Debug)]
@@ -371,6 +993,18 @@ impl ExecutionError
     {
         ExecutionError{message:String::from(msg)}
     }
+
+    pub fn message(&self) -> &str
+    {
+        &self.message
+    }
+}
+
+impl std::fmt::Display for ExecutionError
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "{}", self.message)
+    }
 }
 
 //
@@ -398,12 +1032,38 @@ impl From<ExecutionError> for MultiError
 
 //
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
 pub struct Register
 {
     idx:u8
 }
 
+impl Register
+{
+    /// `ra`, where the `*al` instructions store the line to return to, on a chip with the usual 18
+    /// registers.  Jumps to it go to `CPUContext::get_ra`, the last register on any chip.
+    pub const RA: Register = Register { idx: 17 };
+
+    pub fn new(idx:u8) -> Register
+    {
+        Register { idx }
+    }
+
+    pub fn idx(&self) -> u8
+    {
+        self.idx
+    }
+
+    /// `r0`, `r1`, ...
+    pub fn parse(tag:&str) -> Result<Register, CompileError>
+    {
+        match LValue::parse(tag) {
+            Ok(LValue::Register(reg)) => Ok(reg),
+            _ => Err(CompileError::for_token(tag, "not a register")),
+        }
+    }
+}
+
 impl std::fmt::Display for Register
 {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
@@ -411,7 +1071,7 @@ impl std::fmt::Display for Register
     }
 }
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
 pub enum Device
 {
     Regular(u8),
@@ -420,22 +1080,28 @@ pub enum Device
 
 impl Device
 {
+    /// the device on pin `d{idx}`
+    pub fn new(idx:u8) -> Device
+    {
+        Device::Regular(idx)
+    }
+
     pub fn parse(tag:&str) -> Result<Device, CompileError>
     {
-        if tag.starts_with("d") {
+        if let Some(suffix) = tag.strip_prefix('d') {
             if "db" == tag {
                 return Ok(Device::SpecialB);
             }
-            let idx = tag[1..].parse::<u8>();
+            let idx = suffix.parse::<u8>();
             let idx = match idx {
                 Ok(number) => number,
                 Err(_) => {
-                    return Result::Err(CompileError { message: format!("couldn't parse data line reference {}", tag) });
+                    return Result::Err(CompileError::for_token(tag, &format!("couldn't parse data line reference {}", tag)));
                 }
             };
             Ok(Device::Regular(idx))
         } else {
-            Err(CompileError{message: format!("not a device")})
+            Err(CompileError::for_token(tag, "not a device"))
         }
     }
 }
@@ -450,7 +1116,7 @@ impl std::fmt::Display for Device
     }
 }
 
-#[derive(Copy,Clone,Debug)]
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
 pub enum RegisterOrDevice
 {
     Register(Register),
@@ -463,17 +1129,16 @@ impl RegisterOrDevice
     {
         if let Ok(dev) = Device::parse(tag) {
             Ok(RegisterOrDevice::Device(dev))
-        } else if tag.starts_with("r") {
-            let idx = tag[1..].parse::<u8>();
+        } else if let Some(suffix) = tag.strip_prefix('r') {
+            let idx = suffix.parse::<u8>();
             match idx {
                 Ok(number) =>
                     Ok( RegisterOrDevice::Register(Register{idx:number}) ),
-                Err(_) => {
-                    return Result::Err(CompileError{message: format!("couldn't parse data line reference {}", tag)});
-                }
+                Err(_) =>
+                    Err(CompileError::for_token(tag, &format!("couldn't parse data line reference {}", tag))),
             }
         } else {
-            Err(CompileError{message:"was expecting a register or data line reference".to_string()})
+            Err(CompileError::for_token(tag, "was expecting a register or data line reference"))
         }
     }
 }
@@ -492,10 +1157,24 @@ impl std::fmt::Display for RegisterOrDevice
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub enum AliasOrDevice
 {
     Alias(String),
     Device(Device),
+    /// an alias that always refers to the same device where it is used; see `resolve_names`
+    ResolvedAlias(String, Device),
+}
+
+impl std::fmt::Display for AliasOrDevice
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            AliasOrDevice::Alias(name) => write!(f, "{}", name),
+            AliasOrDevice::Device(dev) => write!(f, "{}", dev),
+            AliasOrDevice::ResolvedAlias(name, _) => write!(f, "{}", name),
+        }
+    }
 }
 
 impl AliasOrDevice
@@ -510,75 +1189,307 @@ impl AliasOrDevice
     }
 }
 
-//
-
-pub enum RValue
+//
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub enum RValue
+{
+    Number(f32),
+    Register(Register),
+    Name(String),
+    /// an alias that always refers to the same register where it is used; see `resolve_names`
+    ResolvedAlias(String, Register),
+    /// a define that always has the same value where it is used
+    ResolvedDefine(String, f32),
+}
+
+impl std::fmt::Display for RValue
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            RValue::Number(val) => write!(f, "{}", val),
+            RValue::Register(reg) => write!(f, "{}", reg),
+            RValue::Name(name) => write!(f, "{}", name),
+            RValue::ResolvedAlias(name, _) => write!(f, "{}", name),
+            RValue::ResolvedDefine(name, _) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl RValue
+{
+    pub fn parse(text: &str) -> Result<RValue, CompileError>
+    {
+        if let Ok(val) = text.parse::<f32>() {
+            Ok(RValue::Number(val))
+        } else if let Some(suffix) = text.strip_prefix('r') {
+            if let Ok(idx) = suffix.parse::<u8>() {
+                Ok(RValue::Register(Register{idx}))
+            } else {
+                Err(CompileError::for_token(text, &format!("unable to parse '{}' to a number or register", text)))
+            }
+        } else {
+            Ok(RValue::Name(text.to_string()))
+            //Err(CompileError{ message: format!("unable to parse '{}' to a number or register", text)})
+        }
+    }
+
+    /// a label used as a value is the number of the line it marks
+    pub fn resolve_labels(&mut self, labels:&LabelTable)
+    {
+        if let RValue::Name(name) = self {
+            if let Some(number) = labels.value_of(name) {
+                *self = RValue::Number(number as f32);
+            }
+        }
+    }
+}
+
+//
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub enum LValue
+{
+    Register(Register),
+    Alias(String),
+    /// an alias that always refers to the same register where it is used; see `resolve_names`
+    ResolvedAlias(String, Register),
+}
+
+impl std::fmt::Display for LValue
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            LValue::Register(reg) => write!(f, "{}", reg),
+            LValue::Alias(name) => write!(f, "{}", name),
+            LValue::ResolvedAlias(name, _) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl LValue
+{
+    pub fn parse(text:&str) -> Result<LValue, CompileError>
+    {
+        if let Some(suffix) = text.strip_prefix('r') {
+            if let Ok(val) = suffix.parse::<u8>() {
+                return Ok(LValue::Register(Register{idx:val}));
+            }
+        }
+        Ok(LValue::Alias(text.to_string()))
+    }
+}
+
+//
+
+/// How an instruction runs.  Everything the compiler understands is an `Op`;
+/// put anything else in an `Op::Extension`.
+pub trait Instruction
+{
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>;
+
+    /// second compiler pass: replace references to labels with line numbers
+    fn resolve_labels(&mut self, _labels: &LabelTable) -> Result<(), CompileError>
+    {
+        Ok(())
+    }
+}
+
+//
+
+/// One compiled line.  Every instruction the compiler understands is plain data,
+/// so a program can be inspected, compared, cloned, serialized and printed back out as source.
+/// Serializing is one way: an `Extension` is written as a bare tag, as there is nothing to read it back into.
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub enum Op
+{
+    NoCode(NoCode),
+    UnrecognizedOpcode(UnrecognizedOpcode),
+    CompileFailure(CompileFailure),
+    Jump(Jump),
+    Alias(Alias),
+    Define(Define),
+    SetDevice(SetDevice),
+    LoadDevice(LoadDevice),
+    Move(Move),
+    Unary(UnaryOperator),
+    Binary(BinaryOperator),
+    Ternary(TernaryOperator),
+    Random(Random),
+    Branch(Branch),
+    BranchDevice(BranchDevice),
+    BranchTernary(BranchTernary),
+    Yield(Yield),
+    Extension(Extension),
+}
+
+impl Op
+{
+    pub fn as_instruction(&self) -> &dyn Instruction
+    {
+        match self {
+            Op::NoCode(op) => op,
+            Op::UnrecognizedOpcode(op) => op,
+            Op::CompileFailure(op) => op,
+            Op::Jump(op) => op,
+            Op::Alias(op) => op,
+            Op::Define(op) => op,
+            Op::SetDevice(op) => op,
+            Op::LoadDevice(op) => op,
+            Op::Move(op) => op,
+            Op::Unary(op) => op,
+            Op::Binary(op) => op,
+            Op::Ternary(op) => op,
+            Op::Random(op) => op,
+            Op::Branch(op) => op,
+            Op::BranchDevice(op) => op,
+            Op::BranchTernary(op) => op,
+            Op::Yield(op) => op,
+            Op::Extension(op) => &*op.0,
+        }
+    }
+
+    /// the mnemonic this was compiled from; empty for a line with no code
+    pub fn opcode(&self) -> &'static str
+    {
+        match self {
+            Op::NoCode(_) => "",
+            Op::UnrecognizedOpcode(_) => "?",
+            Op::CompileFailure(_) => "?",
+            Op::Jump(op) => if op.link { "jal" } else { "j" },
+            Op::Alias(_) => "alias",
+            Op::Define(_) => "define",
+            Op::SetDevice(_) => "s",
+            Op::LoadDevice(_) => "l",
+            Op::Move(_) => "move",
+            Op::Unary(op) => op.op.opcode(),
+            Op::Binary(op) => op.op.opcode(),
+            Op::Ternary(op) => op.op.opcode(),
+            Op::Random(_) => "rand",
+            Op::Branch(op) => op.opcode(),
+            Op::BranchDevice(op) => op.opcode(),
+            Op::BranchTernary(op) => op.opcode(),
+            Op::Yield(_) => "yield",
+            Op::Extension(_) => "?",
+        }
+    }
+}
+
+impl Instruction for Op
 {
-    Number(f32),
-    Register(Register),
-    Name(String),
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
+    {
+        self.as_instruction().execute(ctx)
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError>
+    {
+        match self {
+            Op::Jump(op) => op.resolve_labels(labels),
+            Op::SetDevice(op) => op.resolve_labels(labels),
+            Op::Move(op) => op.resolve_labels(labels),
+            Op::Unary(op) => op.resolve_labels(labels),
+            Op::Binary(op) => op.resolve_labels(labels),
+            Op::Ternary(op) => op.resolve_labels(labels),
+            Op::Branch(op) => op.resolve_labels(labels),
+            Op::BranchDevice(op) => op.resolve_labels(labels),
+            Op::BranchTernary(op) => op.resolve_labels(labels),
+            Op::Extension(op) => match Arc::get_mut(&mut op.0) {
+                Some(inner) => inner.resolve_labels(labels),
+                None => Err(CompileError::new("a shared extension instruction can not resolve labels")),
+            },
+            _ => Ok(()),
+        }
+    }
 }
 
-impl RValue
+/// prints the line of source the op was compiled from (give or take whitespace and comments)
+impl std::fmt::Display for Op
 {
-    pub fn parse(text: &str) -> Result<RValue, CompileError>
-    {
-        if let Ok(val) = text.parse::<f32>() {
-            Ok(RValue::Number(val))
-        } else if text.starts_with("r") {
-            if let Ok(idx) = text[1..].parse::<u8>() {
-                Ok(RValue::Register(Register{idx:idx}))
-            } else {
-                Err(CompileError{ message: format!("unable to parse '{}' to a number or register", text)})
-            }
-        } else {
-            Ok(RValue::Name(text.to_string()))
-            //Err(CompileError{ message: format!("unable to parse '{}' to a number or register", text)})
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            Op::NoCode(_) => Ok(()),
+            Op::UnrecognizedOpcode(op) => write!(f, "{}", op.opcode),
+            Op::CompileFailure(op) => write!(f, "# failed to compile: {}", op.message),
+            Op::Jump(op) => write!(f, "{} {}", self.opcode(), op.line_number),
+            Op::Alias(op) => write!(f, "alias {} {}", op.handle, op.d_line),
+            Op::Define(op) => write!(f, "define {} {}", op.tag, op.value),
+            Op::SetDevice(op) => write!(f, "s {} {} {}", op.device, op.field, op.r_value),
+            Op::LoadDevice(op) => write!(f, "l {} {} {}", op.l_value, op.device, op.field),
+            Op::Move(op) => write!(f, "move {} {}", op.l_value, op.r_value),
+            Op::Unary(op) => write!(f, "{} {} {}", self.opcode(), op.l_value, op.arg1),
+            Op::Binary(op) => write!(f, "{} {} {} {}", self.opcode(), op.l_value, op.arg1, op.arg2),
+            Op::Ternary(op) => write!(f, "{} {} {} {} {}", self.opcode(), op.l_value, op.arg1, op.arg2, op.arg3),
+            Op::Random(op) => write!(f, "rand {}", op.l_value),
+            Op::Branch(op) => write!(f, "{} {} {} {}", self.opcode(), op.arg1, op.arg2, op.target),
+            Op::BranchDevice(op) => write!(f, "{} {} {}", self.opcode(), op.dev, op.target),
+            Op::BranchTernary(op) => write!(f, "{} {} {} {} {}", self.opcode(), op.arg1, op.arg2, op.frac, op.target),
+            Op::Yield(_) => write!(f, "yield"),
+            Op::Extension(_) => write!(f, "# extension instruction"),
         }
     }
 }
 
-//
+/// An instruction from outside this crate, shared between copies of the program
+#[derive(Clone)]
+pub struct Extension(pub Arc<dyn Instruction + Send + Sync>);
 
-pub enum LValue
+impl std::fmt::Debug for Extension
 {
-    Register(Register),
-    Alias(String),
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "Extension(..)")
+    }
 }
 
-impl LValue
+impl Serialize for Extension
 {
-    pub fn parse(text:&str) -> Result<LValue, CompileError>
-    {
-        if text.starts_with("r") {
-            if let Ok(val) = text[1..].parse::<u8>() {
-                return Ok(LValue::Register(Register{idx:val}));
-            }
-        }
-        Ok(LValue::Alias(text.to_string()))
+    fn serialize<S:Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_struct("Extension")
     }
 }
 
-//
-
-pub trait Instruction
+/// two extensions are equal when they are the same instruction
+impl PartialEq for Extension
 {
-    fn execute(&self, ctx: CPUContext) -> Result<CPUContext, ExecutionError>;
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
+impl From<NoCode> for Op { fn from(op: NoCode) -> Self { Op::NoCode(op) } }
+impl From<UnrecognizedOpcode> for Op { fn from(op: UnrecognizedOpcode) -> Self { Op::UnrecognizedOpcode(op) } }
+impl From<CompileFailure> for Op { fn from(op: CompileFailure) -> Self { Op::CompileFailure(op) } }
+impl From<Jump> for Op { fn from(op: Jump) -> Self { Op::Jump(op) } }
+impl From<Alias> for Op { fn from(op: Alias) -> Self { Op::Alias(op) } }
+impl From<Define> for Op { fn from(op: Define) -> Self { Op::Define(op) } }
+impl From<SetDevice> for Op { fn from(op: SetDevice) -> Self { Op::SetDevice(op) } }
+impl From<LoadDevice> for Op { fn from(op: LoadDevice) -> Self { Op::LoadDevice(op) } }
+impl From<Move> for Op { fn from(op: Move) -> Self { Op::Move(op) } }
+impl From<UnaryOperator> for Op { fn from(op: UnaryOperator) -> Self { Op::Unary(op) } }
+impl From<BinaryOperator> for Op { fn from(op: BinaryOperator) -> Self { Op::Binary(op) } }
+impl From<TernaryOperator> for Op { fn from(op: TernaryOperator) -> Self { Op::Ternary(op) } }
+impl From<Random> for Op { fn from(op: Random) -> Self { Op::Random(op) } }
+impl From<Branch> for Op { fn from(op: Branch) -> Self { Op::Branch(op) } }
+impl From<BranchDevice> for Op { fn from(op: BranchDevice) -> Self { Op::BranchDevice(op) } }
+impl From<BranchTernary> for Op { fn from(op: BranchTernary) -> Self { Op::BranchTernary(op) } }
+impl From<Yield> for Op { fn from(op: Yield) -> Self { Op::Yield(op) } }
+impl From<Extension> for Op { fn from(op: Extension) -> Self { Op::Extension(op) } }
+
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct NoCode {}
 
 impl Instruction for NoCode
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         ctx.ip_plus_one();
-        Ok(ctx)
+        Ok(())
     }
 }
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct UnrecognizedOpcode
 {
     opcode:String,
@@ -586,50 +1497,71 @@ pub struct UnrecognizedOpcode
 
 impl Instruction for UnrecognizedOpcode
 {
-    fn execute(&self, _ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, _ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         Err( ExecutionError::new(&format!("unrecognized opcode {}", self.opcode)) )
     }
 }
 
+/// stands in for a line that failed to compile, so the rest of the program can still run
+#[derive(Clone,Debug,PartialEq,Serialize)]
+pub struct CompileFailure
+{
+    message:String,
+}
+
+impl Instruction for CompileFailure
+{
+    fn execute(&self, _ctx: &mut CPUContext) -> Result<(), ExecutionError>
+    {
+        Err( ExecutionError::new(&format!("line failed to compile: {}", self.message)) )
+    }
+}
+
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Jump
 {
-    line_number: LineNumber
+    line_number: LineNumber,
+    /// `jal`, which stores the next line in `ra`
+    link: bool,
 }
 
 impl Jump
 {
-    pub fn new<'a,I>(mut parts: I) ->Result<Jump, CompileError>
+    pub fn new<'a,I>(parts: I) ->Result<Jump, CompileError>
+        where I:Iterator<Item=&'a str>
+    {
+        Jump::parse(parts, false)
+    }
+
+    pub fn jal<'a,I>(parts: I) ->Result<Jump, CompileError>
+        where I:Iterator<Item=&'a str>
+    {
+        Jump::parse(parts, true)
+    }
+
+    fn parse<'a,I>(mut parts: I, link: bool) ->Result<Jump, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        let generic_error = "'j' jump instruction requires 1 argument of line number or label".to_string();
+        let generic_error = format!("'{}' jump instruction requires 1 argument of line number or label",
+                                    if link { "jal" } else { "j" });
         let tgt= parts.next();
         //println!("tgt = {:?}", tgt);
         match tgt {
-            None => Err(CompileError { message: generic_error}),
+            None => Err(CompileError::new(&generic_error)),
             Some(val) => {
 
                 let expect_none = parts.next();
                 //println!("none = {:?}", expect_none);
-                match expect_none {
-                    Some(_) => return Err(CompileError { message: generic_error}),
-                    None => {}
+                if expect_none.is_some() {
+                    return Err(CompileError::new(&generic_error));
                 }
 
-                let a = val.parse::<InstructionPointer>();
-
-                let line_number = match a {
-                    Ok(number) => {
-                        LineNumber::Number(number)
-                    },
-                    Err(_) => {
-                        LineNumber::Label(String::from(val))
-                    }
-                };
+                let line_number = LineNumber::parse(val)?;
 
-                Ok( Jump{ line_number: line_number} )
+                Ok( Jump{ line_number, link } )
 
             }
         }
@@ -639,10 +1571,17 @@ impl Jump
 
 impl Instruction for Jump
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         let line_number = ctx.lookup(&self.line_number)?;
+        if self.link {
+            ctx.set_ra(ctx.instruction_pointer+1);
+        }
         ctx.jump(line_number);
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.line_number.resolve_labels(labels)
     }
 }
 
@@ -657,7 +1596,7 @@ pub fn expect_1<'a, I>(mut parts: I) -> Result<String, CompileError>
     if let (Some(one), None) = (one, doom) {
         Ok(one.to_string())
     } else {
-        Err(CompileError{ message: "expected 2 arguments".to_string()})
+        Err(CompileError::new("expected 1 argument"))
     }
 }
 
@@ -671,7 +1610,7 @@ pub fn expect_2<'a, I>(mut parts: I) -> Result<(String, String), CompileError>
     if let (Some(one), Some(two), None) = (one,two, doom) {
         Ok((one.to_string(),two.to_string()))
     } else {
-        Err(CompileError{ message: "expected 2 arguments".to_string()})
+        Err(CompileError::new("expected 2 arguments"))
     }
 }
 
@@ -686,7 +1625,7 @@ pub fn expect_3<'a, I>(mut parts: I) -> Result<(String, String, String), Compile
     if let (Some(one), Some(two), Some(three), None) = (one,two, three, doom) {
         Ok((one.to_string(),two.to_string(), three.to_string()))
     } else {
-        Err(CompileError{ message: "expected 3 arguments".to_string()})
+        Err(CompileError::new("expected 3 arguments"))
     }
 }
 
@@ -702,12 +1641,13 @@ pub fn expect_4<'a, I>(mut parts: I) -> Result<(String, String, String, String),
     if let (Some(one), Some(two), Some(three), Some(four), None) = (one,two,three,four, doom) {
         Ok((one.to_string(),two.to_string(), three.to_string(), four.to_string()))
     } else {
-        Err(CompileError{ message: "expected 4 arguments".to_string()})
+        Err(CompileError::new("expected 4 arguments"))
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Alias
 {
     handle: String,
@@ -721,20 +1661,21 @@ impl Alias
     {
         let (label, d_line) = expect_2(parts)?;
         let d_line = RegisterOrDevice::parse(&d_line)?;
-        Ok(Alias { handle:label, d_line:d_line})
+        Ok(Alias { handle:label, d_line })
     }
 }
 
 impl Instruction for Alias
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         ctx.set_alias(&self.handle, &self.d_line, true);
-        Ok(ctx)
+        Ok(())
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Define
 {
     tag:String,
@@ -750,22 +1691,23 @@ impl Define
         let (tag, value) = expect_2(parts)?;
         match value.parse::<f32>() {
             Ok(value) =>
-                Ok(Define { tag: tag, value: value }),
-            Err(_) => Err(CompileError{message:format!("failed to parse value '{}' in define", value)}),
+                Ok(Define { tag, value }),
+            Err(_) => Err(CompileError::for_token(&value, &format!("failed to parse value '{}' in define", value))),
         }
     }
 }
 
 impl Instruction for Define
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         ctx.set_define(&self.tag, self.value, true);
-        Ok(ctx)
+        Ok(())
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct SetDevice
 {
     device: AliasOrDevice,
@@ -790,16 +1732,23 @@ impl SetDevice
 
 impl Instruction for SetDevice
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
+        let value = ctx.resolve_r_value(&self.r_value)?;
         ctx.set_device(ctx.resolve_device(&self.device)?,
                        &self.field,
-                       ctx.resolve_r_value(&self.r_value)?)?;
-        Ok(ctx)
+                       value)?;
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.r_value.resolve_labels(labels);
+        Ok(())
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct LoadDevice
 {
     l_value: LValue,
@@ -824,17 +1773,19 @@ impl LoadDevice
 
 impl Instruction for LoadDevice
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
-        ctx.load_device(ctx.resolve_l_value(&self.l_value)?,
-                        ctx.resolve_device(&self.device)?,
-                        &self.field)?;
-        Ok(ctx)
+        ctx.load_device_as(ctx.resolve_l_value(&self.l_value)?,
+                           ctx.resolve_device(&self.device)?,
+                           &self.field,
+                           &self.device)?;
+        Ok(())
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Move
 {
     l_value: LValue,
@@ -858,115 +1809,228 @@ impl Move
 
 impl Instruction for Move
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         let src = ctx.resolve_r_value(&self.r_value)?;
         let dst = ctx.resolve_l_value(&self.l_value)?;
         *ctx.register_reference_mut(dst)? = src;
         ctx.ip_plus_one();
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.r_value.resolve_labels(labels);
+        Ok(())
     }
 }
 
 //
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum UnaryOp
+{
+    Abs,
+    Ceil,
+    Exp,
+    Floor,
+    Log,
+    Round,
+    Sqrt,
+    Trunc,
+}
+
+impl UnaryOp
+{
+    pub fn apply(self, a:f32) -> f32
+    {
+        match self {
+            UnaryOp::Abs => a.abs(),
+            UnaryOp::Ceil => a.ceil(),
+            UnaryOp::Exp => a.exp(),
+            UnaryOp::Floor => a.floor(),
+            UnaryOp::Log => a.ln(),
+            UnaryOp::Round => math::round::half_to_even(a as f64, 0) as f32,
+            UnaryOp::Sqrt => a.sqrt(),
+            UnaryOp::Trunc => a.trunc(),
+        }
+    }
+
+    pub fn opcode(self) -> &'static str
+    {
+        match self {
+            UnaryOp::Abs => "abs",
+            UnaryOp::Ceil => "ceil",
+            UnaryOp::Exp => "exp",
+            UnaryOp::Floor => "floor",
+            UnaryOp::Log => "log",
+            UnaryOp::Round => "round",
+            UnaryOp::Sqrt => "sqrt",
+            UnaryOp::Trunc => "trunc",
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct UnaryOperator
 {
     l_value: LValue,
     arg1: RValue,
-    op: Box<dyn Fn(f32)->f32>,
+    op: UnaryOp,
 }
 
 impl UnaryOperator
 {
-    pub fn new<'a, I, F>(parts: I , op:F) -> Result<UnaryOperator, CompileError>
-        where I:Iterator<Item=&'a str>,
-              F: Fn(f32)->f32 +'static
+    pub fn new<'a, I>(parts: I , op:UnaryOp) -> Result<UnaryOperator, CompileError>
+        where I:Iterator<Item=&'a str>
     {
         let (l_value, arg1) = expect_2(parts)?;
 
         Ok(UnaryOperator{
             l_value: LValue::parse(&l_value)?,
             arg1: RValue::parse(&arg1)?,
-            op: Box::new(op),
+            op,
         })
     }
 
     pub fn abs<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.abs())
+        UnaryOperator::new(parts, UnaryOp::Abs)
     }
 
     pub fn ceil<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.ceil())
+        UnaryOperator::new(parts, UnaryOp::Ceil)
     }
 
     pub fn exp<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.exp())
+        UnaryOperator::new(parts, UnaryOp::Exp)
     }
 
     pub fn floor<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.floor())
+        UnaryOperator::new(parts, UnaryOp::Floor)
     }
 
     pub fn log<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.ln())
+        UnaryOperator::new(parts, UnaryOp::Log)
     }
 
     pub fn round<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| math::round::half_to_even(a as f64, 0) as f32)
+        UnaryOperator::new(parts, UnaryOp::Round)
     }
 
     pub fn sqrt<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.sqrt() )
+        UnaryOperator::new(parts, UnaryOp::Sqrt)
     }
 
     pub fn trunc<'a, I>(parts:I) -> Result<UnaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        UnaryOperator::new(parts, |a| a.trunc() )
+        UnaryOperator::new(parts, UnaryOp::Trunc)
     }
 }
 
 impl Instruction for UnaryOperator
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         let a = ctx.resolve_r_value(&self.arg1)?;
         let dst = ctx.register_reference_mut(ctx.resolve_l_value(&self.l_value)?)?;
-        *dst = (self.op)(a);
+        *dst = self.op.apply(a);
         ctx.ip_plus_one();
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.arg1.resolve_labels(labels);
+        Ok(())
     }
 }
 
 //
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum BinaryOp
+{
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Max,
+    Min,
+    Slt,
+    Sgt,
+    And,
+    Nor,
+    Or,
+    Xor,
+}
+
+impl BinaryOp
+{
+    pub fn apply(self, a:f32, b:f32) -> f32
+    {
+        match self {
+            BinaryOp::Add => a+b,
+            BinaryOp::Sub => a-b,
+            BinaryOp::Mul => a*b,
+            BinaryOp::Div => a/b,
+            BinaryOp::Mod => a%b,
+            BinaryOp::Max => a.max(b),
+            BinaryOp::Min => a.min(b),
+            BinaryOp::Slt => if a<b { 1.0 } else {0.0},
+            BinaryOp::Sgt => if a>b { 1.0 } else {0.0},
+            BinaryOp::And => if (a!=0.0) && (b!=0.0) { 1.0 } else { 0.0 },
+            BinaryOp::Nor => if (a != 0.0) || (b != 0.0) { 0.0 } else { 1.0 },
+            BinaryOp::Or => if (a != 0.0) || (b != 0.0) { 1.0 } else { 0.0 },
+            BinaryOp::Xor => if (a != 0.0) != (b != 0.0) { 1.0 } else { 0.0 },
+        }
+    }
+
+    pub fn opcode(self) -> &'static str
+    {
+        match self {
+            BinaryOp::Add => "add",
+            BinaryOp::Sub => "sub",
+            BinaryOp::Mul => "mul",
+            BinaryOp::Div => "div",
+            BinaryOp::Mod => "mod",
+            BinaryOp::Max => "max",
+            BinaryOp::Min => "min",
+            BinaryOp::Slt => "slt",
+            BinaryOp::Sgt => "sgt",
+            BinaryOp::And => "and",
+            BinaryOp::Nor => "nor",
+            BinaryOp::Or => "or",
+            BinaryOp::Xor => "xor",
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct BinaryOperator
 {
     l_value: LValue,
     arg1: RValue,
     arg2: RValue,
-    op: Box<dyn Fn(f32,f32)->f32>,
+    op: BinaryOp,
 }
 
 impl BinaryOperator
 {
-    pub fn new<'a, I, F>(parts: I , op:F) -> Result<BinaryOperator, CompileError>
-        where I:Iterator<Item=&'a str>,
-              F: Fn(f32,f32)->f32 +'static
+    pub fn new<'a, I>(parts: I , op:BinaryOp) -> Result<BinaryOperator, CompileError>
+        where I:Iterator<Item=&'a str>
     {
         let (l_value, arg1, arg2) = expect_3(parts)?;
 
@@ -974,118 +2038,147 @@ impl BinaryOperator
             l_value: LValue::parse(&l_value)?,
             arg1: RValue::parse(&arg1)?,
             arg2: RValue::parse(&arg2)?,
-            op: Box::new(op),
+            op,
         })
     }
 
     pub fn add<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a+b)
+        BinaryOperator::new(parts, BinaryOp::Add)
     }
 
     pub fn multiply<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a*b)
+        BinaryOperator::new(parts, BinaryOp::Mul)
     }
 
     pub fn sub<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a-b)
+        BinaryOperator::new(parts, BinaryOp::Sub)
     }
 
     pub fn div<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a/b)
+        BinaryOperator::new(parts, BinaryOp::Div)
     }
 
     pub fn modulus<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a%b)
+        BinaryOperator::new(parts, BinaryOp::Mod)
     }
 
     pub fn max<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a.max(b))
+        BinaryOperator::new(parts, BinaryOp::Max)
     }
 
     pub fn min<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| a.min(b))
+        BinaryOperator::new(parts, BinaryOp::Min)
     }
 
     pub fn slt<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| if a<b { 1.0 } else {0.0} )
+        BinaryOperator::new(parts, BinaryOp::Slt)
     }
 
     pub fn sgt<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| if a>b { 1.0 } else {0.0} )
+        BinaryOperator::new(parts, BinaryOp::Sgt)
     }
 
     pub fn and<'a, I>(parts:I) -> Result<BinaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a,b| if (a!=0.0) && (b!=0.0) { 1.0 } else { 0.0 } )
+        BinaryOperator::new(parts, BinaryOp::And)
     }
 
     pub fn nor<'a, I>(parts: I) -> Result<BinaryOperator, CompileError>
         where I: Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a, b| if (a != 0.0) || (b != 0.0) { 0.0 } else { 1.0 })
+        BinaryOperator::new(parts, BinaryOp::Nor)
     }
 
     pub fn or<'a, I>(parts: I) -> Result<BinaryOperator, CompileError>
         where I: Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a, b| if (a != 0.0) || (b != 0.0) { 1.0 } else { 0.0 })
+        BinaryOperator::new(parts, BinaryOp::Or)
     }
 
     pub fn xor<'a, I>(parts: I) -> Result<BinaryOperator, CompileError>
         where I: Iterator<Item=&'a str>
     {
-        BinaryOperator::new(parts, |a, b| if (a != 0.0) != (b != 0.0) { 1.0 } else { 0.0 })
+        BinaryOperator::new(parts, BinaryOp::Xor)
     }
 }
 
 impl Instruction for BinaryOperator
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         let a = ctx.resolve_r_value(&self.arg1)?;
         let b = ctx.resolve_r_value(&self.arg2)?;
         let dst = ctx.register_reference_mut(ctx.resolve_l_value(&self.l_value)?)?;
-        *dst = (self.op)(a, b);
+        *dst = self.op.apply(a, b);
         ctx.ip_plus_one();
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.arg1.resolve_labels(labels);
+        self.arg2.resolve_labels(labels);
+        Ok(())
     }
 }
 
 //
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum TernaryOp
+{
+    Select,
+}
+
+impl TernaryOp
+{
+    pub fn apply(self, a:f32, b:f32, c:f32) -> f32
+    {
+        match self {
+            TernaryOp::Select => if a!=0.0 {b} else {c},
+        }
+    }
+
+    pub fn opcode(self) -> &'static str
+    {
+        match self {
+            TernaryOp::Select => "select",
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct TernaryOperator
 {
     l_value: LValue,
     arg1: RValue,
     arg2: RValue,
     arg3: RValue,
-    op: Box<dyn Fn(f32,f32,f32)->f32>,
+    op: TernaryOp,
 }
 
 impl TernaryOperator
 {
-    pub fn new<'a, I, F>(parts: I , op:F) -> Result<TernaryOperator, CompileError>
-        where I:Iterator<Item=&'a str>,
-              F: Fn(f32,f32,f32)->f32 +'static
+    pub fn new<'a, I>(parts: I , op:TernaryOp) -> Result<TernaryOperator, CompileError>
+        where I:Iterator<Item=&'a str>
     {
         let (l_value, arg1, arg2, arg3) = expect_4(parts)?;
 
@@ -1094,34 +2187,42 @@ impl TernaryOperator
             arg1: RValue::parse(&arg1)?,
             arg2: RValue::parse(&arg2)?,
             arg3: RValue::parse(&arg3)?,
-            op: Box::new(op),
+            op,
         })
     }
 
     pub fn select<'a, I>(parts:I) -> Result<TernaryOperator, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        TernaryOperator::new(parts, |a,b, c| if a!=0.0 {b} else {c} )
+        TernaryOperator::new(parts, TernaryOp::Select)
     }
 
 }
 
 impl Instruction for TernaryOperator
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         let a = ctx.resolve_r_value(&self.arg1)?;
         let b = ctx.resolve_r_value(&self.arg2)?;
         let c = ctx.resolve_r_value(&self.arg3)?;
         let dst = ctx.register_reference_mut(ctx.resolve_l_value(&self.l_value)?)?;
-        *dst = (self.op)(a, b, c);
+        *dst = self.op.apply(a, b, c);
         ctx.ip_plus_one();
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.arg1.resolve_labels(labels);
+        self.arg2.resolve_labels(labels);
+        self.arg3.resolve_labels(labels);
+        Ok(())
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Random
 {
     l_value: LValue,
@@ -1142,16 +2243,18 @@ impl Random
 
 impl Instruction for Random
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         let x = ctx.resolve_l_value(&self.l_value)?;
-        *ctx.register_reference_mut(x)? = rand::random::<f32>();
-
-        Ok(ctx)
+        let val = ctx.next_random();
+        *ctx.register_reference_mut(x)? = val;
+        ctx.ip_plus_one();
+        Ok(())
     }
 }
 
 //
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
 pub enum JumpStyle
 {
     Abs,
@@ -1159,20 +2262,38 @@ pub enum JumpStyle
     AL, // ? and link
 }
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum BranchCondition
+{
+    Eq,
+    Gt,
+}
+
+impl BranchCondition
+{
+    pub fn test(self, a:f32, b:f32) -> bool
+    {
+        match self {
+            BranchCondition::Eq => a==b,
+            BranchCondition::Gt => a>b,
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Branch
 {
     arg1: RValue,
     arg2: RValue,
     target: LineNumber,
-    op: Box<dyn Fn(f32,f32)->bool>,
+    op: BranchCondition,
     style: JumpStyle,
 }
 
 impl Branch
 {
-    pub fn new<'a, I, F>(parts: I , op:F, style:JumpStyle) -> Result<Branch, CompileError>
-        where I:Iterator<Item=&'a str>,
-              F: Fn(f32,f32)->bool +'static
+    pub fn new<'a, I>(parts: I , op:BranchCondition, style:JumpStyle) -> Result<Branch, CompileError>
+        where I:Iterator<Item=&'a str>
     {
         let (arg1, arg2, target) = expect_3(parts)?;
 
@@ -1180,39 +2301,50 @@ impl Branch
             arg1: RValue::parse(&arg1)?,
             arg2: RValue::parse(&arg2)?,
             target: LineNumber::parse(&target)?,
-            op: Box::new(op),
-            style:style,
+            op,
+            style,
         })
     }
 
     pub fn eq<'a, I>(parts:I) -> Result<Branch, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        Branch::new(parts, |a,b| a==b, JumpStyle::Abs)
+        Branch::new(parts, BranchCondition::Eq, JumpStyle::Abs)
     }
 
     pub fn eqal<'a, I>(parts:I) -> Result<Branch, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        Branch::new(parts, |a,b| a==b, JumpStyle::AL)
+        Branch::new(parts, BranchCondition::Eq, JumpStyle::AL)
     }
 
     pub fn gt<'a, I>(parts:I) -> Result<Branch, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        Branch::new(parts, |a,b| a>b, JumpStyle::Abs)
+        Branch::new(parts, BranchCondition::Gt, JumpStyle::Abs)
     }
 
+    pub fn opcode(&self) -> &'static str
+    {
+        match (self.op, self.style) {
+            (BranchCondition::Eq, JumpStyle::Abs) => "beq",
+            (BranchCondition::Eq, JumpStyle::Rel) => "breq",
+            (BranchCondition::Eq, JumpStyle::AL) => "beqal",
+            (BranchCondition::Gt, JumpStyle::Abs) => "bgt",
+            (BranchCondition::Gt, JumpStyle::Rel) => "brgt",
+            (BranchCondition::Gt, JumpStyle::AL) => "bgtal",
+        }
+    }
 }
 
 impl Instruction for Branch
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         let a = ctx.resolve_r_value(&self.arg1)?;
         let b = ctx.resolve_r_value(&self.arg2)?;
 
-        let result = (self.op)(a,b);
+        let result = self.op.test(a,b);
         if result {
             let target = ctx.lookup(&self.target)?;
             ctx.instruction_pointer = match self.style {
@@ -1226,42 +2358,65 @@ impl Instruction for Branch
         } else {
             ctx.instruction_pointer += 1;
         }
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.arg1.resolve_labels(labels);
+        self.arg2.resolve_labels(labels);
+        self.target.resolve_labels(labels)
     }
 }
 
 //
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum DeviceCondition
+{
+    NotSet,
+    Set,
+}
+
+impl DeviceCondition
+{
+    pub fn test(self, ctx:&CPUContext, dev:Device) -> Result<bool, ExecutionError>
+    {
+        match self {
+            DeviceCondition::NotSet => BranchDevice::device_not_set(ctx, dev),
+            DeviceCondition::Set => BranchDevice::device_attached(ctx, dev),
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct BranchDevice
 {
     dev: AliasOrDevice,
     target: LineNumber,
-    predicate: Box<dyn Fn(&CPUContext, Device)->Result<bool,ExecutionError>>,
+    predicate: DeviceCondition,
     and_link: bool,
     relative: bool,
 }
 
 impl BranchDevice
 {
-    pub fn new<'a, I, F>(parts: I , op:F, and_link: bool, relative:bool) -> Result<BranchDevice, CompileError>
-        where I:Iterator<Item=&'a str>,
-//              F: Fn(f32,f32)->bool +'static
-              F: Fn(&CPUContext, Device)->Result<bool,ExecutionError> +'static
+    pub fn new<'a, I>(parts: I , op:DeviceCondition, and_link: bool, relative:bool) -> Result<BranchDevice, CompileError>
+        where I:Iterator<Item=&'a str>
     {
         let (arg1, target) = expect_2(parts)?;
 
         Ok(BranchDevice{
             dev: AliasOrDevice::parse(&arg1)?,
             target: LineNumber::parse(&target)?,
-            predicate: Box::new(op),
-            and_link: and_link,
-            relative: relative,
+            predicate: op,
+            and_link,
+            relative,
         })
     }
 
     pub fn device_not_set(ctx : &CPUContext, dev: Device) ->Result<bool,ExecutionError>
     {
-        return Ok(!BranchDevice::device_attached(ctx, dev)?);
+        Ok(!BranchDevice::device_attached(ctx, dev)?)
     }
 
     pub fn device_attached(ctx : &CPUContext, dev: Device) ->Result<bool,ExecutionError>
@@ -1286,46 +2441,58 @@ impl BranchDevice
     pub fn bdns<'a,I>(parts:I) ->Result<BranchDevice, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchDevice::new(parts, BranchDevice::device_not_set, false, false)
+        BranchDevice::new(parts, DeviceCondition::NotSet, false, false)
     }
 
     pub fn bdnsal<'a,I>(parts:I) ->Result<BranchDevice, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchDevice::new(parts, BranchDevice::device_not_set, true, false)
+        BranchDevice::new(parts, DeviceCondition::NotSet, true, false)
     }
 
     pub fn bdse<'a,I>(parts:I) ->Result<BranchDevice, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchDevice::new(parts, BranchDevice::device_attached, false, false)
+        BranchDevice::new(parts, DeviceCondition::Set, false, false)
     }
 
     pub fn bdseal<'a,I>(parts:I) ->Result<BranchDevice, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchDevice::new(parts, BranchDevice::device_attached, true, false)
+        BranchDevice::new(parts, DeviceCondition::Set, true, false)
     }
 
     pub fn brdns<'a,I>(parts:I) ->Result<BranchDevice, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchDevice::new(parts, BranchDevice::device_not_set, false, true)
+        BranchDevice::new(parts, DeviceCondition::NotSet, false, true)
     }
 
     pub fn brdse<'a,I>(parts:I) ->Result<BranchDevice, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchDevice::new(parts, BranchDevice::device_attached, false, true)
+        BranchDevice::new(parts, DeviceCondition::Set, false, true)
+    }
+
+    pub fn opcode(&self) -> &'static str
+    {
+        match (self.predicate, self.relative, self.and_link) {
+            (DeviceCondition::NotSet, false, false) => "bdns",
+            (DeviceCondition::NotSet, false, true) => "bdnsal",
+            (DeviceCondition::NotSet, true, _) => "brdns",
+            (DeviceCondition::Set, false, false) => "bdse",
+            (DeviceCondition::Set, false, true) => "bdseal",
+            (DeviceCondition::Set, true, _) => "brdse",
+        }
     }
 }
 
 impl Instruction for BranchDevice
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         let dev = ctx.resolve_device(&self.dev)?;
         ctx.ip_plus_one();
-        if (self.predicate)(&ctx, dev)? {
+        if self.predicate.test(ctx, dev)? {
             if self.and_link {
                 ctx.set_ra(ctx.instruction_pointer);
             }
@@ -1336,27 +2503,47 @@ impl Instruction for BranchDevice
                 ctx.instruction_pointer = target;
             }
         }
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.target.resolve_labels(labels)
     }
 }
 
 //
 
+#[derive(Copy,Clone,Debug,PartialEq,Serialize)]
+pub enum TernaryCondition
+{
+    Approximately,
+}
+
+impl TernaryCondition
+{
+    pub fn test(self, a:f32, b:f32, c:f32) -> bool
+    {
+        match self {
+            TernaryCondition::Approximately => BranchTernary::approximately_the_same(a, b, c),
+        }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct BranchTernary
 {
     arg1: RValue,
     arg2: RValue,
     frac: RValue,
     target: LineNumber,
-    op: Box<dyn Fn(f32,f32,f32)->bool>,
+    op: TernaryCondition,
     and_link: bool,
 }
 
 impl BranchTernary
 {
-    pub fn new<'a, I, F>(parts: I , op:F, and_link:bool) -> Result<BranchTernary, CompileError>
-        where I:Iterator<Item=&'a str>,
-              F: Fn(f32,f32,f32)->bool +'static
+    pub fn new<'a, I>(parts: I , op:TernaryCondition, and_link:bool) -> Result<BranchTernary, CompileError>
+        where I:Iterator<Item=&'a str>
     {
         let (arg1, arg2, arg3, target) = expect_4(parts)?;
 
@@ -1365,15 +2552,15 @@ impl BranchTernary
             arg2: RValue::parse(&arg2)?,
             frac: RValue::parse(&arg3)?,
             target: LineNumber::parse(&target)?,
-            op: Box::new(op),
-            and_link:and_link,
+            op,
+            and_link,
         })
     }
 
     pub fn approximately_the_same(a:f32, b:f32, frac:f32) ->bool
     {
         // yeah, this is mildly confusing
-        let margin1 = std::f32::EPSILON * 8.;
+        let margin1 = f32::EPSILON * 8.;
         let scale = a.abs().max(b.abs());
         let margin2 = frac * scale;
         let tolerance = margin1.max(margin2);
@@ -1383,26 +2570,33 @@ impl BranchTernary
     pub fn bap<'a, I>(parts:I) -> Result<BranchTernary, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchTernary::new(parts, BranchTernary::approximately_the_same, false)
+        BranchTernary::new(parts, TernaryCondition::Approximately, false)
     }
 
     pub fn bapal<'a, I>(parts:I) -> Result<BranchTernary, CompileError>
         where I:Iterator<Item=&'a str>
     {
-        BranchTernary::new(parts, BranchTernary::approximately_the_same, true)
+        BranchTernary::new(parts, TernaryCondition::Approximately, true)
     }
 
+    pub fn opcode(&self) -> &'static str
+    {
+        match (self.op, self.and_link) {
+            (TernaryCondition::Approximately, false) => "bap",
+            (TernaryCondition::Approximately, true) => "bapal",
+        }
+    }
 }
 
 impl Instruction for BranchTernary
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError>
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError>
     {
         let a = ctx.resolve_r_value(&self.arg1)?;
         let b = ctx.resolve_r_value(&self.arg2)?;
         let c = ctx.resolve_r_value(&self.frac)?;
 
-        let result = (self.op)(a,b, c);
+        let result = self.op.test(a,b, c);
         if result {
             if self.and_link {
                 ctx.set_ra(ctx.instruction_pointer+1);
@@ -1411,30 +2605,38 @@ impl Instruction for BranchTernary
         } else {
             ctx.instruction_pointer += 1;
         }
-        Ok(ctx)
+        Ok(())
+    }
+
+    fn resolve_labels(&mut self, labels: &LabelTable) -> Result<(), CompileError> {
+        self.arg1.resolve_labels(labels);
+        self.arg2.resolve_labels(labels);
+        self.frac.resolve_labels(labels);
+        self.target.resolve_labels(labels)
     }
 }
 
 //
 
+#[derive(Clone,Debug,PartialEq,Serialize)]
 pub struct Yield { }
 
 impl Instruction for Yield
 {
-    fn execute(&self, mut ctx: CPUContext) -> Result<CPUContext, ExecutionError> {
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
         ctx.yield_();
-        Ok(ctx)
+        Ok(())
     }
 }
 
 //
 
-impl<T:Instruction+'static> From<Result<T, CompileError>> for ParsedLine
+impl<T:Into<Op>> From<Result<T, CompileError>> for ParsedLine
 {
     fn from(x: Result<T, CompileError>) -> Self
     {
         match x {
-            Ok(a) => ParsedLine::OpCode(Box::new(a)),
+            Ok(a) => ParsedLine::OpCode(a.into()),
             Err(e) => ParsedLine::Err(e),
         }
     }
@@ -1445,7 +2647,7 @@ impl<T:Instruction+'static> From<Result<T, CompileError>> for ParsedLine
 
 pub enum ParsedLine
 {
-    OpCode(Box<dyn Instruction>),
+    OpCode(Op),
     JumpLabel(String),
     Err(CompileError)
 }
@@ -1466,18 +2668,17 @@ pub fn parse_one_line(line:&str) -> ParsedLine
         return ParsedLine::JumpLabel(line[..idx].trim().to_string());
     }
 
-    let mut parts = line.trim().split_whitespace();
+    let mut parts = line.split_whitespace();
 
     let opcode = parts.next();
-    return match opcode {
-        None => {
-            let x:Box<dyn Instruction> = Box::new(NoCode{});
-            ParsedLine::OpCode(x)
-        },
+    match opcode {
+        None => ParsedLine::OpCode(Op::NoCode(NoCode{})),
 
         Some(opcode)=> {
             if "j" == opcode {
                 Jump::new(parts).into()
+            } else if "jal" == opcode {
+                Jump::jal(parts).into()
             } else if "alias" == opcode {
                 Alias::new(parts).into()
             } else if "define" == opcode {
@@ -1485,11 +2686,11 @@ pub fn parse_one_line(line:&str) -> ParsedLine
             } else if "s" == opcode {
                 SetDevice::new(parts).into()
             } else if "yield" == opcode {
-                ParsedLine::OpCode(Box::new(Yield {}))
+                ParsedLine::OpCode(Op::Yield(Yield {}))
             } else if "l" == opcode {
                 LoadDevice::new(parts).into()
             } else if "ls" == opcode || "lr" == opcode {
-                ParsedLine::Err(CompileError { message: format!("{} unimplemented because I do not understand them yet", opcode)})
+                ParsedLine::Err(CompileError::for_token(opcode, &format!("{} unimplemented because I do not understand them yet", opcode)))
             } else if "move" == opcode {
                 Move::new(parts).into()
 
@@ -1541,7 +2742,7 @@ pub fn parse_one_line(line:&str) -> ParsedLine
             } else if "bapal" == opcode {
                 BranchTernary::bapal(parts).into()
             } else if "bapz" == opcode || "bapzal" == opcode {
-                ParsedLine::Err(CompileError { message: format!("{} unimplemented because in-game documentation is defective", opcode)})
+                ParsedLine::Err(CompileError::for_token(opcode, &format!("{} unimplemented because in-game documentation is defective", opcode)))
 
             } else if "beq" == opcode {
                 Branch::eq(parts).into()
@@ -1573,11 +2774,11 @@ pub fn parse_one_line(line:&str) -> ParsedLine
                 BinaryOperator::xor(parts).into()
 
             } else {
-                ParsedLine::Err(CompileError{message: format!("unrecognized opcode {}", opcode)})
+                ParsedLine::Err(CompileError::for_token(opcode, &format!("unrecognized opcode {}", opcode)))
             }
         }
 
-    };
+    }
 }
 
 //
@@ -1607,32 +2808,110 @@ impl DeviceStateBuilder
     }
 }
 
+impl Default for DeviceStateBuilder
+{
+    fn default() -> Self {
+        DeviceStateBuilder::new()
+    }
+}
+
 //
 
+/// A compiled program is `Send + Sync`, and cloning it only bumps reference counts,
+/// so one compilation can be shared by every thread of a test suite.
+#[derive(Clone,Debug,PartialEq)]
 pub struct CompiledProgram
 {
-    codes: Vec<Box<dyn Instruction>>,
-    labels: HashMap<String, InstructionPointer>,
+    codes: Arc<[Op]>,
+    labels: Arc<HashMap<String, InstructionPointer>>,
 }
 
 impl CompiledProgram
 {
+    /// a program put together by hand rather than compiled; `labels` are the line numbers of the `NoCode` label lines
+    pub fn new(codes: Vec<Op>, labels: HashMap<String, InstructionPointer>) -> CompiledProgram
+    {
+        CompiledProgram { codes: codes.into(), labels: Arc::new(labels) }
+    }
+
     pub fn labels(&self) -> HashMap<String,InstructionPointer>
     {
-        self.labels.clone()
+        (*self.labels).clone()
     }
 
-    pub fn get_instruction(&self, idx:InstructionPointer) -> Option<&dyn Instruction>
+    pub fn ops(&self) -> &[Op]
     {
-        if (idx as usize) < self.codes.len() {
-            Some(& * self.codes[idx as usize])
-        } else {
-            None
+        &self.codes
+    }
+
+    pub fn get_instruction(&self, idx:InstructionPointer) -> Option<&Op>
+    {
+        self.codes.get(idx as usize)
+    }
+}
+
+/// prints the program back out as source, one line per op, with its labels
+impl std::fmt::Display for CompiledProgram
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        let mut label_at: HashMap<InstructionPointer, &str> = HashMap::new();
+        for (label, &line) in self.labels.iter() {
+            label_at.insert(line, label);
+        }
+        for (line, op) in self.codes.iter().enumerate() {
+            match (label_at.get(&(line as InstructionPointer)), op) {
+                (Some(label), Op::NoCode(_)) => writeln!(f, "{}:", label)?,
+                _ => writeln!(f, "{}", op)?,
+            }
         }
+        Ok(())
     }
 }
 
 
+/// The result of compiling a whole source file: every diagnostic found,
+/// plus a best-effort program in which lines that failed to compile fault when executed.
+pub struct Compilation
+{
+    pub program: CompiledProgram,
+    pub diagnostics: Vec<Diagnostic>,
+    /// how close the source is to the chip's size limits
+    pub size: SizeReport,
+}
+
+impl Compilation
+{
+    pub fn has_errors(&self) -> bool
+    {
+        self.diagnostics.iter().any(|d| d.is_error())
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item=&Diagnostic>
+    {
+        self.diagnostics.iter().filter(|d| d.is_error())
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item=&Diagnostic>
+    {
+        self.diagnostics.iter().filter(|d| !d.is_error())
+    }
+
+    /// the safe autofixes suggested by the lints; see `apply_fixes`
+    pub fn fixes(&self) -> impl Iterator<Item=&Fix>
+    {
+        self.diagnostics.iter().filter_map(|d| d.fix.as_ref())
+    }
+
+    /// the program if there were no errors, otherwise the first error (with its location)
+    pub fn into_result(self) -> Result<CompiledProgram, CompileError>
+    {
+        match self.diagnostics.into_iter().find(|d| d.is_error()) {
+            None => Ok(self.program),
+            Some(diag) => Err(CompileError::new(&diag.to_string())),
+        }
+    }
+}
+
 pub fn compile(src:&str) ->Result<CompiledProgram, CompileError>
 {
     let lines:std::str::Lines = src.lines();
@@ -1643,75 +2922,141 @@ pub fn compile(src:&str) ->Result<CompiledProgram, CompileError>
 pub fn compile_lines<'a,I>(lines: I) -> Result<CompiledProgram, CompileError>
     where I:Iterator<Item=&'a str>
 {
-    let mut codes2: Vec<Box<dyn Instruction>> = Vec::new();
+    compile_lines_with_diagnostics(lines).into_result()
+}
+
+pub fn compile_with_diagnostics(src:&str) -> Compilation
+{
+    compile_lines_with_diagnostics(src.lines())
+}
+
+pub fn compile_with_profile(src:&str, profile:&ChipProfile) -> Compilation
+{
+    compile_lines_with_profile(src.lines(), profile)
+}
+
+pub fn compile_lines_with_diagnostics<'a,I>(lines: I) -> Compilation
+    where I:Iterator<Item=&'a str>
+{
+    compile_lines_with_profile(lines, &ChipProfile::default())
+}
+
+/// Compile in two passes.  The first parses each line and records where the labels are,
+/// the second replaces label references with line numbers, so an undefined label is
+/// reported here instead of when (if ever) the branch is taken.
+/// Then the operands are checked against `profile` and against what the aliases
+/// and defines refer to along the control flow, the lints are run, the names that always
+/// mean the same thing are resolved ahead of time, and the source is measured against
+/// the profile's size limits.
+pub fn compile_lines_with_profile<'a,I>(lines: I, profile:&ChipProfile) -> Compilation
+    where I:Iterator<Item=&'a str>
+{
+    let lines: Vec<&str> = lines.collect();
+    let mut codes2: Vec<Op> = Vec::new();
     let mut labels: HashMap<String, InstructionPointer> = HashMap::new();
-    let mut line_number = 0;
-    for line in lines {
+    let mut declared_names = HashSet::new();
+    let mut diagnostics = Vec::new();
+    for (line_number, line) in lines.iter().enumerate() {
         let x = parse_one_line(line);
         let transformed = match x {
-            ParsedLine::OpCode(op_code) => op_code,
+            ParsedLine::OpCode(op_code) => {
+                let tokens = tokenize(line);
+                if let [opcode, name, ..] = tokens.as_slice() {
+                    if "alias" == opcode.text || "define" == opcode.text {
+                        declared_names.insert(name.text.to_string());
+                    }
+                }
+                op_code
+            },
             ParsedLine::JumpLabel(jump_label) => {
-                labels.insert(jump_label, line_number);
-                Box::new(NoCode {})
+                let start = line.find(jump_label.as_str()).unwrap_or(0);
+                let span = Span::new(line_number, start, start+jump_label.len());
+                match labels.entry(jump_label) {
+                    Entry::Occupied(entry) =>
+                        diagnostics.push(Diagnostic::error(span, &format!("duplicate label '{}'", entry.key()))),
+                    Entry::Vacant(entry) => {
+                        entry.insert(line_number as InstructionPointer);
+                    },
+                }
+                Op::NoCode(NoCode {})
             },
             ParsedLine::Err(e) => {
-                println!("{}: {}", line_number, line);
-                return Err(e)
+                diagnostics.push(Diagnostic::from_compile_error(line_number, line, &e));
+                Op::CompileFailure(CompileFailure { message: e.message })
             }
         };
         codes2.push(transformed);
-        line_number += 1;
     }
-    return Ok(CompiledProgram {
-        codes: codes2,
-        labels: labels,
-    });
+
+    let label_table = LabelTable::new(labels.clone(), declared_names);
+    for (line_number, code) in codes2.iter_mut().enumerate() {
+        if let Err(e) = code.resolve_labels(&label_table) {
+            diagnostics.push(Diagnostic::from_compile_error(line_number, lines[line_number], &e));
+            *code = Op::CompileFailure(CompileFailure { message: e.message });
+        }
+    }
+
+    let failed_lines = diagnostics.iter().filter(|d| d.is_error()).map(|d| d.span.line).collect();
+    let model = SourceModel::new(&lines, &labels, &failed_lines);
+    diagnostics.extend(check_operand_types(&model, profile));
+    diagnostics.extend(lint(&model));
+    resolve_names(&mut codes2, &model);
+    let (size, limit_diagnostics) = profile.check_limits(&lines);
+    diagnostics.extend(limit_diagnostics);
+    diagnostics.sort_by_key(|d| d.span);
+
+    Compilation {
+        program: CompiledProgram::new(codes2, labels),
+        diagnostics,
+        size,
+    }
 }
 
 //
 //
 //
 
-pub fn execute_until_yields(program:&CompiledProgram, mut ctx:CPUContext, min_yields:u32) -> Result<CPUContext, ExecutionError>
+/// The most instructions an IC runs in one game tick.  A program that has not yielded by then
+/// carries on from the same place next tick, as if it had.
+pub const INSTRUCTIONS_PER_TICK: u32 = 128;
+
+/// game time for one tick
+pub const SECONDS_PER_TICK: f32 = 0.5;
+
+/// Run the program for `min_yields` ticks: until it has yielded that many times, or runs off the end.
+/// A tick also ends after `INSTRUCTIONS_PER_TICK` instructions without a yield, and counts towards
+/// `min_yields` as if it had yielded, so a loop without a yield no longer runs until it faults.
+/// If an instruction faults, `ctx` is left as it was at the fault, with the IP on the faulting line.
+pub fn execute_until_yields(program:&CompiledProgram, ctx:&mut CPUContext, min_yields:u32) -> Result<(), ExecutionError>
 {
-    let mut yield_count=0;
-    for _i in 0..99 {
-        let inst = program.get_instruction(ctx.instruction_pointer);
-        if let None = inst {
-            println!("reached end of program");
-            break;
-        }
-        ctx = inst.unwrap().execute(ctx)?;
-        if ctx.reset_yield() {
-            yield_count+=1;
-            if yield_count >= min_yields {
-                break;
-            }
-        }
-        println!("IP = {}", ctx.instruction_pointer)
-    }
-    Ok(ctx)
+    execute_until_yields2(program, ctx, min_yields, |_| {})
 }
 
-pub fn execute_until_yields2<F>(program:&CompiledProgram, mut ctx:CPUContext, min_yields:u32, callback:F) -> Result<CPUContext, ExecutionError>
-    where F:Fn(&mut CPUContext)
+/// `execute_until_yields`, calling `callback` after every instruction.  Ticks end in the same
+/// places, including after `INSTRUCTIONS_PER_TICK` instructions without a yield.
+pub fn execute_until_yields2<F>(program:&CompiledProgram, ctx:&mut CPUContext, min_yields:u32, mut callback:F) -> Result<(), ExecutionError>
+    where F:FnMut(&mut CPUContext)
 {
     let mut yield_count=0;
-    for _i in 0..99 {
-        let inst = program.get_instruction(ctx.instruction_pointer);
-        if let None = inst {
-            println!("reached end of program");
-            break;
-        }
-        ctx = inst.unwrap().execute(ctx)?;
-        callback(&mut ctx);
-        if ctx.reset_yield() {
+    let mut tick_instructions = 0;
+    while yield_count < min_yields {
+        let inst = match program.get_instruction(ctx.instruction_pointer) {
+            Some(inst) => inst,
+            None => break, // reached end of program
+        };
+        let ip = ctx.instruction_pointer;
+        if let Err(e) = inst.execute(ctx) {
+            ctx.instruction_pointer = ip;
+            return Err(e);
+        }
+        ctx.instructions_executed += 1;
+        tick_instructions += 1;
+        callback(ctx);
+        if ctx.reset_yield() || tick_instructions >= INSTRUCTIONS_PER_TICK {
             yield_count+=1;
-            if yield_count >= min_yields {
-                break;
-            }
+            ctx.ticks += 1;
+            tick_instructions = 0;
         }
-        println!("IP = {}", ctx.instruction_pointer)
     }
-    Ok(ctx)
+    Ok(())
 }
@@ -0,0 +1,312 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{tokenize, token_span, Diagnostic, Fix, OperandKind, Register, RegisterOrDevice, SourceModel, Span, TextEdit};
+
+pub const UNUSED_ALIAS: &str = "unused-alias";
+pub const UNUSED_DEFINE: &str = "unused-define";
+pub const UNREACHABLE_CODE: &str = "unreachable-code";
+pub const LOOP_WITHOUT_YIELD: &str = "loop-without-yield";
+pub const NUMERIC_JUMP_TARGET: &str = "numeric-jump-target";
+pub const UNREAD_REGISTER: &str = "unread-register";
+pub const ALIAS_SHADOWING: &str = "alias-shadowing";
+
+/// Warnings about code that compiles but is probably a mistake.
+/// Where a fix is safe (it does not change what the program does) it is attached to the diagnostic.
+pub fn lint(model:&SourceModel) -> Vec<Diagnostic>
+{
+    let mut rval = Vec::new();
+    unused_names(model, &mut rval);
+    unreachable_code(model, &mut rval);
+    loops_without_yield(model, &mut rval);
+    numeric_jump_targets(model, &mut rval);
+    unread_registers(model, &mut rval);
+    alias_shadowing(model, &mut rval);
+    rval
+}
+
+/// an edit that empties the code on `line` but keeps the line (and any comment)
+fn blank_statement(model:&SourceModel, line:usize) -> TextEdit
+{
+    TextEdit { span: token_span(line, model.lines[line], None), replacement: String::new() }
+}
+
+fn unused_names(model:&SourceModel, out:&mut Vec<Diagnostic>)
+{
+    let mut used = BTreeSet::new();
+    for statement in model.statements.iter().flatten() {
+        for (kind, token) in statement.typed_operands() {
+            match kind {
+                OperandKind::Name | OperandKind::LogicType | OperandKind::Number => {},
+                _ => { used.insert(token.text); },
+            }
+        }
+    }
+
+    for (line, statement) in model.statements.iter().enumerate() {
+        if let Some(statement) = statement {
+            let (code, what) = if statement.is("alias") {
+                (UNUSED_ALIAS, "alias")
+            } else if statement.is("define") {
+                (UNUSED_DEFINE, "define")
+            } else {
+                continue;
+            };
+            let name = &statement.operands[0];
+            if !used.contains(name.text) {
+                let fix = Fix::new(&format!("remove the {} of '{}'", what, name.text), vec![blank_statement(model, line)]);
+                out.push(Diagnostic::warning(model.span(line, name), &format!("{} '{}' is never used", what, name.text))
+                    .with_code(code)
+                    .with_fix(fix));
+            }
+        }
+    }
+}
+
+fn unreachable_code(model:&SourceModel, out:&mut Vec<Diagnostic>)
+{
+    let reachable = model.reachable();
+    let guessing = model.has_register_jumps();
+    let mut run: Vec<usize> = Vec::new();
+    // a live line past the end flushes the last run
+    for (line, live) in reachable.iter().copied().chain(std::iter::once(true)).enumerate() {
+        let dead_statement = !live && model.statements[line].is_some();
+        if dead_statement {
+            run.push(line);
+        } else if live && !run.is_empty() {
+            let first = run[0];
+            let last = run[run.len()-1];
+            let span = token_span(first, model.lines[first], None);
+            let edits = run.iter().map(|&l| blank_statement(model, l)).collect();
+            let msg = if first == last {
+                "this line can never be reached".to_string()
+            } else {
+                format!("lines {} to {} can never be reached", first, last)
+            };
+            let diag = Diagnostic::warning(span, &msg).with_code(UNREACHABLE_CODE);
+            // a register may hold a line number no label names, so the code may be live after all
+            out.push(if guessing { diag } else { diag.with_fix(Fix::new("remove the unreachable code", edits)) });
+            run.clear();
+        }
+    }
+}
+
+/// Tarjan's algorithm, for the strongly connected components of the control flow graph
+struct Components<'m, 'a>
+{
+    model: &'m SourceModel<'a>,
+    index: Vec<Option<usize>>,
+    low: Vec<usize>,
+    on_stack: Vec<bool>,
+    stack: Vec<usize>,
+    next_index: usize,
+    components: Vec<Vec<usize>>,
+}
+
+impl<'m, 'a> Components<'m, 'a>
+{
+    fn find(model:&'m SourceModel<'a>) -> Vec<Vec<usize>>
+    {
+        let n = model.lines.len();
+        let mut c = Components { model, index: vec![None; n], low: vec![0; n], on_stack: vec![false; n],
+            stack: Vec::new(), next_index: 0, components: Vec::new() };
+        for line in 0..n {
+            if c.index[line].is_none() {
+                c.visit(line);
+            }
+        }
+        c.components
+    }
+
+    fn visit(&mut self, line:usize)
+    {
+        self.index[line] = Some(self.next_index);
+        self.low[line] = self.next_index;
+        self.next_index += 1;
+        self.stack.push(line);
+        self.on_stack[line] = true;
+
+        for next in self.model.successors(line) {
+            match self.index[next] {
+                None => {
+                    self.visit(next);
+                    self.low[line] = self.low[line].min(self.low[next]);
+                },
+                Some(idx) if self.on_stack[next] => self.low[line] = self.low[line].min(idx),
+                _ => {},
+            }
+        }
+
+        if Some(self.low[line]) == self.index[line] {
+            let mut component = Vec::new();
+            while let Some(member) = self.stack.pop() {
+                self.on_stack[member] = false;
+                component.push(member);
+                if member == line {
+                    break;
+                }
+            }
+            component.sort_unstable();
+            self.components.push(component);
+        }
+    }
+}
+
+fn loops_without_yield(model:&SourceModel, out:&mut Vec<Diagnostic>)
+{
+    let reachable = model.reachable();
+    for component in Components::find(model) {
+        let first = component[0];
+        let is_loop = component.len() > 1 || model.successors(first).contains(&first);
+        if !is_loop || !reachable[first] {
+            continue;
+        }
+        let yields = component.iter().any(|&line| {
+            match tokenize(model.lines[line]).first() {
+                Some(token) => token.text == "yield" || token.text == "sleep",
+                None => false,
+            }
+        });
+        if yields {
+            continue;
+        }
+        // point at the branch that closes the loop
+        let closing = component.iter().copied()
+            .rev()
+            .find(|&line| model.branch_target(line).is_some_and(|t| t <= line && component.contains(&t)))
+            .unwrap_or(component[component.len()-1]);
+        out.push(Diagnostic::warning(token_span(closing, model.lines[closing], None),
+                                     &format!("the loop over lines {} to {} has no yield or sleep", first, component[component.len()-1]))
+            .with_code(LOOP_WITHOUT_YIELD));
+    }
+}
+
+fn numeric_jump_targets(model:&SourceModel, out:&mut Vec<Diagnostic>)
+{
+    let mut label_at: HashMap<usize, &str> = HashMap::new();
+    for (label, &line) in &model.labels {
+        label_at.insert(line as usize, label);
+    }
+
+    for (line, statement) in model.statements.iter().enumerate() {
+        if let Some(statement) = statement {
+            for (kind, token) in statement.typed_operands() {
+                if kind != OperandKind::Target || token.text.parse::<u16>().is_err() {
+                    continue;
+                }
+                let span = model.span(line, token);
+                let mut diag = Diagnostic::warning(span, &format!("jump to line number {} will break when lines are inserted or removed; use a label", token.text))
+                    .with_code(NUMERIC_JUMP_TARGET);
+                let target = token.text.parse::<usize>().unwrap_or(usize::MAX);
+                if let Some(label) = label_at.get(&target) {
+                    if model.is_label(label) {
+                        diag = diag.with_fix(Fix::new(&format!("jump to '{}' instead", label),
+                                                      vec![TextEdit { span, replacement: label.to_string() }]));
+                    }
+                }
+                out.push(diag);
+            }
+        }
+    }
+}
+
+fn register_of(text:&str) -> Option<u8>
+{
+    match RegisterOrDevice::parse(text) {
+        Ok(RegisterOrDevice::Register(_)) => text[1..].parse::<u8>().ok(),
+        _ => None,
+    }
+}
+
+fn unread_registers(model:&SourceModel, out:&mut Vec<Diagnostic>)
+{
+    // every register each alias is ever pointed at, regardless of control flow
+    let mut alias_registers: HashMap<&str, Vec<u8>> = HashMap::new();
+    for statement in model.statements.iter().flatten() {
+        if statement.is("alias") {
+            if let Some(idx) = register_of(statement.operands[1].text) {
+                alias_registers.entry(statement.operands[0].text).or_default().push(idx);
+            }
+        }
+    }
+    let registers = |text:&str| -> Vec<u8> {
+        match register_of(text) {
+            Some(idx) => vec![idx],
+            None => alias_registers.get(text).cloned().unwrap_or_default(),
+        }
+    };
+
+    let mut read = BTreeSet::new();
+    let mut first_write: BTreeMap<u8, (usize, Span, &str)> = BTreeMap::new();
+    for (line, statement) in model.statements.iter().enumerate() {
+        if let Some(statement) = statement {
+            for (kind, token) in statement.typed_operands() {
+                match kind {
+                    OperandKind::RValue => read.extend(registers(token.text)),
+                    // `j r0` reads r0 for the line to go to
+                    OperandKind::Target if token.text == "ra" => { read.insert(Register::RA.idx()); },
+                    OperandKind::Target => read.extend(register_of(token.text)),
+                    OperandKind::LValue => {
+                        for idx in registers(token.text) {
+                            first_write.entry(idx).or_insert((line, model.span(line, token), token.text));
+                        }
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    for (idx, (_line, span, name)) in first_write {
+        if read.contains(&idx) {
+            continue;
+        }
+        let msg = if register_of(name).is_some() {
+            format!("r{} is written but never read", idx)
+        } else {
+            format!("'{}' (r{}) is written but never read", name, idx)
+        };
+        out.push(Diagnostic::warning(span, &msg).with_code(UNREAD_REGISTER));
+    }
+}
+
+fn alias_shadowing(model:&SourceModel, out:&mut Vec<Diagnostic>)
+{
+    // name -> (line, what it was declared as)
+    let mut declared: HashMap<&str, (usize, String)> = HashMap::new();
+    for (line, statement) in model.statements.iter().enumerate() {
+        if let Some(statement) = statement {
+            let what = if statement.is("alias") {
+                format!("alias of {}", statement.operands[1].text)
+            } else if statement.is("define") {
+                format!("define of {}", statement.operands[1].text)
+            } else {
+                continue;
+            };
+            let name = &statement.operands[0];
+            let span = model.span(line, name);
+
+            if let Ok(rod) = RegisterOrDevice::parse(name.text) {
+                let thing = match rod {
+                    RegisterOrDevice::Register(_) => "register",
+                    RegisterOrDevice::Device(_) => "device",
+                };
+                out.push(Diagnostic::warning(span, &format!("'{}' shadows the {} of the same name", name.text, thing))
+                    .with_code(ALIAS_SHADOWING));
+            }
+            if model.labels.contains_key(name.text) {
+                out.push(Diagnostic::warning(span, &format!("'{}' has the same name as a label", name.text))
+                    .with_code(ALIAS_SHADOWING));
+            }
+            match declared.get(name.text) {
+                Some((earlier, earlier_what)) if *earlier_what != what => {
+                    out.push(Diagnostic::warning(span, &format!("'{}' shadows the {} on line {}", name.text, earlier_what, earlier))
+                        .with_code(ALIAS_SHADOWING));
+                },
+                Some(_) => {},
+                None => {
+                    declared.insert(name.text, (line, what));
+                },
+            }
+        }
+    }
+}
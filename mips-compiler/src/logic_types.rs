@@ -0,0 +1,110 @@
+/// The names the game accepts for a device's logic type, as in `l r0 d0 Pressure`
+pub static LOGIC_TYPES: &[&str] = &[
+    "None", "Power", "Open", "Mode", "Error", "Lock", "Pressure", "Temperature",
+    "PressureExternal", "PressureInternal", "Activate", "Charge", "Setting", "Reagents",
+    "RatioOxygen", "RatioCarbonDioxide", "RatioNitrogen", "RatioPollutant", "RatioVolatiles",
+    "RatioWater", "RatioNitrousOxide", "RatioLiquidNitrogen", "RatioLiquidOxygen",
+    "RatioLiquidVolatiles", "RatioSteam", "RatioLiquidCarbonDioxide", "RatioLiquidPollutant",
+    "RatioLiquidNitrousOxide", "RatioHydrogen", "RatioLiquidHydrogen", "RatioPollutedWater",
+    "Horizontal", "Vertical", "SolarAngle", "Maximum", "Ratio", "PowerPotential", "PowerActual",
+    "Quantity", "On", "ImportQuantity", "ImportSlotOccupant", "ExportQuantity", "ExportSlotOccupant",
+    "RequiredPower", "HorizontalRatio", "VerticalRatio", "PowerRequired", "Idle", "Color",
+    "ElevatorSpeed", "ElevatorLevel", "RecipeHash", "ExportSlotHash", "ImportSlotHash",
+    "PlantHealth1", "PlantHealth2", "PlantHealth3", "PlantHealth4",
+    "PlantGrowth1", "PlantGrowth2", "PlantGrowth3", "PlantGrowth4",
+    "PlantEfficiency1", "PlantEfficiency2", "PlantEfficiency3", "PlantEfficiency4",
+    "PlantHash1", "PlantHash2", "PlantHash3", "PlantHash4",
+    "RequestHash", "CompletionRatio", "ClearMemory", "ExportCount", "ImportCount",
+    "PowerGeneration", "TotalMoles", "Volume", "Plant", "Harvest", "Output",
+    "PressureSetting", "TemperatureSetting", "TemperatureExternal", "Filtration", "AirRelease",
+    "PositionX", "PositionY", "PositionZ", "VelocityMagnitude",
+    "VelocityRelativeX", "VelocityRelativeY", "VelocityRelativeZ",
+    "PrefabHash", "ForceWrite", "SignalStrength", "SignalID", "TargetX", "TargetY", "TargetZ",
+    "SettingInput", "SettingOutput", "CurrentResearchPodType", "ManualResearchRequiredPod",
+    "MineablesInVicinity", "MineablesInQueue", "NextWeatherEventTime", "Combustion", "Fuel",
+    "ReturnFuelCost", "CollectableGoods", "Time", "Bpm", "EnvironmentEfficiency",
+    "WorkingGasEfficiency",
+    "PressureInput", "TemperatureInput", "RatioOxygenInput", "RatioCarbonDioxideInput",
+    "RatioNitrogenInput", "RatioPollutantInput", "RatioVolatilesInput", "RatioWaterInput",
+    "RatioNitrousOxideInput", "TotalMolesInput",
+    "PressureInput2", "TemperatureInput2", "RatioOxygenInput2", "RatioCarbonDioxideInput2",
+    "RatioNitrogenInput2", "RatioPollutantInput2", "RatioVolatilesInput2", "RatioWaterInput2",
+    "RatioNitrousOxideInput2", "TotalMolesInput2",
+    "PressureOutput", "TemperatureOutput", "RatioOxygenOutput", "RatioCarbonDioxideOutput",
+    "RatioNitrogenOutput", "RatioPollutantOutput", "RatioVolatilesOutput", "RatioWaterOutput",
+    "RatioNitrousOxideOutput", "TotalMolesOutput",
+    "PressureOutput2", "TemperatureOutput2", "RatioOxygenOutput2", "RatioCarbonDioxideOutput2",
+    "RatioNitrogenOutput2", "RatioPollutantOutput2", "RatioVolatilesOutput2", "RatioWaterOutput2",
+    "RatioNitrousOxideOutput2", "TotalMolesOutput2",
+    "CombustionInput", "CombustionInput2", "CombustionOutput", "CombustionOutput2",
+    "OperationalTemperatureEfficiency", "TemperatureDifferentialEfficiency", "PressureEfficiency",
+    "CombustionLimiter", "Throttle", "Rpm", "Stress", "InterrogationProgress", "TargetPadIndex",
+    "SizeX", "SizeY", "SizeZ", "MinimumWattsToContact", "WattsReachingContact",
+    "Channel0", "Channel1", "Channel2", "Channel3", "Channel4", "Channel5", "Channel6", "Channel7",
+    "LineNumber", "Flush", "SoundAlert", "SolarIrradiance", "ReferenceId",
+];
+
+/// The names the game accepts for the logic type of a device's slot, as in `ls r0 d0 0 Occupied`
+pub static SLOT_LOGIC_TYPES: &[&str] = &[
+    "None", "Occupied", "OccupantHash", "Quantity", "Damage", "Efficiency", "Health", "Growth",
+    "Pressure", "Temperature", "Charge", "ChargeRatio", "Class", "PressureWaste", "PressureAir",
+    "MaxQuantity", "Mature", "PrefabHash", "Seeding", "LineNumber", "Volume", "Open", "On", "Lock",
+    "SortingClass", "FilterType", "ReferenceId",
+];
+
+/// The ways the batch instructions (`lb` and friends) combine the values from many devices
+pub static BATCH_MODES: &[&str] = &[
+    "Average", "Sum", "Minimum", "Maximum",
+];
+
+pub fn is_logic_type(name:&str) -> bool
+{
+    LOGIC_TYPES.contains(&name)
+}
+
+pub fn is_slot_logic_type(name:&str) -> bool
+{
+    SLOT_LOGIC_TYPES.contains(&name)
+}
+
+pub fn is_batch_mode(name:&str) -> bool
+{
+    BATCH_MODES.contains(&name)
+}
+
+/// the number of single-character edits between `a` and `b`, ignoring case
+fn edit_distance(a:&str, b:&str) -> usize
+{
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i+1; b.len()+1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == cb { 0 } else { 1 };
+            current[j+1] = substitution.min(previous[j+1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// the name in `catalogue` closest to `name`, if any is close enough to be a likely misspelling
+pub fn suggest<'c>(name:&str, catalogue:&[&'c str]) -> Option<&'c str>
+{
+    let limit = (name.chars().count() / 3).max(2);
+    catalogue.iter()
+        .map(|&candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// the complaint about a name that is not in `catalogue`, with a suggestion if there is one
+pub fn unknown_name_message(what:&str, name:&str, catalogue:&[&str]) -> String
+{
+    match suggest(name, catalogue) {
+        Some(suggestion) => format!("unknown {} '{}'; did you mean '{}'?", what, name, suggestion),
+        None => format!("unknown {} '{}'", what, name),
+    }
+}
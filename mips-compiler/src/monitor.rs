@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+use std::fmt::{Formatter, Error};
+use std::sync::Arc;
+
+use crate::{CPUContext, InstructionPointer, Invariant, Register};
+
+/// how many of the most recent observed ticks a `MonitorViolation` shows
+pub const MONITOR_TRACE_LENGTH: usize = 8;
+
+#[derive(Clone)]
+enum Check
+{
+    Always(Invariant),
+    /// holds at some tick no later than `by`
+    Eventually { by: u64, holds: Invariant, done: bool },
+    /// `holds` at every tick until `release` does, if it ever does
+    Until { holds: Invariant, release: Invariant, done: bool },
+}
+
+#[derive(Clone)]
+struct Temporal
+{
+    name: String,
+    check: Check,
+}
+
+/// Temporal requirements on a run, such as "the pump is never on while the vent is open"
+/// or "the pressure reaches its target by tick 40", checked once a tick:
+///
+/// ```ignore
+/// let mut monitor = Monitor::new()
+///     .always("pump off while the vent is open", |ctx| ...)
+///     .eventually("pressure reaches target", 40, |ctx| ...)
+///     .watch("pump On")
+///     .watch("vent Open");
+/// for _ in 0..100 {
+///     execute_until_yields(&program, &mut ctx, 1)?;
+///     monitor.observe(&ctx)?;
+/// }
+/// monitor.finish(&ctx)?;
+/// ```
+///
+/// It only looks at the context, so it works with any run loop; `Scenario::monitor` hooks one into a scenario.
+/// Ticks are counted by the context's clock, `CPUContext::ticks`.
+#[derive(Clone,Default)]
+pub struct Monitor
+{
+    temporals: Vec<Temporal>,
+    watches: Vec<String>,
+    trace: VecDeque<ObservedTick>,
+    last_tick: Option<u64>,
+    violation: Option<MonitorViolation>,
+}
+
+impl Monitor
+{
+    pub fn new() -> Monitor
+    {
+        Monitor::default()
+    }
+
+    /// `holds` at every tick observed
+    pub fn always<F>(mut self, name:&str, holds:F) -> Monitor
+        where F:Fn(&CPUContext) -> bool + Send + Sync + 'static
+    {
+        self.add(name, Check::Always(Arc::new(holds)));
+        self
+    }
+
+    /// `holds` at some tick observed no later than tick `by`
+    pub fn eventually<F>(mut self, name:&str, by:u64, holds:F) -> Monitor
+        where F:Fn(&CPUContext) -> bool + Send + Sync + 'static
+    {
+        self.add(name, Check::Eventually { by, holds: Arc::new(holds), done: false });
+        self
+    }
+
+    /// `holds` at every tick observed until the first one where `release` does.
+    /// Nothing requires `release` to happen; pair it with `eventually` for that.
+    pub fn until<F, G>(mut self, name:&str, holds:F, release:G) -> Monitor
+        where F:Fn(&CPUContext) -> bool + Send + Sync + 'static, G:Fn(&CPUContext) -> bool + Send + Sync + 'static
+    {
+        self.add(name, Check::Until { holds: Arc::new(holds), release: Arc::new(release), done: false });
+        self
+    }
+
+    /// Show a value in the trace of a violation: `pumpGH On` for a device field,
+    /// or a register, alias or define such as `r3` or `wantGHPump`
+    pub fn watch(mut self, name:&str) -> Monitor
+    {
+        self.watches.push(name.to_string());
+        self
+    }
+
+    fn add(&mut self, name:&str, check:Check)
+    {
+        self.temporals.push(Temporal { name: name.to_string(), check });
+    }
+
+    /// the first violation found, if any
+    pub fn violation(&self) -> Option<&MonitorViolation>
+    {
+        self.violation.as_ref()
+    }
+
+    /// Check the requirements against the context as it is at the end of a tick.
+    /// Observing the same tick again does nothing, and once a requirement is broken
+    /// every observation returns that first violation.
+    pub fn observe(&mut self, ctx:&CPUContext) -> Result<(), MonitorViolation>
+    {
+        if let Some(violation) = &self.violation {
+            return Err(violation.clone());
+        }
+        let tick = ctx.ticks();
+        if self.last_tick == Some(tick) {
+            return Ok(());
+        }
+        self.last_tick = Some(tick);
+        if self.trace.len() == MONITOR_TRACE_LENGTH {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(ObservedTick {
+            tick,
+            line: ctx.instruction_pointer(),
+            values: self.watches.iter().map(|name| (name.clone(), watched_value(ctx, name))).collect(),
+        });
+
+        for temporal in &mut self.temporals {
+            let broken = match &mut temporal.check {
+                Check::Always(holds) => (!holds(ctx)).then(|| "does not hold".to_string()),
+                Check::Eventually { done: true, .. } | Check::Until { done: true, .. } => None,
+                Check::Eventually { by, holds, done } => {
+                    // holding only after the deadline, seen when observations skip ticks, is too late
+                    *done = tick <= *by && holds(ctx);
+                    (!*done && tick >= *by).then(|| format!("had not held by tick {}", by))
+                },
+                Check::Until { holds, release, done } => {
+                    *done = release(ctx);
+                    (!*done && !holds(ctx)).then(|| "stopped holding before its release".to_string())
+                },
+            };
+            if let Some(message) = broken {
+                let violation = MonitorViolation::new(&temporal.name, tick, message, &self.trace, ctx);
+                self.violation = Some(violation.clone());
+                return Err(violation);
+            }
+        }
+        Ok(())
+    }
+
+    /// At the end of a run: observe the last tick, then fail if anything `eventually` was waiting for never happened
+    pub fn finish(&mut self, ctx:&CPUContext) -> Result<(), MonitorViolation>
+    {
+        self.observe(ctx)?;
+        let pending = self.temporals.iter().find(|t| matches!(t.check, Check::Eventually { done: false, .. }));
+        if let Some(Temporal { name, check: Check::Eventually { by, .. } }) = pending {
+            let message = format!("had not held when the run ended, before its deadline of tick {}", by);
+            let violation = MonitorViolation::new(name, ctx.ticks(), message, &self.trace, ctx);
+            self.violation = Some(violation.clone());
+            return Err(violation);
+        }
+        Ok(())
+    }
+}
+
+fn watched_value(ctx:&CPUContext, name:&str) -> Option<f32>
+{
+    match name.split_once(' ') {
+        Some((device, field)) => ctx.device_field(device, field).ok(),
+        None => match Register::parse(name) {
+            Ok(reg) => ctx.register_reference(reg).ok(),
+            Err(_) => ctx.alias_value(name).ok(),
+        },
+    }
+}
+
+//
+
+/// The values a `Monitor` watches, at the end of one tick
+#[derive(Clone,Debug,PartialEq)]
+pub struct ObservedTick
+{
+    pub tick: u64,
+    /// where the program will carry on from
+    pub line: InstructionPointer,
+    /// `None` for a value that could not be read
+    pub values: Vec<(String, Option<f32>)>,
+}
+
+impl std::fmt::Display for ObservedTick
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "tick {} line {}", self.tick, self.line)?;
+        for (i, (name, value)) in self.values.iter().enumerate() {
+            let value = value.map_or("-".to_string(), |v| v.to_string());
+            write!(f, "{} {} = {}", if i == 0 { ":" } else { "," }, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The first tick at which a `Monitor`'s requirement was broken, and what led up to it
+#[derive(Clone,Debug,PartialEq)]
+pub struct MonitorViolation
+{
+    /// the name the requirement was given
+    pub property: String,
+    pub tick: u64,
+    pub message: String,
+    /// the last few ticks observed, oldest first, ending with the violating one
+    pub trace: Vec<ObservedTick>,
+    /// the context's most recent device writes, as in `line 6: d1 On = 1`
+    pub device_writes: Vec<String>,
+}
+
+impl MonitorViolation
+{
+    fn new(property:&str, tick:u64, message:String, trace:&VecDeque<ObservedTick>, ctx:&CPUContext) -> MonitorViolation
+    {
+        MonitorViolation {
+            property: property.to_string(),
+            tick,
+            message,
+            trace: trace.iter().cloned().collect(),
+            device_writes: ctx.recent_device_writes().map(|w| w.to_string()).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for MonitorViolation
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "tick {}: {}: {}", self.tick, self.property, self.message)?;
+        if !self.trace.is_empty() {
+            write!(f, "\nrecent ticks:")?;
+            for tick in &self.trace {
+                write!(f, "\n  {}", tick)?;
+            }
+        }
+        if !self.device_writes.is_empty() {
+            write!(f, "\nrecent device writes:")?;
+            for write in &self.device_writes {
+                write!(f, "\n  {}", write)?;
+            }
+        }
+        Ok(())
+    }
+}
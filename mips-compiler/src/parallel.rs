@@ -0,0 +1,58 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use crate::CompiledProgram;
+
+/// Run `scenario` once for every case, spread over as many threads as the machine has cores.
+/// The results come back in the same order as `cases`, however the work was scheduled.
+pub fn run_scenarios<C, R, F>(program:&CompiledProgram, cases:Vec<C>, scenario:F) -> Vec<R>
+    where C:Send, R:Send, F:Fn(&CompiledProgram, C) -> R + Sync
+{
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    run_scenarios_on(program, cases, threads, scenario)
+}
+
+/// `run_scenarios` on at most `threads` threads.
+/// A panic in any scenario, such as a failed assertion, is passed on once the other threads have
+/// finished the cases they are running; no new cases are started after it.
+pub fn run_scenarios_on<C, R, F>(program:&CompiledProgram, cases:Vec<C>, threads:usize, scenario:F) -> Vec<R>
+    where C:Send, R:Send, F:Fn(&CompiledProgram, C) -> R + Sync
+{
+    let count = cases.len();
+    let threads = threads.clamp(1, count.max(1));
+    // each worker takes the next case as soon as it is free, so slow cases don't hold up a whole batch
+    let queue = Mutex::new(cases.into_iter().enumerate());
+    let next_case = || queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+    let stop = AtomicBool::new(false);
+
+    let finished: Vec<Vec<(usize, R)>> = thread::scope(|s| {
+        let workers: Vec<_> = (0..threads).map(|_| s.spawn(|| {
+            let mut results = Vec::new();
+            while !stop.load(Ordering::Relaxed) {
+                let (idx, case) = match next_case() {
+                    Some(next) => next,
+                    None => break,
+                };
+                match panic::catch_unwind(AssertUnwindSafe(|| scenario(program, case))) {
+                    Ok(result) => results.push((idx, result)),
+                    Err(panic) => {
+                        stop.store(true, Ordering::Relaxed);
+                        panic::resume_unwind(panic);
+                    },
+                }
+            }
+            results
+        })).collect();
+        workers.into_iter()
+            .map(|w| w.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    });
+
+    let mut ordered: Vec<Option<R>> = (0..count).map(|_| None).collect();
+    for (idx, result) in finished.into_iter().flatten() {
+        ordered[idx] = Some(result);
+    }
+    ordered.into_iter().map(|r| r.expect("every case runs exactly once")).collect()
+}
@@ -0,0 +1,180 @@
+use std::fmt::{Formatter, Error};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Diagnostic, Severity, Span};
+
+/// The hardware a program is compiled for.
+/// The defaults match an IC10 chip in a housing: r0..r17, pins d0..d5 (plus `db`), a 512-value stack,
+/// and the in-game editor's limits of 128 lines of at most 90 characters, 4096 bytes in all.
+#[derive(Clone,Debug,PartialEq)]
+pub struct ChipProfile
+{
+    pub registers: u8,
+    pub device_pins: u8,
+    /// how many values the stack holds
+    pub stack_size: usize,
+    pub max_lines: usize,
+    /// in characters
+    pub max_line_length: usize,
+    /// in bytes, counting the newline between lines
+    pub max_bytes: usize,
+    /// how to report a program that breaks one of the size limits
+    pub limit_severity: Severity,
+}
+
+impl ChipProfile
+{
+    pub fn ic10() -> ChipProfile
+    {
+        ChipProfile {
+            registers: 18,
+            device_pins: 6,
+            stack_size: 512,
+            max_lines: 128,
+            max_line_length: 90,
+            max_bytes: 4096,
+            limit_severity: Severity::Error,
+        }
+    }
+
+    pub fn has_register(&self, idx:u8) -> bool
+    {
+        idx < self.registers
+    }
+
+    pub fn has_device_pin(&self, idx:u8) -> bool
+    {
+        idx < self.device_pins
+    }
+
+    fn limit_diagnostic(&self, span:Span, message:&str) -> Diagnostic
+    {
+        match self.limit_severity {
+            Severity::Error => Diagnostic::error(span, message),
+            Severity::Warning => Diagnostic::warning(span, message),
+        }
+    }
+
+    /// measure the source against the size limits, reporting each line that breaks one
+    pub fn check_limits(&self, lines:&[&str]) -> (SizeReport, Vec<Diagnostic>)
+    {
+        let mut diagnostics = Vec::new();
+        let mut longest_line = 0;
+        let mut longest_length = 0;
+        let mut bytes = 0;
+
+        for (line_number, line) in lines.iter().enumerate() {
+            let length = line.chars().count();
+            if length > longest_length {
+                longest_line = line_number;
+                longest_length = length;
+            }
+            if length > self.max_line_length {
+                let start = line.char_indices().nth(self.max_line_length).map_or(0, |(idx, _)| idx);
+                diagnostics.push(self.limit_diagnostic(Span::new(line_number, start, line.len()),
+                                                       &format!("line is {} characters long; the limit is {}", length, self.max_line_length)));
+            }
+
+            let separator = if line_number > 0 { 1 } else { 0 };
+            if bytes <= self.max_bytes && bytes + separator + line.len() > self.max_bytes {
+                let start = (self.max_bytes - bytes).saturating_sub(separator).min(line.len());
+                diagnostics.push(self.limit_diagnostic(Span::new(line_number, start, line.len()),
+                                                       &format!("program is longer than {} bytes", self.max_bytes)));
+            }
+            bytes += separator + line.len();
+
+            if line_number == self.max_lines {
+                diagnostics.push(self.limit_diagnostic(Span::new(line_number, 0, line.len()),
+                                                       &format!("program has {} lines; the chip holds {}", lines.len(), self.max_lines)));
+            }
+        }
+
+        let report = SizeReport {
+            lines: lines.len(),
+            longest_line,
+            longest_line_length: longest_length,
+            bytes,
+            max_lines: self.max_lines,
+            max_line_length: self.max_line_length,
+            max_bytes: self.max_bytes,
+        };
+        (report, diagnostics)
+    }
+}
+
+impl Default for ChipProfile
+{
+    fn default() -> Self {
+        ChipProfile::ic10()
+    }
+}
+
+//
+
+/// How much of the chip's source limits a program uses
+#[derive(Clone,Debug,PartialEq)]
+pub struct SizeReport
+{
+    pub lines: usize,
+    /// the line number of the longest line
+    pub longest_line: usize,
+    pub longest_line_length: usize,
+    pub bytes: usize,
+    pub max_lines: usize,
+    pub max_line_length: usize,
+    pub max_bytes: usize,
+}
+
+impl SizeReport
+{
+    /// negative when over the limit
+    pub fn line_headroom(&self) -> isize
+    {
+        self.max_lines as isize - self.lines as isize
+    }
+
+    pub fn line_length_headroom(&self) -> isize
+    {
+        self.max_line_length as isize - self.longest_line_length as isize
+    }
+
+    pub fn byte_headroom(&self) -> isize
+    {
+        self.max_bytes as isize - self.bytes as isize
+    }
+
+    pub fn fits(&self) -> bool
+    {
+        self.line_headroom() >= 0 && self.line_length_headroom() >= 0 && self.byte_headroom() >= 0
+    }
+}
+
+impl std::fmt::Display for SizeReport
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        writeln!(f, "lines: {}/{} ({} left)", self.lines, self.max_lines, self.line_headroom())?;
+        writeln!(f, "longest line: {}/{} characters on line {} ({} left)",
+                 self.longest_line_length, self.max_line_length, self.longest_line, self.line_length_headroom())?;
+        write!(f, "bytes: {}/{} ({} left)", self.bytes, self.max_bytes, self.byte_headroom())
+    }
+}
+
+//
+
+/// How forgiving a running chip is about values nothing has set.
+/// Every profile, `Lenient` included, faults on a device pin with nothing attached, as the game does:
+/// `Lenient` is the default, and reading zeros from a device a test forgot to attach would let the
+/// missing wiring pass unnoticed.  Pins that are meant to be empty can be tested with `bdns`.
+#[derive(Copy,Clone,Debug,Default,PartialEq,Serialize,Deserialize)]
+pub enum Strictness
+{
+    #[default]
+    /// like the javascript simulator: registers start as NaN and a field the device does not have loads 0
+    Lenient,
+    /// like the game: registers start at 0 and loading a field the device does not have faults
+    GameAccurate,
+    /// registers start at 0, but reading a register or device field that was never written faults,
+    /// and a `DeviceKind`'s default values do not count as written
+    Pedantic,
+}
@@ -0,0 +1,315 @@
+use std::fmt::{Formatter, Error};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use rand::{Rng, SeedableRng};
+use rand::distributions::Uniform;
+use rand_chacha::ChaCha8Rng;
+
+use crate::wiring::{debug_as_display, Wiring};
+use crate::{compile, execute_until_yields, CPUContext, CompileError, CompiledProgram, Device, DeviceAttachment, Invariant,
+            Strictness};
+
+/// how many cases `Property::run` tries unless told otherwise
+pub const PROPERTY_CASES: u32 = 100;
+
+/// how many times shrinking goes over every input before settling for what it has
+const SHRINK_PASSES: usize = 8;
+/// halvings of the gap between a failing value and the simplest one
+const SHRINK_STEPS: usize = 24;
+
+struct FieldInput
+{
+    name: String,
+    device: Device,
+    field: String,
+    low: f32,
+    high: f32,
+}
+
+/// A property of a program checked against many random inputs, rather than a few hand-picked ones:
+///
+/// ```ignore
+/// Property::compile(PROG2)?
+///     .device("sensorGH", DeviceState::new())
+///     .device("filter", DeviceState::new())
+///     .input("sensorGH", "RatioCarbonDioxide", 0.0..=0.3)
+///     .invariant("filter on whenever CO2 is low", |ctx| ...)
+///     .run()?;
+/// ```
+///
+/// Each case draws every input from its range, sets the fields, and runs the program for `ticks` ticks,
+/// checking the invariants after each one.  A failing case is shrunk, one input at a time, to the values
+/// closest to 0 and with the fewest decimals that still fail, and reported with the seed that replays it.
+/// The cases follow from `seed`, so a run can be repeated exactly.
+pub struct Property
+{
+    wiring: Wiring,
+    inputs: Vec<FieldInput>,
+    invariants: Vec<(String, Invariant)>,
+    ticks: u32,
+    cases: u32,
+    seed: u64,
+}
+
+impl Property
+{
+    pub fn new(program:CompiledProgram) -> Property
+    {
+        Property {
+            wiring: Wiring::new(program),
+            inputs: Vec::new(),
+            invariants: Vec::new(),
+            ticks: 1,
+            cases: PROPERTY_CASES,
+            seed: 0,
+        }
+    }
+
+    pub fn compile(src:&str) -> Result<Property, CompileError>
+    {
+        Ok(Property::new(compile(src)?))
+    }
+
+    pub fn strictness(mut self, strictness:Strictness) -> Property
+    {
+        self.wiring.strictness = strictness;
+        self
+    }
+
+    /// as `Scenario::device`
+    pub fn device<D:Into<DeviceAttachment>>(mut self, name:&str, dev:D) -> Property
+    {
+        self.wiring.device(name, dev.into());
+        self
+    }
+
+    /// draw the field's value for each case from `range`
+    pub fn input(mut self, name:&str, field:&str, range:RangeInclusive<f32>) -> Property
+    {
+        let (low, high) = range.into_inner();
+        if !low.is_finite() || !high.is_finite() {
+            self.wiring.complain(format!("{} {} needs a finite range, not {}..={}", name, field, low, high));
+        } else if low > high {
+            self.wiring.complain(format!("{} {} has an empty range {}..={}", name, field, low, high));
+        }
+        if let Some(device) = self.wiring.find_device(name) {
+            self.inputs.push(FieldInput { name: name.to_string(), device, field: field.to_string(), low, high });
+        }
+        self
+    }
+
+    /// something that must hold after every tick of every case
+    pub fn invariant<F>(mut self, name:&str, holds:F) -> Property
+        where F:Fn(&CPUContext) -> bool + Send + Sync + 'static
+    {
+        self.invariants.push((name.to_string(), Arc::new(holds)));
+        self
+    }
+
+    /// how many ticks each case runs; 1 unless told otherwise
+    pub fn ticks(mut self, ticks:u32) -> Property
+    {
+        self.ticks = ticks;
+        self
+    }
+
+    pub fn cases(mut self, cases:u32) -> Property
+    {
+        self.cases = cases;
+        self
+    }
+
+    pub fn seed(mut self, seed:u64) -> Property
+    {
+        self.seed = seed;
+        self
+    }
+
+    /// Try every case, stopping at the first that fails
+    pub fn run(&self) -> Result<(), PropertyFailure>
+    {
+        let mut rng = ChaCha8Rng::seed_from_u64(self.seed);
+        for case in 0..self.cases {
+            let case_seed = rng.gen::<u64>();
+            self.try_case(case_seed).map_err(|failure| PropertyFailure { case: Some(case), ..failure })?;
+        }
+        Ok(())
+    }
+
+    /// Try only the case `case_seed` picks, as given in a `PropertyFailure`
+    pub fn replay(&self, case_seed:u64) -> Result<(), PropertyFailure>
+    {
+        self.try_case(case_seed)
+    }
+
+    fn try_case(&self, case_seed:u64) -> Result<(), PropertyFailure>
+    {
+        let failure = |message:String, found:&[f32], inputs:&[f32]| PropertyFailure {
+            seed: self.seed,
+            case: None,
+            case_seed,
+            message,
+            inputs: self.named(inputs),
+            found: self.named(found),
+        };
+        if let Some(problem) = self.wiring.problem() {
+            return Err(failure(problem.to_string(), &[], &[]));
+        }
+
+        let mut rng = ChaCha8Rng::seed_from_u64(case_seed);
+        let found: Vec<f32> = self.inputs.iter()
+            .map(|i| rng.sample(Uniform::new_inclusive(i.low, i.high)))
+            .collect();
+        if self.check(&found).is_ok() {
+            return Ok(());
+        }
+        let inputs = self.shrink(found.clone());
+        let message = self.check(&inputs).err().unwrap_or_default();
+        Err(failure(message, &found, &inputs))
+    }
+
+    fn named(&self, values:&[f32]) -> Vec<(String, f32)>
+    {
+        self.inputs.iter().zip(values)
+            .map(|(input, &value)| (format!("{} {}", input.name, input.field), value))
+            .collect()
+    }
+
+    /// run one case, returning what went wrong
+    fn check(&self, values:&[f32]) -> Result<(), String>
+    {
+        let mut ctx = self.wiring.context()?;
+        for (input, &value) in self.inputs.iter().zip(values) {
+            ctx.device_reference(input.device)
+                .map_err(|e| format!("can not set {} {}: {}", input.name, input.field, e))?
+                .insert(input.field.clone(), value);
+        }
+        for _ in 0..self.ticks {
+            if let Err(e) = execute_until_yields(&self.wiring.program, &mut ctx, 1) {
+                return Err(format!("after tick {}: line {} faulted: {}", ctx.ticks(), ctx.instruction_pointer(), e));
+            }
+            if let Some((name, _)) = self.invariants.iter().find(|(_, holds)| !holds(&ctx)) {
+                return Err(format!("after tick {}: invariant broken: {}", ctx.ticks(), name));
+            }
+        }
+        Ok(())
+    }
+
+    fn fails_with(&self, values:&[f32], idx:usize, value:f32) -> bool
+    {
+        let mut values = values.to_vec();
+        values[idx] = value;
+        self.check(&values).is_err()
+    }
+
+    /// Move each input of a failing case towards the value in its range nearest 0, as far as it
+    /// will still fail, then to the roundest value between there and where it started that fails.
+    fn shrink(&self, mut values:Vec<f32>) -> Vec<f32>
+    {
+        for _ in 0..SHRINK_PASSES {
+            let mut changed = false;
+            for idx in 0..values.len() {
+                let (low, high) = (self.inputs[idx].low, self.inputs[idx].high);
+                let target = 0f32.max(low).min(high);
+                let start = values[idx];
+                if start == target {
+                    continue;
+                }
+                if self.fails_with(&values, idx, target) {
+                    values[idx] = target;
+                    changed = true;
+                    continue;
+                }
+
+                let (mut passing, mut failing) = (target, start);
+                for _ in 0..SHRINK_STEPS {
+                    let mid = passing + (failing - passing) / 2.0;
+                    if mid == passing || mid == failing {
+                        break;
+                    }
+                    if self.fails_with(&values, idx, mid) {
+                        failing = mid;
+                    } else {
+                        passing = mid;
+                    }
+                }
+
+                let no_further = |v:f32| (v - target) * (start - target) >= 0.0 && (v - target).abs() <= (start - target).abs();
+                'round: for decimals in 0..7 {
+                    let scale = 10f32.powi(decimals);
+                    let (down, up) = ((failing * scale).floor() / scale, (failing * scale).ceil() / scale);
+                    let candidates = if (down - target).abs() <= (up - target).abs() { [down, up] } else { [up, down] };
+                    for candidate in candidates {
+                        if no_further(candidate) && self.fails_with(&values, idx, candidate) {
+                            failing = candidate;
+                            break 'round;
+                        }
+                    }
+                }
+
+                if failing != start {
+                    values[idx] = failing;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        values
+    }
+}
+
+//
+
+/// A case a `Property` failed on, shrunk as far as it would go.
+#[derive(Clone,PartialEq)]
+pub struct PropertyFailure
+{
+    /// the seed of the whole run
+    pub seed: u64,
+    /// which case failed, counting from 0; `None` for a replayed case
+    pub case: Option<u32>,
+    /// what to give `Property::replay` to try this case again
+    pub case_seed: u64,
+    /// what went wrong with the shrunk inputs
+    pub message: String,
+    /// the shrunk inputs, such as `("sensorGH Pressure", 109.0)`
+    pub inputs: Vec<(String, f32)>,
+    /// the inputs as first drawn
+    pub found: Vec<(String, f32)>,
+}
+
+impl std::fmt::Display for PropertyFailure
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        if self.inputs.is_empty() {
+            return write!(f, "{}", self.message);
+        }
+        match self.case {
+            Some(case) => write!(f, "case {} of seed {} failed", case, self.seed)?,
+            None => write!(f, "case seed {} failed", self.case_seed)?,
+        }
+        write!(f, ": {}\nsmallest failing inputs:", self.message)?;
+        for (name, value) in &self.inputs {
+            write!(f, "\n  {} = {}", name, value)?;
+        }
+        if self.found != self.inputs {
+            write!(f, "\nas first drawn:")?;
+            for (name, value) in &self.found {
+                write!(f, "\n  {} = {}", name, value)?;
+            }
+        }
+        write!(f, "\nreplay with .replay({})", self.case_seed)
+    }
+}
+
+debug_as_display!(PropertyFailure);
+
+impl From<CompileError> for PropertyFailure
+{
+    fn from(e: CompileError) -> Self {
+        PropertyFailure { seed: 0, case: None, case_seed: 0, message: e.to_string(), inputs: Vec::new(), found: Vec::new() }
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::VecDeque;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// Where `rand` gets its values: a seeded generator, so a run can be repeated exactly,
+/// and a queue of scripted values that are used up before the generator is consulted.
+/// The generator is ChaCha8, named rather than `StdRng`, so that seeds saved in snapshots
+/// and test failures give the same values whatever version of `rand` is in use.
+#[derive(Clone,Debug)]
+pub struct RandomSource
+{
+    seed: u64,
+    /// how many values the generator has produced since it was seeded
+    draws: u64,
+    rng: ChaCha8Rng,
+    scripted: VecDeque<f32>,
+}
+
+impl RandomSource
+{
+    pub fn new(seed:u64) -> RandomSource
+    {
+        RandomSource {
+            seed,
+            draws: 0,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            scripted: VecDeque::new(),
+        }
+    }
+
+    /// a source seeded with `seed` that has already produced `draws` values, with `scripted` still queued
+    pub fn resume<I:IntoIterator<Item=f32>>(seed:u64, draws:u64, scripted:I) -> RandomSource
+    {
+        let mut rval = RandomSource::new(seed);
+        // each value takes one 32-bit word of the stream
+        rval.rng.set_word_pos(draws as u128);
+        rval.draws = draws;
+        rval.script(scripted);
+        rval
+    }
+
+    pub fn seed(&self) -> u64
+    {
+        self.seed
+    }
+
+    pub fn draws(&self) -> u64
+    {
+        self.draws
+    }
+
+    /// the values still waiting to be returned ahead of the generator
+    pub fn scripted(&self) -> impl Iterator<Item=f32> + '_
+    {
+        self.scripted.iter().copied()
+    }
+
+    /// queue values for `rand` to return, in order, before it goes back to the generator
+    pub fn script<I:IntoIterator<Item=f32>>(&mut self, values:I)
+    {
+        self.scripted.extend(values);
+    }
+
+    /// a value in [0, 1)
+    pub fn next_value(&mut self) -> f32
+    {
+        match self.scripted.pop_front() {
+            Some(val) => val,
+            None => {
+                self.draws += 1;
+                self.rng.gen::<f32>()
+            }
+        }
+    }
+}
+
+impl Default for RandomSource
+{
+    fn default() -> Self {
+        RandomSource::new(0)
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use crate::{binding_states, AliasOrDevice, Device, LValue, Op, RValue, Register, RegisterOrDevice, SourceModel};
+
+/// What every alias or define of a name in the program agrees it means
+#[derive(Copy,Clone,PartialEq)]
+enum Meaning
+{
+    Register(Register),
+    Device(Device),
+    Value(f32),
+    /// declared more than one way, so it has to be looked up when the program runs
+    Varies,
+}
+
+struct Resolver
+{
+    meanings: HashMap<String, Meaning>,
+}
+
+impl Resolver
+{
+    fn meaning(&self, name:&str) -> Meaning
+    {
+        self.meanings.get(name).copied().unwrap_or(Meaning::Varies)
+    }
+
+    fn r_value(&self, r_value:&mut RValue, declared:&dyn Fn(&str) -> bool)
+    {
+        if let RValue::Name(name) = r_value {
+            if !declared(name) {
+                return;
+            }
+            match self.meaning(name) {
+                Meaning::Register(reg) => *r_value = RValue::ResolvedAlias(std::mem::take(name), reg),
+                Meaning::Value(val) => *r_value = RValue::ResolvedDefine(std::mem::take(name), val),
+                _ => {},
+            }
+        }
+    }
+
+    fn l_value(&self, l_value:&mut LValue, declared:&dyn Fn(&str) -> bool)
+    {
+        if let LValue::Alias(name) = l_value {
+            if let (true, Meaning::Register(reg)) = (declared(name), self.meaning(name)) {
+                *l_value = LValue::ResolvedAlias(std::mem::take(name), reg);
+            }
+        }
+    }
+
+    fn device(&self, device:&mut AliasOrDevice, declared:&dyn Fn(&str) -> bool)
+    {
+        if let AliasOrDevice::Alias(name) = device {
+            if let (true, Meaning::Device(dev)) = (declared(name), self.meaning(name)) {
+                *device = AliasOrDevice::ResolvedAlias(std::mem::take(name), dev);
+            }
+        }
+    }
+}
+
+/// Replace the aliases and defines in `codes` with what they refer to, wherever that can be
+/// decided before the program runs: every declaration of the name in the program agrees, and
+/// the name has been declared on every path that reaches the line.  The names are kept for
+/// printing and for error messages, but the CPU no longer has to look them up.
+pub fn resolve_names(codes:&mut [Op], model:&SourceModel)
+{
+    let mut meanings: HashMap<String, Meaning> = HashMap::new();
+    for op in codes.iter() {
+        let (name, meaning) = match op {
+            Op::Alias(alias) => match alias.d_line {
+                RegisterOrDevice::Register(reg) => (&alias.handle, Meaning::Register(reg)),
+                RegisterOrDevice::Device(dev) => (&alias.handle, Meaning::Device(dev)),
+            },
+            Op::Define(define) => (&define.tag, Meaning::Value(define.value)),
+            _ => continue,
+        };
+        let entry = meanings.entry(name.clone()).or_insert(meaning);
+        if *entry != meaning {
+            *entry = Meaning::Varies;
+        }
+    }
+
+    let resolver = Resolver { meanings };
+    let states = binding_states(model);
+    for (line, op) in codes.iter_mut().enumerate() {
+        let state = match states.get(line) {
+            Some(Some(state)) => state,
+            _ => continue,
+        };
+        let declared = |name:&str| state.get(name).is_some_and(|b| !b.unbound);
+        match op {
+            Op::SetDevice(op) => {
+                resolver.device(&mut op.device, &declared);
+                resolver.r_value(&mut op.r_value, &declared);
+            },
+            Op::LoadDevice(op) => {
+                resolver.l_value(&mut op.l_value, &declared);
+                resolver.device(&mut op.device, &declared);
+            },
+            Op::Move(op) => {
+                resolver.l_value(&mut op.l_value, &declared);
+                resolver.r_value(&mut op.r_value, &declared);
+            },
+            Op::Unary(op) => {
+                resolver.l_value(&mut op.l_value, &declared);
+                resolver.r_value(&mut op.arg1, &declared);
+            },
+            Op::Binary(op) => {
+                resolver.l_value(&mut op.l_value, &declared);
+                resolver.r_value(&mut op.arg1, &declared);
+                resolver.r_value(&mut op.arg2, &declared);
+            },
+            Op::Ternary(op) => {
+                resolver.l_value(&mut op.l_value, &declared);
+                resolver.r_value(&mut op.arg1, &declared);
+                resolver.r_value(&mut op.arg2, &declared);
+                resolver.r_value(&mut op.arg3, &declared);
+            },
+            Op::Random(op) => resolver.l_value(&mut op.l_value, &declared),
+            Op::Branch(op) => {
+                resolver.r_value(&mut op.arg1, &declared);
+                resolver.r_value(&mut op.arg2, &declared);
+            },
+            Op::BranchDevice(op) => resolver.device(&mut op.dev, &declared),
+            Op::BranchTernary(op) => {
+                resolver.r_value(&mut op.arg1, &declared);
+                resolver.r_value(&mut op.arg2, &declared);
+                resolver.r_value(&mut op.frac, &declared);
+            },
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fmt::{Formatter, Error};
+
+use crate::wiring::{debug_as_display, device_named, Wiring};
+use crate::{compile, CPUContext, CompileError, CompiledProgram, Device, DeviceAttachment,
+            InputSource, InstructionPointer, Monitor, Op, Strictness, Timeline, TimelineError};
+
+/// how many of the most recent instructions a `ScenarioFailure` shows
+pub const SCENARIO_TRACE_LENGTH: usize = 16;
+
+enum Step
+{
+    Set { name: String, device: Device, field: String, value: f32 },
+    Input { device: Device, field: String, source: InputSource },
+    Ticks(u32),
+    ExpectField { name: String, device: Device, field: String, value: f32 },
+    ExpectValue { name: String, value: f32 },
+}
+
+/// A tick-by-tick test of a program, written in the program's own vocabulary:
+///
+/// ```ignore
+/// Scenario::compile(PROG2)?
+///     .device("sensorGH", DeviceState::new())
+///     .device("pumpGH", &VOLUME_PUMP)
+///     .set("sensorGH", "Pressure", 125.0)
+///     .tick()
+///     .expect("pumpGH", "On", 1.0)
+///     .run()?;
+/// ```
+///
+/// Devices are named by the aliases the program declares for them (or as `d0`..`d5`, `db`),
+/// so they can be wired and set up before the `alias` lines have run.
+/// Nothing happens until `run`, which stops at the first expectation that does not hold.
+pub struct Scenario
+{
+    wiring: Wiring,
+    seed: Option<u64>,
+    steps: Vec<Step>,
+    timeline: Timeline,
+    monitors: Vec<Monitor>,
+}
+
+impl Scenario
+{
+    pub fn new(program:CompiledProgram) -> Scenario
+    {
+        Scenario {
+            wiring: Wiring::new(program),
+            seed: None,
+            steps: Vec::new(),
+            timeline: Timeline::new(),
+            monitors: Vec::new(),
+        }
+    }
+
+    pub fn compile(src:&str) -> Result<Scenario, CompileError>
+    {
+        Ok(Scenario::new(compile(src)?))
+    }
+
+    pub fn strictness(mut self, strictness:Strictness) -> Scenario
+    {
+        self.wiring.strictness = strictness;
+        self
+    }
+
+    pub fn random_seed(mut self, seed:u64) -> Scenario
+    {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// plug `dev` into the pin `name` refers to; `dev` is anything `CPUContext::attach_device` accepts
+    pub fn device<D:Into<DeviceAttachment>>(mut self, name:&str, dev:D) -> Scenario
+    {
+        self.wiring.device(name, dev.into());
+        self
+    }
+
+    /// write a field of a device before the next tick, as the game world would
+    pub fn set(mut self, name:&str, field:&str, value:f32) -> Scenario
+    {
+        if let Some(device) = self.wiring.find_device(name) {
+            self.steps.push(Step::Set { name: name.to_string(), device, field: field.to_string(), value });
+        }
+        self
+    }
+
+    /// from here on, reads of the field take their values from `source`
+    pub fn input(mut self, name:&str, field:&str, source:InputSource) -> Scenario
+    {
+        if let Some(device) = self.wiring.find_device(name) {
+            self.steps.push(Step::Input { device, field: field.to_string(), source });
+        }
+        self
+    }
+
+    /// apply the events of `timeline` at their ticks, alongside any timelines already added
+    pub fn timeline(mut self, timeline:&Timeline) -> Scenario
+    {
+        for event in timeline.events() {
+            if let Err(message) = device_named(&self.wiring.program, event.event.device()) {
+                self.wiring.complain(format!("{}: {}", event, message));
+            }
+        }
+        self.timeline = self.timeline.merge(timeline);
+        self
+    }
+
+    /// check `monitor`'s requirements after every tick, and at the end of the run
+    pub fn monitor(mut self, monitor:&Monitor) -> Scenario
+    {
+        self.monitors.push(monitor.clone());
+        self
+    }
+
+    pub fn tick(self) -> Scenario
+    {
+        self.ticks(1)
+    }
+
+    pub fn ticks(mut self, count:u32) -> Scenario
+    {
+        self.steps.push(Step::Ticks(count));
+        self
+    }
+
+    /// after the ticks so far, the device `name` has `field` set to `value`
+    pub fn expect(mut self, name:&str, field:&str, value:f32) -> Scenario
+    {
+        if let Some(device) = self.wiring.find_device(name) {
+            self.steps.push(Step::ExpectField { name: name.to_string(), device, field: field.to_string(), value });
+        }
+        self
+    }
+
+    /// after the ticks so far, the register alias or define `name` has `value`
+    pub fn expect_value(mut self, name:&str, value:f32) -> Scenario
+    {
+        self.steps.push(Step::ExpectValue { name: name.to_string(), value });
+        self
+    }
+
+    /// Play the scenario through.  The context is returned for any further checks.
+    pub fn run(self) -> Result<CPUContext, ScenarioFailure>
+    {
+        let program = &self.wiring.program;
+        let mut trace: VecDeque<(u64, InstructionPointer)> = VecDeque::new();
+        let timeline = &self.timeline;
+        // how many of the timeline's events have been applied
+        let applied = Cell::new(0);
+        let mut monitors = self.monitors;
+        let fail = |tick:u64, trace:&VecDeque<(u64, InstructionPointer)>, message:String| ScenarioFailure {
+            tick,
+            message,
+            events: timeline.events()[..applied.get()].iter().map(|e| e.to_string()).collect(),
+            trace: trace.iter().map(|&(tick, line)| TraceLine {
+                tick,
+                line,
+                source: program.get_instruction(line).map_or(String::new(), |op| op.to_string()),
+            }).collect(),
+        };
+
+        let mut ctx = self.wiring.context().map_err(|problem| fail(0, &trace, problem))?;
+        if let Some(seed) = self.seed {
+            ctx.set_random_seed(seed);
+        }
+
+        for step in self.steps {
+            match step {
+                Step::Set { name, device, field, value } => {
+                    match ctx.device_reference(device) {
+                        Ok(state) => { state.insert(field, value); },
+                        Err(e) => return Err(fail(ctx.ticks(), &trace, format!("can not set {} {}: {}", name, field, e))),
+                    }
+                },
+                Step::Input { device, field, source } => ctx.attach_input(device, &field, source),
+                Step::Ticks(count) => {
+                    // a tick at a time, for the monitors
+                    for _ in 0..count {
+                        let before = ctx.ticks();
+                        let mut ran = ctx.instruction_pointer();
+                        let mut next = applied.get();
+                        let result = timeline.run(program, &mut ctx, 1, &mut next, |ctx| {
+                            // blank lines, comments and labels only clutter the trace
+                            if !matches!(program.get_instruction(ran), Some(Op::NoCode(_))) {
+                                if trace.len() == SCENARIO_TRACE_LENGTH {
+                                    trace.pop_front();
+                                }
+                                trace.push_back((ctx.ticks() + 1, ran));
+                            }
+                            ran = ctx.instruction_pointer();
+                        }, |_| {});
+                        applied.set(next);
+                        match result {
+                            Ok(()) => {},
+                            Err(TimelineError::Event(event, e)) => {
+                                return Err(fail(ctx.ticks(), &trace, format!("{} failed: {}", event, e)));
+                            },
+                            Err(TimelineError::Fault(e)) => {
+                                let message = format!("line {} faulted: {}", ctx.instruction_pointer(), e);
+                                return Err(fail(ctx.ticks(), &trace, message));
+                            },
+                        }
+                        for monitor in &mut monitors {
+                            if let Err(violation) = monitor.observe(&ctx) {
+                                return Err(fail(ctx.ticks(), &trace, violation.to_string()));
+                            }
+                        }
+                        if ctx.ticks() == before {
+                            break; // ran off the end of the program
+                        }
+                    }
+                },
+                Step::ExpectField { name, device, field, value } => {
+                    let actual = ctx.device_reference(device).ok().and_then(|state| state.get(&field).copied());
+                    if actual.is_none_or(|actual| !same(actual, value)) {
+                        let actual = actual.map_or("nothing".to_string(), |v| v.to_string());
+                        return Err(fail(ctx.ticks(), &trace, format!("expected {} {} = {}, found {}", name, field, value, actual)));
+                    }
+                },
+                Step::ExpectValue { name, value } => {
+                    match ctx.alias_value(&name) {
+                        Ok(actual) if same(actual, value) => {},
+                        Ok(actual) => return Err(fail(ctx.ticks(), &trace, format!("expected {} = {}, found {}", name, value, actual))),
+                        Err(e) => return Err(fail(ctx.ticks(), &trace, e.to_string())),
+                    }
+                },
+            }
+        }
+        for monitor in &mut monitors {
+            if let Err(violation) = monitor.finish(&ctx) {
+                return Err(fail(ctx.ticks(), &trace, violation.to_string()));
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+/// equal, counting NaN as equal to NaN
+fn same(a:f32, b:f32) -> bool
+{
+    a == b || (a.is_nan() && b.is_nan())
+}
+
+//
+
+/// One instruction a scenario ran
+#[derive(Clone,Debug,PartialEq)]
+pub struct TraceLine
+{
+    /// counting from 1
+    pub tick: u64,
+    pub line: InstructionPointer,
+    pub source: String,
+}
+
+impl std::fmt::Display for TraceLine
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "tick {} line {}: {}", self.tick, self.line, self.source)
+    }
+}
+
+/// Why a `Scenario` stopped, with the instructions that led up to it.
+#[derive(Clone,PartialEq)]
+pub struct ScenarioFailure
+{
+    /// how many ticks had run
+    pub tick: u64,
+    pub message: String,
+    /// the last few instructions, oldest first
+    pub trace: Vec<TraceLine>,
+    /// the timeline events that had happened, as in `tick 10: sensorPipe Pressure = 200`
+    pub events: Vec<String>,
+}
+
+impl std::fmt::Display for ScenarioFailure
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "after tick {}: {}", self.tick, self.message)?;
+        if !self.events.is_empty() {
+            write!(f, "\ntimeline so far:")?;
+            for event in &self.events {
+                write!(f, "\n  {}", event)?;
+            }
+        }
+        if !self.trace.is_empty() {
+            write!(f, "\nrecent instructions:")?;
+            for line in &self.trace {
+                write!(f, "\n  {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+debug_as_display!(ScenarioFailure);
+
+impl From<CompileError> for ScenarioFailure
+{
+    fn from(e: CompileError) -> Self {
+        ScenarioFailure { tick: 0, message: e.to_string(), trace: Vec::new(), events: Vec::new() }
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{CPUContext, Device, DeviceKind, DeviceState, ExecutionError, InstructionPointer, Location,
+            RandomSource, Register, RegisterOrDevice, Strictness, UninitializedRead};
+
+/// The complete state of a `CPUContext` at one moment, including the slots and any injected faults,
+/// apart from the labels, which belong to the program, and attached inputs, which may be closures.
+/// Restore it to branch several runs from the same point, or save it as JSON for a test fixture.
+/// The JSON is stable: names are sorted, so the same state always produces the same text.
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub struct ContextSnapshot
+{
+    pub(crate) instruction_pointer: InstructionPointer,
+    pub(crate) registers: Vec<Value>,
+    /// the registers that something has stored a value in
+    pub(crate) registers_written: Vec<String>,
+    pub(crate) aliases: BTreeMap<String, String>,
+    pub(crate) defines: BTreeMap<String, Value>,
+    /// one entry per pin, `null` where nothing is attached
+    pub(crate) devices: Vec<Option<DeviceSnapshot>>,
+    pub(crate) db: BTreeMap<String, Value>,
+    /// trailing zeros are left off
+    pub(crate) stack: Vec<Value>,
+    pub(crate) clock: Clock,
+    pub(crate) strictness: Strictness,
+    pub(crate) random: RandomSnapshot,
+    pub(crate) uninitialized_reads: Vec<ReadSnapshot>,
+    /// the fields of each slot, by pin, for the pins whose device has slots
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) slots: BTreeMap<String, Vec<BTreeMap<String, Value>>>,
+    /// by pin, what `detach_device` took off it and `reattach_device` will put back
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) unplugged: BTreeMap<String, DeviceSnapshot>,
+    /// the devices ignoring the program's writes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) dropping_writes: Vec<String>,
+    /// the value each stuck field reads, as in `d0[Pressure]`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) stuck_fields: BTreeMap<String, Value>,
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub(crate) struct DeviceSnapshot
+{
+    /// the prefab of its `DeviceKind`, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) kind: Option<String>,
+    pub(crate) fields: BTreeMap<String, Value>,
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub(crate) struct Clock
+{
+    pub(crate) ticks: u64,
+    pub(crate) instructions: u64,
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub(crate) struct RandomSnapshot
+{
+    pub(crate) seed: u64,
+    pub(crate) draws: u64,
+    pub(crate) scripted: Vec<Value>,
+}
+
+#[derive(Clone,Debug,PartialEq,Serialize,Deserialize)]
+pub(crate) struct ReadSnapshot
+{
+    pub(crate) ip: InstructionPointer,
+    pub(crate) operand: String,
+    /// `r1`, or `d0[Pressure]`
+    pub(crate) location: String,
+}
+
+/// JSON has no NaN or infinity, and unwritten registers are NaN, so those are written as strings
+#[derive(Copy,Clone,Debug)]
+pub(crate) struct Value(pub(crate) f32);
+
+impl PartialEq for Value
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits() || (self.0.is_nan() && other.0.is_nan())
+    }
+}
+
+impl Serialize for Value
+{
+    fn serialize<S:Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            val if val.is_finite() => serializer.serialize_f32(val),
+            val if val.is_nan() => serializer.serialize_str("NaN"),
+            val if val > 0.0 => serializer.serialize_str("inf"),
+            _ => serializer.serialize_str("-inf"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value
+{
+    fn deserialize<D:Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw
+        {
+            Number(f32),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Number(val) => Ok(Value(val)),
+            Raw::Text(text) => match text.as_str() {
+                "NaN" => Ok(Value(f32::NAN)),
+                "inf" => Ok(Value(f32::INFINITY)),
+                "-inf" => Ok(Value(f32::NEG_INFINITY)),
+                _ => Err(serde::de::Error::custom(format!("'{}' is not a number", text))),
+            },
+        }
+    }
+}
+
+fn sorted(state:&DeviceState) -> BTreeMap<String, Value>
+{
+    state.iter().map(|(field, &val)| (field.clone(), Value(val))).collect()
+}
+
+fn unsorted(fields:&BTreeMap<String, Value>) -> DeviceState
+{
+    fields.iter().map(|(field, val)| (field.clone(), val.0)).collect()
+}
+
+fn device_snapshot(state:&DeviceState, kind:Option<&'static DeviceKind>) -> DeviceSnapshot
+{
+    DeviceSnapshot { kind: kind.map(|k| k.prefab.to_string()), fields: sorted(state) }
+}
+
+fn device_kind(dev:&DeviceSnapshot) -> Result<Option<&'static DeviceKind>, ExecutionError>
+{
+    match &dev.kind {
+        None => Ok(None),
+        Some(prefab) => DeviceKind::by_prefab(prefab).map(Some)
+            .ok_or_else(|| ExecutionError::new(&format!("unknown device kind {}", prefab))),
+    }
+}
+
+/// the index of a pin named as in a snapshot, such as `d2`
+fn parse_pin(text:&str, pins:usize) -> Result<usize, ExecutionError>
+{
+    match Device::parse(text) {
+        Ok(Device::Regular(idx)) if (idx as usize) < pins => Ok(idx as usize),
+        _ => Err(ExecutionError::new(&format!("no device pin {}", text))),
+    }
+}
+
+fn parse_location(text:&str) -> Result<Location, ExecutionError>
+{
+    let bad = || ExecutionError::new(&format!("'{}' is not a register or device field", text));
+    match text.strip_suffix(']').and_then(|t| t.split_once('[')) {
+        Some((dev, field)) => Ok(Location::DeviceField(Device::parse(dev).map_err(|_| bad())?, field.to_string())),
+        None => match RegisterOrDevice::parse(text) {
+            Ok(RegisterOrDevice::Register(reg)) => Ok(Location::Register(reg)),
+            _ => Err(bad()),
+        },
+    }
+}
+
+impl ContextSnapshot
+{
+    pub fn capture(ctx:&CPUContext) -> ContextSnapshot
+    {
+        let stack_len = ctx.stack.iter().rposition(|&val| val != 0.0).map_or(0, |idx| idx+1);
+        ContextSnapshot {
+            instruction_pointer: ctx.instruction_pointer,
+            registers: ctx.registers.iter().map(|&val| Value(val)).collect(),
+            registers_written: ctx.registers_written.iter().enumerate()
+                .filter(|(_, &written)| written)
+                .map(|(idx, _)| Register { idx: idx as u8 }.to_string())
+                .collect(),
+            aliases: ctx.aliases.iter().map(|(name, rod)| (name.clone(), rod.to_string())).collect(),
+            defines: ctx.defines.iter().map(|(name, &val)| (name.clone(), Value(val))).collect(),
+            devices: ctx.devices.iter().zip(&ctx.device_kinds)
+                .map(|(state, &kind)| state.as_ref().map(|state| device_snapshot(state, kind)))
+                .collect(),
+            db: sorted(&ctx.device_b),
+            stack: ctx.stack[..stack_len].iter().map(|&val| Value(val)).collect(),
+            clock: Clock { ticks: ctx.ticks, instructions: ctx.instructions_executed },
+            strictness: ctx.strictness,
+            random: RandomSnapshot {
+                seed: ctx.random.seed(),
+                draws: ctx.random.draws(),
+                scripted: ctx.random.scripted().map(Value).collect(),
+            },
+            uninitialized_reads: ctx.uninitialized_reads.iter()
+                .map(|read| ReadSnapshot { ip: read.ip, operand: read.operand.clone(), location: read.location.to_string() })
+                .collect(),
+            slots: ctx.slots.iter().enumerate()
+                .filter(|(_, slots)| !slots.is_empty())
+                .map(|(idx, slots)| (format!("d{}", idx), slots.iter().map(sorted).collect()))
+                .collect(),
+            unplugged: ctx.unplugged.iter().enumerate()
+                .filter_map(|(idx, u)| u.as_ref().map(|(state, kind)| (format!("d{}", idx), device_snapshot(state, *kind))))
+                .collect(),
+            dropping_writes: {
+                let mut names: Vec<String> = ctx.dropping_writes.iter().map(|dev| dev.to_string()).collect();
+                names.sort();
+                names
+            },
+            stuck_fields: ctx.stuck_fields.iter()
+                .map(|(dev, field, val)| (Location::DeviceField(*dev, field.clone()).to_string(), Value(*val)))
+                .collect(),
+        }
+    }
+
+    /// see `CPUContext::restore`
+    pub fn restore_into(&self, ctx:&mut CPUContext) -> Result<(), ExecutionError>
+    {
+        if self.registers.len() != ctx.registers.len() || self.devices.len() != ctx.devices.len() {
+            return Err(ExecutionError::new(&format!(
+                "snapshot is of a chip with {} registers and {} pins, not {} and {}",
+                self.registers.len(), self.devices.len(), ctx.registers.len(), ctx.devices.len())));
+        }
+        if self.stack.len() > ctx.stack.len() {
+            return Err(ExecutionError::new(&format!("snapshot stack holds {} values, more than {}", self.stack.len(), ctx.stack.len())));
+        }
+
+        let mut registers_written = vec![false; ctx.registers.len()];
+        for name in &self.registers_written {
+            match RegisterOrDevice::parse(name) {
+                Ok(RegisterOrDevice::Register(reg)) if (reg.idx as usize) < registers_written.len() =>
+                    registers_written[reg.idx as usize] = true,
+                _ => return Err(ExecutionError::new(&format!("no register {}", name))),
+            }
+        }
+        let mut aliases = HashMap::new();
+        for (name, target) in &self.aliases {
+            let rod = RegisterOrDevice::parse(target).map_err(|e| ExecutionError::new(&format!("alias {}: {}", name, e)))?;
+            aliases.insert(name.clone(), rod);
+        }
+        let mut device_kinds = Vec::new();
+        for dev in &self.devices {
+            device_kinds.push(match dev {
+                None => None,
+                Some(dev) => device_kind(dev)?,
+            });
+        }
+        let pins = ctx.devices.len();
+        let mut slots: Vec<Vec<DeviceState>> = vec![Vec::new(); pins];
+        for (pin, fields) in &self.slots {
+            slots[parse_pin(pin, pins)?] = fields.iter().map(unsorted).collect();
+        }
+        let mut unplugged: Vec<Option<(DeviceState, Option<&'static DeviceKind>)>> = vec![None; pins];
+        for (pin, dev) in &self.unplugged {
+            unplugged[parse_pin(pin, pins)?] = Some((unsorted(&dev.fields), device_kind(dev)?));
+        }
+        let dropping_writes = self.dropping_writes.iter()
+            .map(|name| Device::parse(name).map_err(|_| ExecutionError::new(&format!("no device {}", name))))
+            .collect::<Result<Vec<_>, ExecutionError>>()?;
+        let stuck_fields = self.stuck_fields.iter()
+            .map(|(location, val)| match parse_location(location)? {
+                Location::DeviceField(dev, field) => Ok((dev, field, val.0)),
+                Location::Register(_) => Err(ExecutionError::new(&format!("{} is not a device field", location))),
+            })
+            .collect::<Result<Vec<_>, ExecutionError>>()?;
+        let uninitialized_reads = self.uninitialized_reads.iter()
+            .map(|read| Ok(UninitializedRead { ip: read.ip, operand: read.operand.clone(), location: parse_location(&read.location)? }))
+            .collect::<Result<Vec<_>, ExecutionError>>()?;
+
+        ctx.instruction_pointer = self.instruction_pointer;
+        ctx.registers = self.registers.iter().map(|val| val.0).collect();
+        ctx.registers_written = registers_written;
+        ctx.aliases = aliases;
+        ctx.defines = self.defines.iter().map(|(name, val)| (name.clone(), val.0)).collect();
+        ctx.devices = self.devices.iter().map(|dev| dev.as_ref().map(|d| unsorted(&d.fields))).collect();
+        ctx.device_kinds = device_kinds;
+        ctx.device_b = unsorted(&self.db);
+        for (idx, slot) in ctx.stack.iter_mut().enumerate() {
+            *slot = self.stack.get(idx).map_or(0.0, |val| val.0);
+        }
+        ctx.ticks = self.clock.ticks;
+        ctx.instructions_executed = self.clock.instructions;
+        ctx.strictness = self.strictness;
+        ctx.random = RandomSource::resume(self.random.seed, self.random.draws, self.random.scripted.iter().map(|val| val.0));
+        ctx.uninitialized_reads = uninitialized_reads;
+        ctx.slots = slots;
+        ctx.unplugged = unplugged;
+        ctx.dropping_writes = dropping_writes;
+        ctx.stuck_fields = stuck_fields;
+        ctx.saw_yield = false;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> String
+    {
+        serde_json::to_string_pretty(self).expect("a snapshot is always representable as JSON")
+    }
+
+    pub fn from_json(json:&str) -> Result<ContextSnapshot, ExecutionError>
+    {
+        serde_json::from_str(json).map_err(|e| ExecutionError::new(&format!("bad snapshot: {}", e)))
+    }
+}
@@ -0,0 +1,166 @@
+use crate::wiring::Wiring;
+use crate::{CPUContext, CompiledProgram, Device, DeviceAttachment, ExecutionError, Instruction, InstructionPointer,
+            LineNumber, Op, Register, Strictness};
+
+/// A subroutine that has not come back after this many instructions is assumed to be stuck in a loop
+pub const SUBROUTINE_INSTRUCTION_LIMIT: u64 = 100_000;
+
+/// Where `call_subroutine` stops
+#[derive(Clone,Debug,PartialEq)]
+pub enum ReturnPoint
+{
+    /// when the subroutine jumps back to `ra`
+    Link,
+    /// when control reaches this label
+    Label(String),
+}
+
+/// Run every `alias` and `define` line of the program, in order, and nothing else,
+/// so code that is started partway through can still use the program's names.
+pub fn run_declarations(program:&CompiledProgram, ctx:&mut CPUContext) -> Result<(), ExecutionError>
+{
+    let ip = ctx.instruction_pointer;
+    for op in program.ops() {
+        if let Op::Alias(_) | Op::Define(_) = op {
+            op.execute(ctx)?;
+        }
+    }
+    ctx.instruction_pointer = ip;
+    Ok(())
+}
+
+/// Run the code at `label` as if a `jal label` just past the end of the program had called it,
+/// until it gets to `until`.  Yields inside the subroutine end ticks as usual but don't stop it.
+/// If an instruction faults, `ctx` is left as it was at the fault, with the IP on the faulting line.
+pub fn call_subroutine(program:&CompiledProgram, ctx:&mut CPUContext, label:&str, until:&ReturnPoint) -> Result<(), ExecutionError>
+{
+    let start = ctx.lookup(&LineNumber::Label(label.to_string()))?;
+    // one past the end rather than the end itself, so falling off the end isn't mistaken for returning
+    let return_address = program.ops().len() as InstructionPointer + 1;
+    let stop = match until {
+        ReturnPoint::Link => return_address,
+        ReturnPoint::Label(label) => ctx.lookup(&LineNumber::Label(label.clone()))?,
+    };
+
+    ctx.set_ra(return_address);
+    ctx.jump(start);
+    let mut count = 0;
+    while ctx.instruction_pointer != stop {
+        let ip = ctx.instruction_pointer;
+        let inst = match program.get_instruction(ip) {
+            Some(inst) => inst,
+            None => return Err(ExecutionError::new(&format!("{} ran off the end of the program without returning", label))),
+        };
+        if let Err(e) = inst.execute(ctx) {
+            ctx.instruction_pointer = ip;
+            return Err(e);
+        }
+        ctx.instructions_executed += 1;
+        if ctx.reset_yield() {
+            ctx.ticks += 1;
+        }
+        count += 1;
+        if count >= SUBROUTINE_INSTRUCTION_LIMIT {
+            return Err(ExecutionError::new(&format!("{} did not return after {} instructions", label, count)));
+        }
+    }
+    Ok(())
+}
+
+//
+
+/// A test of one subroutine on its own, like a unit test of a function:
+///
+/// ```ignore
+/// let ctx = Subroutine::new(&program, "clamp")
+///     .value("input", 150.0)
+///     .call()?;
+/// assert_alias!(ctx, "output", 100.0);
+/// ```
+///
+/// The program's `alias` and `define` lines are run first, so registers and devices can be set up
+/// by the names the program gives them.
+pub struct Subroutine
+{
+    wiring: Wiring,
+    label: String,
+    until: ReturnPoint,
+    fields: Vec<(Device, String, f32)>,
+    registers: Vec<(Register, f32)>,
+    values: Vec<(String, f32)>,
+}
+
+impl Subroutine
+{
+    pub fn new(program:&CompiledProgram, label:&str) -> Subroutine
+    {
+        Subroutine {
+            wiring: Wiring::new(program.clone()),
+            label: label.to_string(),
+            until: ReturnPoint::Link,
+            fields: Vec::new(),
+            registers: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn strictness(mut self, strictness:Strictness) -> Subroutine
+    {
+        self.wiring.strictness = strictness;
+        self
+    }
+
+    /// stop when control reaches `label`, instead of when the subroutine returns
+    pub fn until_label(mut self, label:&str) -> Subroutine
+    {
+        self.until = ReturnPoint::Label(label.to_string());
+        self
+    }
+
+    pub fn register(mut self, reg:Register, value:f32) -> Subroutine
+    {
+        self.registers.push((reg, value));
+        self
+    }
+
+    /// set the register the alias `name` refers to
+    pub fn value(mut self, name:&str, value:f32) -> Subroutine
+    {
+        self.values.push((name.to_string(), value));
+        self
+    }
+
+    /// as `Scenario::device`
+    pub fn device<D:Into<DeviceAttachment>>(mut self, name:&str, dev:D) -> Subroutine
+    {
+        self.wiring.device(name, dev.into());
+        self
+    }
+
+    pub fn set(mut self, name:&str, field:&str, value:f32) -> Subroutine
+    {
+        if let Some(device) = self.wiring.find_device(name) {
+            self.fields.push((device, field.to_string(), value));
+        }
+        self
+    }
+
+    /// Run the subroutine, returning the state it leaves behind
+    pub fn call(self) -> Result<CPUContext, ExecutionError>
+    {
+        let program = &self.wiring.program;
+        let mut ctx = self.wiring.context().map_err(|problem| ExecutionError::new(&problem))?;
+        run_declarations(program, &mut ctx)?;
+        for (device, field, value) in self.fields {
+            ctx.device_reference(device)?.insert(field, value);
+        }
+        for (reg, value) in self.registers {
+            *ctx.register_reference_mut(reg)? = value;
+        }
+        for (name, value) in self.values {
+            ctx.set_alias_value(&name, value)?;
+        }
+        call_subroutine(program, &mut ctx, &self.label, &self.until)?;
+        Ok(ctx)
+    }
+}
@@ -1,4 +1,6 @@
 use super::*;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /*
 This is a (probably-incomplete) test of the Stationeers/MIPS instructions listed at
@@ -17,14 +19,14 @@ pub fn test_bdns() -> Result<(),MultiError>
 
     {
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 2.0);
         assert!(ctx.get_ra().is_nan());
     }
     {
         let mut ctx = CPUContext::new_simple(&program);
         ctx.attach_device(0, DeviceState::new())?;
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 3.0);
     }
     Ok(())
@@ -40,14 +42,14 @@ pub fn test_bdnsal() -> Result<(),MultiError>
 
     {
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 2.0);
         assert_eq!(ctx.get_ra(), 2.0);
     }
     {
         let mut ctx = CPUContext::new_simple(&program);
         ctx.attach_device(0, DeviceState::new())?;
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 3.0);
         assert!(ctx.get_ra().is_nan());
     }
@@ -64,7 +66,7 @@ pub fn test_bdse() -> Result<(),MultiError>
 
     {
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 4.0);
         assert_eq!(ctx.register_reference(Register{idx:1})?, 11.0);
         assert!(ctx.get_ra().is_nan());
@@ -72,7 +74,7 @@ pub fn test_bdse() -> Result<(),MultiError>
     {
         let mut ctx = CPUContext::new_simple(&program);
         ctx.attach_device(0, DeviceState::new())?;
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 5.0);
         assert_eq!(ctx.register_reference(Register{idx:1})?, 11.0);
     }
@@ -89,14 +91,14 @@ pub fn test_bdseal() -> Result<(),MultiError>
 
     {
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 4.0);
         assert!(ctx.get_ra().is_nan());
     }
     {
         let mut ctx = CPUContext::new_simple(&program);
         ctx.attach_device(0, DeviceState::new())?;
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 5.0);
         assert_eq!(ctx.get_ra(), 2.0);
     }
@@ -113,7 +115,7 @@ pub fn test_brdns() -> Result<(),MultiError>
 
     {
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 2.0);
         assert!(ctx.register_reference(Register{idx:1})?.is_nan());
         assert!(ctx.get_ra().is_nan());
@@ -121,7 +123,7 @@ pub fn test_brdns() -> Result<(),MultiError>
     {
         let mut ctx = CPUContext::new_simple(&program);
         ctx.attach_device(0, DeviceState::new())?;
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 3.0);
         assert!(ctx.get_ra().is_nan());
     }
@@ -138,14 +140,14 @@ pub fn test_brdse() -> Result<(),MultiError>
 
     {
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 4.0);
         assert!(ctx.get_ra().is_nan());
     }
     {
         let mut ctx = CPUContext::new_simple(&program);
         ctx.attach_device(0, DeviceState::new())?;
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        execute_until_yields(&program, &mut ctx, 99)?;
         assert_eq!(ctx.register_reference(Register{idx:0})?, 5.0);
         assert!(ctx.register_reference(Register{idx:1})?.is_nan());
         assert!(ctx.get_ra().is_nan());
@@ -161,8 +163,8 @@ pub fn test_load1() -> Result<(),MultiError>
     let source = include_str!("tests/test_l.mips");
     let program = compile(source)?;
 
-    let ctx = CPUContext::new_simple(&program);
-    assert!( execute_until_yields(&program, ctx, 99).is_err() , "should have failed");
+    let mut ctx = CPUContext::new_simple(&program);
+    assert!( execute_until_yields(&program, &mut ctx, 99).is_err() , "should have failed");
 
     Ok(())
 }
@@ -174,8 +176,8 @@ pub fn test_load2() -> Result<(),MultiError>
     let program = compile(source)?;
 
     let mut ctx = CPUContext::new_simple(&program);
-    ctx.attach_device(0, DeviceStateBuilder::new().set("Bacon", 7.5).build())?;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    ctx.attach_device(0, DeviceStateBuilder::new().set("Setting", 7.5).build())?;
+    execute_until_yields(&program, &mut ctx, 99)?;
     assert_eq!(ctx.register_reference(Register{idx:0})?, 7.5);
 
     Ok(())
@@ -189,10 +191,17 @@ pub fn test_load3() -> Result<(),MultiError>
 
     let mut ctx = CPUContext::new_simple(&program);
     ctx.attach_device(0, DeviceState::new())?;
-    ctx = execute_until_yields(&program, ctx, 99)?;
-    // XXX does the game really return 0 for fields that do not exist on a device?
+    execute_until_yields(&program, &mut ctx, 99)?;
+    // the javascript simulator returns 0 for fields that do not exist on a device; the game faults
     assert_eq!(ctx.register_reference(Register{idx:0})?, 0.0);
 
+    let mut ctx = CPUContext::new_with_strictness(&program, Strictness::GameAccurate);
+    ctx.attach_device(0, DeviceState::new())?;
+    match execute_until_yields(&program, &mut ctx, 99) {
+        Ok(_) => panic!("loading a missing field should fault"),
+        Err(e) => assert_eq!(e.message(), "d0[Setting] has no value"),
+    }
+
     Ok(())
 }
 
@@ -204,8 +213,8 @@ pub fn test_store1() ->Result<(), MultiError>
     let source = include_str!("tests/test_s.mips");
     let program = compile(source)?;
 
-    let ctx = CPUContext::new_simple(&program);
-    assert!( execute_until_yields(&program, ctx, 99).is_err(), "should have died on unlinked device");
+    let mut ctx = CPUContext::new_simple(&program);
+    assert!( execute_until_yields(&program, &mut ctx, 99).is_err(), "should have died on unlinked device");
 
     Ok(())
 }
@@ -218,12 +227,12 @@ pub fn test_store2() ->Result<(), MultiError>
 
     let mut ctx = CPUContext::new_simple(&program);
     ctx.attach_device(0, DeviceState::new())?;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     let dev_state = ctx.device_reference(Device::Regular(0))?;
-    assert_eq!( *dev_state.get("Nyan").unwrap(), 9000_f32);
-    assert_eq!( *dev_state.get("Cake").unwrap(), 5.0);
-    assert_eq!( *dev_state.get("Price").unwrap(), 4.75);
+    assert_eq!( *dev_state.get("Setting").unwrap(), 9000_f32);
+    assert_eq!( *dev_state.get("Mode").unwrap(), 5.0);
+    assert_eq!( *dev_state.get("Horizontal").unwrap(), 4.75);
 
     Ok(())
 }
@@ -240,7 +249,7 @@ pub fn test_bap1() ->Result<(), MultiError>
     *ctx.register_reference_mut(Register{idx:0})? = 4.0;
     *ctx.register_reference_mut(Register{idx:1})? = 4.01;
     *ctx.register_reference_mut(Register{idx:2})? = 0.01;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, 1.0);
 
@@ -257,7 +266,7 @@ pub fn test_bap2() ->Result<(), MultiError>
     *ctx.register_reference_mut(Register{idx:0})? = 4.0;
     *ctx.register_reference_mut(Register{idx:1})? = 4.05;
     *ctx.register_reference_mut(Register{idx:2})? = 0.01;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, 2.5);
 
@@ -274,7 +283,7 @@ pub fn test_bap3() ->Result<(), MultiError>
     *ctx.register_reference_mut(Register{idx:0})? = -4.0;
     *ctx.register_reference_mut(Register{idx:1})? = -4.01;
     *ctx.register_reference_mut(Register{idx:2})? = 0.01;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, 1.0);
 
@@ -293,7 +302,7 @@ pub fn test_bapal1() ->Result<(), MultiError>
     *ctx.register_reference_mut(Register{idx:0})? = 4.0;
     *ctx.register_reference_mut(Register{idx:1})? = 4.01;
     *ctx.register_reference_mut(Register{idx:2})? = 0.01;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, -4.0);
     assert_eq!(ctx.get_ra(), 1.0);
@@ -311,7 +320,7 @@ pub fn test_bapal2() ->Result<(), MultiError>
     *ctx.register_reference_mut(Register{idx:0})? = 4.0;
     *ctx.register_reference_mut(Register{idx:1})? = 4.05;
     *ctx.register_reference_mut(Register{idx:2})? = 0.01;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, 3.5);
     assert!(ctx.get_ra().is_nan());
@@ -329,7 +338,7 @@ pub fn test_bapal3() ->Result<(), MultiError>
     *ctx.register_reference_mut(Register{idx:0})? = -3.0;
     *ctx.register_reference_mut(Register{idx:1})? = -3.01;
     *ctx.register_reference_mut(Register{idx:2})? = 0.01;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, -4.0);
     assert_eq!(ctx.get_ra(), 1.0);
@@ -343,10 +352,10 @@ pub fn test_bapal3() ->Result<(), MultiError>
 pub fn check_binary_operator_019(program:&CompiledProgram, a:f32, b:f32, expected:f32) ->Result<(), MultiError>
 {
 
-    let mut ctx = CPUContext::new_simple(&program);
+    let mut ctx = CPUContext::new_simple(program);
     *ctx.register_reference_mut(Register{idx:0})? = a;
     *ctx.register_reference_mut(Register{idx:1})? = b;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, expected);
 
@@ -357,9 +366,9 @@ pub fn check_binary_operator_019(program:&CompiledProgram, a:f32, b:f32, expecte
 pub fn check_unary_operator_09(program:&CompiledProgram, a:f32, expected:f32) ->Result<(), MultiError>
 {
 
-    let mut ctx = CPUContext::new_simple(&program);
+    let mut ctx = CPUContext::new_simple(program);
     *ctx.register_reference_mut(Register{idx:0})? = a;
-    ctx = execute_until_yields(&program, ctx, 99)?;
+    execute_until_yields(program, &mut ctx, 99)?;
 
     assert_eq!(ctx.register_reference(Register{idx:9})?, expected);
 
@@ -575,20 +584,54 @@ pub fn test_rand() ->Result<(), MultiError>
     let source = include_str!("tests/test_rand.mips");
     let program = compile(source)?;
 
-    for _i in 0..10 {
+    for i in 0..10 {
 
         let mut ctx = CPUContext::new_simple(&program);
-        ctx = execute_until_yields(&program, ctx, 99)?;
+        ctx.set_random_seed(i);
+        execute_until_yields(&program, &mut ctx, 99)?;
 
         let val = ctx.register_reference(Register{idx:0})?;
-        let good = 0.0 <= val && val<1.0;
-        assert!(good, format!("random number {} outside acceptable range [0..1)", val));
+        let good = (0.0..1.0).contains(&val);
+        assert!(good, "random number {} outside acceptable range [0..1)", val);
 
     }
 
     Ok(())
 }
 
+#[test]
+pub fn test_rand_sequence() -> Result<(), MultiError>
+{
+    let source = include_str!("tests/test_rand_sequence.mips");
+    let program = compile(source)?;
+    let run = |mut ctx: CPUContext| -> Result<Vec<f32>, MultiError> {
+        execute_until_yields(&program, &mut ctx, 1)?;
+        assert_eq!(ctx.random_source().draws(), 3);
+        Ok((0..3).map(|idx| ctx.register_reference(Register{idx}).unwrap()).collect())
+    };
+
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.set_random_seed(42);
+    let first = run(ctx)?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.set_random_seed(42);
+    assert_eq!(run(ctx)?, first);
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.set_random_seed(43);
+    assert_ne!(run(ctx)?, first);
+
+    // scripted values come first, then the seeded sequence carries on
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.set_random_seed(42);
+    ctx.script_random(vec![0.25, 0.5]);
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_eq!(ctx.register_reference(Register{idx:0})?, 0.25);
+    assert_eq!(ctx.register_reference(Register{idx:1})?, 0.5);
+    assert_eq!(ctx.register_reference(Register{idx:2})?, first[0]);
+    assert_eq!(ctx.random_source().draws(), 1);
+    Ok(())
+}
+
 #[test]
 pub fn test_sub() -> Result<(), MultiError>
 {
@@ -652,19 +695,1213 @@ pub fn test_xor() -> Result<(), MultiError>
 //
 
 #[test]
-pub fn bad_register() -> Result<(), MultiError>
+pub fn bad_register()
 {
 
     let source = include_str!("tests/bad_register.mips");
-    let program = compile(source)?;
+    let compilation = compile_with_diagnostics(source);
+
+    let errors:Vec<String> = compilation.errors().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec!["0:5-8: error: register r31 does not exist; this chip has r0 to r17"]);
 
     {
-        let ctx = CPUContext::new_simple(&program);
-        assert!( execute_until_yields(&program, ctx, 99).is_err(), "should have failed to execute");
+        let program = &compilation.program;
+        let mut ctx = CPUContext::new_simple(program);
+        assert!( execute_until_yields(program, &mut ctx, 99).is_err(), "should have failed to execute");
+    }
+
+    assert!( compile(source).is_err(), "should have failed to compile");
+}
+
+#[test]
+pub fn type_errors()
+{
+    let source = include_str!("tests/type_errors.mips");
+    let compilation = compile_with_diagnostics(source);
+
+    let diagnostics:Vec<String> = compilation.diagnostics.iter()
+        .filter(|d| d.code.is_none())
+        .map(|e| e.to_string()).collect();
+    assert_eq!(diagnostics, vec![
+        "3:8-14: error: 'sensor' is a device alias and can not be used as a value",
+        "4:8-13: error: 'limit' is a define and can not be used as a device",
+        "5:2-4: error: device d9 does not exist; this chip has d0 to d5",
+        "11:8-13: warning: 'thing' may be a device alias here, which can not be used as a value",
+        "12:5-10: warning: 'later' may be used before it is defined",
+        "13:5-10: error: 'limit' is a define and can not be used as a register to write",
+        "13:11-14: error: register r20 does not exist; this chip has r0 to r17",
+    ]);
+}
+
+#[test]
+pub fn type_check_profile()
+{
+    let profile = ChipProfile { registers: 8, device_pins: 2, ..ChipProfile::default() };
+    let compilation = compile_with_profile("move r7 1\nmove r8 1\nbdse d2 0\n", &profile);
+
+    let errors:Vec<String> = compilation.errors().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec![
+        "1:5-7: error: register r8 does not exist; this chip has r0 to r7",
+        "2:5-7: error: device d2 does not exist; this chip has d0 to d1",
+    ]);
+}
+
+//
+
+#[test]
+pub fn compile_errors_collected()
+{
+    let source = include_str!("tests/compile_errors.mips");
+    let compilation = compile_with_diagnostics(source);
+
+    let errors:Vec<&Diagnostic> = compilation.errors().collect();
+    assert_eq!(errors.len(), 3);
+
+    assert_eq!(errors[0].span, Span::new(1, 0, 4));
+    assert_eq!(errors[0].message, "unrecognized opcode frob");
+    assert_eq!(errors[1].span, Span::new(2, 0, 9));
+    assert_eq!(errors[1].message, "expected 3 arguments");
+    assert_eq!(errors[2].span, Span::new(4, 8, 10));
+    assert!(errors.iter().all(|e| e.severity == Severity::Error));
+
+    // the best-effort program runs until it reaches the first bad line
+    let program = &compilation.program;
+    let mut ctx = CPUContext::new_simple(program);
+    assert!(execute_until_yields(program, &mut ctx, 99).is_err(), "should have faulted on line 1");
+}
+
+#[test]
+pub fn compile_error_has_location()
+{
+    let source = include_str!("tests/compile_errors.mips");
+    match compile(source) {
+        Ok(_) => panic!("should have failed to compile"),
+        Err(e) => assert_eq!(e.message, "1:0-4: error: unrecognized opcode frob"),
     }
+}
+
+//
+
+#[test]
+pub fn test_label_value() -> Result<(), MultiError>
+{
+    let source = include_str!("tests/test_label_value.mips");
+    let program = compile(source)?;
+
+    let mut ctx = CPUContext::new_simple(&program);
+    execute_until_yields(&program, &mut ctx, 99)?;
+    assert_eq!(ctx.register_reference(Register{idx:0})?, 3.0);
+    assert_eq!(ctx.register_reference(Register{idx:1})?, 3.0);
+    assert_eq!(ctx.register_reference(Register{idx:2})?, 6.0);
+
+    Ok(())
+}
+
+#[test]
+pub fn bad_labels()
+{
+    let source = include_str!("tests/bad_labels.mips");
+    let compilation = compile_with_diagnostics(source);
+
+    let errors:Vec<String> = compilation.errors().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec![
+        "0:2-9: error: undefined label 'nowhere'",
+        "3:0-4: error: duplicate label 'loop'",
+        "5:10-19: error: undefined label 'elsewhere'",
+    ]);
+}
+
+//
+
+#[test]
+pub fn lints()
+{
+    let source = include_str!("tests/lint.mips");
+    let compilation = compile_with_diagnostics(source);
+    assert!(!compilation.has_errors());
+
+    let warnings:Vec<String> = compilation.warnings().map(|e| e.to_string()).collect();
+    assert_eq!(warnings, vec![
+        "1:6-12: warning[unused-alias]: alias 'unused' is never used",
+        "2:7-19: warning[unused-define]: define 'unusedDefine' is never used",
+        "4:7-9: warning[unused-define]: define 'r1' is never used",
+        "4:7-9: warning[alias-shadowing]: 'r1' shadows the register of the same name",
+        "7:5-7: warning[unread-register]: r2 is written but never read",
+        "8:12-13: warning[numeric-jump-target]: jump to line number 5 will break when lines are inserted or removed; use a label",
+        "9:0-7: warning[loop-without-yield]: the loop over lines 5 to 9 has no yield or sleep",
+        "10:0-9: warning[unreachable-code]: lines 10 to 13 can never be reached",
+        "10:5-7: warning[unread-register]: r3 is written but never read",
+    ]);
+}
 
+#[test]
+pub fn lint_fixes() -> Result<(), MultiError>
+{
+    let source = include_str!("tests/lint.mips");
+    let compilation = compile_with_diagnostics(source);
+
+    let fixed = apply_fixes(source, compilation.fixes());
+    assert_eq!(fixed, "alias sensor d0\n # spare pin\n\nalias value r0\n\nstart:\nl value sensor Temperature\nmove r2 value\nbeq value 0 start\nj start\n\n\nspin:\n\n");
+
+    // the fixed program still compiles, and only the warnings without fixes remain
+    let again = compile_with_diagnostics(&fixed);
+    assert!(again.fixes().next().is_none());
+    assert_eq!(again.warnings().filter_map(|d| d.code).collect::<Vec<_>>(), vec![UNREAD_REGISTER, LOOP_WITHOUT_YIELD]);
+    assert_eq!(compile(&fixed)?.labels(), compilation.program.labels());
 
     Ok(())
 }
 
-//assert!( compile(source).is_err(), "should have failed to compile");
+#[test]
+pub fn overlapping_fixes()
+{
+    // the dead code holds a numeric jump; removing the code wins, and the other fix is not half-applied
+    let source = "top:\nyield\nj top\nadd r0 r0 1\nj 0\nmove r1 r0\n";
+    let compilation = compile_with_diagnostics(source);
+    assert_eq!(compilation.fixes().count(), 2);
+    assert_eq!(apply_fixes(source, compilation.fixes()), "top:\nyield\nj top\n\n\n\n");
+
+    let whole = Fix::new("rename", vec![
+        TextEdit { span: Span::new(0, 0, 3), replacement: "one".to_string() },
+        TextEdit { span: Span::new(1, 0, 3), replacement: "two".to_string() },
+    ]);
+    let clash = Fix::new("clash", vec![
+        TextEdit { span: Span::new(1, 1, 2), replacement: "X".to_string() },
+        TextEdit { span: Span::new(2, 0, 3), replacement: "six".to_string() },
+    ]);
+    assert_eq!(apply_fixes("abc\ndef\nghi", &[whole.clone(), clash.clone()]), "one\ntwo\nghi");
+    assert_eq!(apply_fixes("abc\ndef\nghi", &[clash, whole]), "abc\ndXf\nsix");
+}
+
+#[test]
+pub fn colons_in_comments()
+{
+    // the colon in the comment does not make the jal a label, so the subroutine is reachable
+    let source = "start:\njal sub # call: the subroutine\nyield\nj start\nsub:\nmove r0 1\nj start\n";
+    let compilation = compile_with_diagnostics(source);
+    let warnings:Vec<String> = compilation.warnings().map(|e| e.to_string()).collect();
+    assert_eq!(warnings, vec!["5:5-7: warning[unread-register]: r0 is written but never read"]);
+    assert!(compilation.fixes().next().is_none());
+}
+
+#[test]
+pub fn lint_register_jumps()
+{
+    // the jump through r0 can go to any label, so only the line straight after it is dead
+    let compilation = compile_with_diagnostics("move r0 target\nj r0\nyield\ntarget:\ns d0 On 1\nyield\nj target");
+    let warnings:Vec<String> = compilation.warnings().map(|e| e.to_string()).collect();
+    assert_eq!(warnings, vec![
+        "2:0-5: warning[unreachable-code]: this line can never be reached",
+    ]);
+    // but where r0 points is only a guess, so the warning comes without a fix
+    assert!(compilation.fixes().next().is_none());
+
+    let compilation = compile_with_diagnostics("start:\njal sub\ns db Setting r0\nyield\nj start\nsub:\nmove r0 1\nj ra");
+    assert!(compilation.diagnostics.is_empty(), "{:?}", compilation.diagnostics);
+}
+
+#[test]
+pub fn sample_programs_lint_clean()
+{
+    for source in &[include_str!("../../samples1/src/prog1.mips"), include_str!("../../samples1/src/prog2.mips")] {
+        let compilation = compile_with_diagnostics(source);
+        assert!(compilation.diagnostics.is_empty(), "{:?}", compilation.diagnostics);
+    }
+}
+
+//
+
+#[test]
+pub fn source_limits()
+{
+    let long_comment = format!("yield # {}", "x".repeat(87));
+    let mut lines: Vec<&str> = vec!["yield"; 131];
+    lines[3] = &long_comment;
+    let source = lines.join("\n");
+
+    let compilation = compile_with_diagnostics(&source);
+    let errors:Vec<String> = compilation.errors().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec![
+        "3:90-95: error: line is 95 characters long; the limit is 90",
+        "128:0-5: error: program has 131 lines; the chip holds 128",
+    ]);
+
+    assert_eq!(compilation.size.lines, 131);
+    assert_eq!(compilation.size.line_headroom(), -3);
+    assert_eq!(compilation.size.longest_line, 3);
+    assert_eq!(compilation.size.line_length_headroom(), -5);
+    assert_eq!(compilation.size.bytes, source.len());
+    assert!(!compilation.size.fits());
+}
+
+#[test]
+pub fn source_limits_as_warnings()
+{
+    let profile = ChipProfile { max_bytes: 20, limit_severity: Severity::Warning, ..ChipProfile::default() };
+    let compilation = compile_with_profile("yield\nmove r0 1\nmove r1 r0\n", &profile);
+
+    assert!(!compilation.has_errors());
+    let warnings:Vec<String> = compilation.warnings().map(|e| e.to_string()).collect();
+    assert_eq!(warnings, vec![
+        "2:4-10: warning: program is longer than 20 bytes",
+        "2:5-7: warning[unread-register]: r1 is written but never read",
+    ]);
+    assert_eq!(compilation.size.to_string(),
+               "lines: 3/128 (125 left)\nlongest line: 10/90 characters on line 2 (80 left)\nbytes: 26/20 (-6 left)");
+}
+
+//
+
+#[test]
+pub fn logic_type_names()
+{
+    let compilation = compile_with_diagnostics("l r0 d0 Presure\ns d1 on r0\ns d1 Frobnication 1\nl r1 d0 RatioCarbonDioxide\n");
+
+    let errors:Vec<String> = compilation.errors().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec![
+        "0:8-15: error: unknown logic type 'Presure'; did you mean 'Pressure'?",
+        "1:5-7: error: unknown logic type 'on'; did you mean 'On'?",
+        "2:5-17: error: unknown logic type 'Frobnication'",
+    ]);
+
+    assert_eq!(suggest("Averge", BATCH_MODES), Some("Average"));
+    assert_eq!(suggest("Ocupied", SLOT_LOGIC_TYPES), Some("Occupied"));
+    assert!(is_slot_logic_type("OccupantHash"));
+    assert!(!is_logic_type("OccupantHash"));
+}
+
+#[test]
+pub fn get_device_field_suggests() -> Result<(), MultiError>
+{
+    let program = compile("yield\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, DeviceStateBuilder::new().set("Pressure", 101.0).build())?;
+
+    assert_eq!(ctx.get_device_field(0, "Pressure")?, 101.0);
+    match ctx.get_device_field(0, "Presure") {
+        Ok(_) => panic!("should not have found a value"),
+        Err(e) => assert_eq!(e.message(), "unknown logic type 'Presure'; did you mean 'Pressure'?"),
+    }
+    Ok(())
+}
+
+//
+
+#[test]
+pub fn device_kinds() -> Result<(), MultiError>
+{
+    let program = compile("l r0 d0 Pressure\nl r1 d1 Maximum\ns d1 Setting r0\nyield\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, (&GAS_SENSOR, DeviceStateBuilder::new().set("Pressure", 4.5).build()))?;
+    ctx.attach_device(1, &VOLUME_PUMP)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
+    assert_eq!(ctx.register_reference(Register{idx:0})?, 4.5);
+    assert_eq!(ctx.register_reference(Register{idx:1})?, 10.0);
+    assert_eq!(ctx.get_device_field(1, "Setting")?, 4.5);
+    assert_eq!(ctx.device_kind(Device::Regular(1)), DeviceKind::by_prefab("StructureVolumePump"));
+    Ok(())
+}
+
+#[test]
+pub fn device_kind_faults()
+{
+    let cases = [
+        ("s d0 Pressure 5\nyield\n", "StructureGasSensor on d0 can not write Pressure"),
+        ("l r0 d0 Setting\nyield\n", "StructureGasSensor on d0 can not read Setting"),
+    ];
+    for (source, message) in cases.iter() {
+        let program = compile(source).unwrap();
+        let mut ctx = CPUContext::new_simple(&program);
+        ctx.attach_device(0, &GAS_SENSOR).unwrap();
+        match execute_until_yields(&program, &mut ctx, 99) {
+            Ok(_) => panic!("{:?} should have faulted", source),
+            Err(e) => assert_eq!(e.message(), *message),
+        }
+    }
+}
+
+#[test]
+pub fn strictness_profiles() -> Result<(), MultiError>
+{
+    let program = compile("add r1 r0 1\nl r2 d0 Pressure\nyield\n")?;
+
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, &GAS_SENSOR)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
+    assert!(ctx.register_reference(Register{idx:1})?.is_nan());
+    assert_eq!(ctx.register_reference(Register{idx:2})?, 0.0);
+    // even leniently, a pin with nothing attached is a mistake in the test
+    let mut ctx = CPUContext::new_simple(&program);
+    assert_eq!(execute_until_yields(&program, &mut ctx, 99).unwrap_err().message(), "no device attached to d0");
+
+    let mut ctx = CPUContext::new_with_strictness(&program, Strictness::GameAccurate);
+    ctx.attach_device(0, &GAS_SENSOR)?;
+    execute_until_yields(&program, &mut ctx, 99)?;
+    assert_eq!(ctx.register_reference(Register{idx:1})?, 1.0);
+
+    let mut ctx = CPUContext::new_with_strictness(&program, Strictness::Pedantic);
+    ctx.attach_device(0, &GAS_SENSOR)?;
+    match execute_until_yields(&program, &mut ctx, 99) {
+        Ok(_) => panic!("r0 was never written"),
+        Err(e) => assert_eq!(e.message(), "r0 is read before anything was written to it"),
+    }
+
+    let program = compile("move r0 0\nadd r1 r0 1\nl r2 d0 Pressure\nyield\n")?;
+    let mut ctx = CPUContext::new_with_strictness(&program, Strictness::Pedantic);
+    ctx.attach_device(0, &GAS_SENSOR)?;
+    match execute_until_yields(&program, &mut ctx, 99) {
+        Ok(_) => panic!("the sensor's Pressure was never written"),
+        Err(e) => assert_eq!(e.message(), "d0[Pressure] is read before anything was written to it"),
+    }
+    Ok(())
+}
+
+#[test]
+pub fn uninitialized_reads() -> Result<(), MultiError>
+{
+    let program = compile(include_str!("tests/uninitialized.mips"))?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, DeviceStateBuilder::new().set("Temperature", 293.0).build())?;
+    execute_until_yields2(&program, &mut ctx, 1, |ctx| {
+        // the observer sees each read as soon as it happens
+        if ctx.uninitialized_reads().len() == 1 {
+            assert_eq!(ctx.uninitialized_reads()[0].location, Location::Register(Register{idx:1}));
+        }
+    })?;
+
+    let reads: Vec<String> = ctx.uninitialized_reads().iter().map(|r| r.to_string()).collect();
+    assert_eq!(reads, vec![
+        "line 3: 'total' (r1) is read before anything was written to it",
+        "line 4: 'sensor' (d0[Pressure]) is read before anything was written to it",
+    ]);
+    match ctx.assert_no_uninitialized_reads() {
+        Ok(_) => panic!("r1 was read before it was written"),
+        Err(e) => assert_eq!(e.message(), reads[0]),
+    }
+    Ok(())
+}
+
+//
+
+#[test]
+pub fn programs_are_data() -> Result<(), MultiError>
+{
+    let program = compile(include_str!("../../samples1/src/prog2.mips"))?;
+    assert_eq!(program.clone(), program);
+
+    // printing a program gives source that compiles back to the same program
+    let printed = program.to_string();
+    assert_eq!(compile(&printed)?, program);
+
+    let program = compile("start:\nadd r0 r0 2.5\nbgt r0 10 start\nbdseal d1 start\nyield\n")?;
+    let printed: Vec<String> = program.ops().iter().map(|op| op.to_string()).collect();
+    // the labels have been resolved to line numbers, but still print by name
+    assert_eq!(printed, vec!["", "add r0 r0 2.5", "bgt r0 10 start", "bdseal d1 start", "yield"]);
+    assert_eq!(program.ops()[1], Op::Binary(BinaryOperator::add("r0 r0 2.5".split_whitespace())?));
+    assert_eq!(program.ops()[2].opcode(), "bgt");
+
+    assert_eq!(serde_json::to_string(&program.ops()[2]).unwrap(),
+               r#"{"Branch":{"arg1":{"Register":{"idx":0}},"arg2":{"Number":10.0},"target":{"ResolvedLabel":["start",0]},"op":"Gt","style":"Abs"}}"#);
+    let extension = Op::Extension(Extension(Arc::new(Double)));
+    assert_eq!(serde_json::to_string(&extension).unwrap(), r#"{"Extension":null}"#);
+    Ok(())
+}
+
+struct Double;
+
+impl Instruction for Double
+{
+    fn execute(&self, ctx: &mut CPUContext) -> Result<(), ExecutionError> {
+        let reg = ctx.register_reference_mut(ctx.resolve_l_value(&LValue::parse("r0").unwrap())?)?;
+        *reg *= 2.0;
+        ctx.ip_plus_one();
+        Ok(())
+    }
+}
+
+#[test]
+pub fn extension_instructions() -> Result<(), MultiError>
+{
+    let compiled = compile("move r0 3\nyield\n")?;
+    let mut ops = compiled.ops().to_vec();
+    ops.insert(1, Op::Extension(Extension(Arc::new(Double))));
+    let program = CompiledProgram::new(ops, HashMap::new());
+
+    let mut ctx = CPUContext::new_simple(&program);
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_eq!(ctx.register_reference(Register{idx:0})?, 6.0);
+    Ok(())
+}
+
+#[test]
+pub fn state_survives_faults() -> Result<(), MultiError>
+{
+    let program = compile("move r0 5\nadd r1 r0 1\ns d0 Setting r1\nyield\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    match execute_until_yields(&program, &mut ctx, 1) {
+        Ok(_) => panic!("d0 is not attached"),
+        Err(e) => assert_eq!(e.message(), "no device attached to d0"),
+    }
+    assert_eq!(ctx.instruction_pointer(), 2);
+    assert_eq!(ctx.register_reference(Register{idx:1})?, 6.0);
+
+    // plug the device in and carry on from the faulting line
+    ctx.attach_device(0, DeviceState::new())?;
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_eq!(ctx.get_device_field(0, "Setting")?, 6.0);
+    Ok(())
+}
+
+//
+
+#[test]
+pub fn names_resolved_ahead_of_time() -> Result<(), MultiError>
+{
+    let program = compile(include_str!("tests/resolve_names.mips"))?;
+    match &program.ops()[3] {
+        Op::LoadDevice(op) => {
+            assert_eq!(op.l_value, LValue::ResolvedAlias("x".to_string(), Register{idx:1}));
+            assert_eq!(op.device, AliasOrDevice::ResolvedAlias("sensor".to_string(), Device::Regular(0)));
+        },
+        op => panic!("unexpected {:?}", op),
+    }
+    match &program.ops()[4] {
+        Op::Branch(op) => assert_eq!(op.arg2, RValue::ResolvedDefine("limit".to_string(), 10.0)),
+        op => panic!("unexpected {:?}", op),
+    }
+    // y is r2 on one path and r3 on the other, so it is still looked up at runtime
+    match &program.ops()[10] {
+        Op::Move(op) => assert_eq!(op.l_value, LValue::Alias("y".to_string())),
+        op => panic!("unexpected {:?}", op),
+    }
+    assert_eq!(program.ops()[10].to_string(), "move y limit");
+
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, DeviceStateBuilder::new().set("Pressure", 12.0).build())?;
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_eq!(ctx.register_reference(Register{idx:3})?, 10.0);
+    assert!(ctx.register_reference(Register{idx:2})?.is_nan());
+    Ok(())
+}
+
+#[test]
+pub fn long_runs() -> Result<(), MultiError>
+{
+    let program = compile("loop:\nadd r0 r0 1\nyield\nj loop\n")?;
+    let mut ctx = CPUContext::new_with_strictness(&program, Strictness::GameAccurate);
+    execute_until_yields(&program, &mut ctx, 10_000)?;
+    assert_eq!(ctx.register_reference(Register{idx:0})?, 10_000.0);
+
+    // a loop that never yields still only gets INSTRUCTIONS_PER_TICK instructions a tick
+    let program = compile("loop:\nadd r0 r0 1\nj loop\n")?;
+    let mut ctx = CPUContext::new_with_strictness(&program, Strictness::GameAccurate);
+    execute_until_yields(&program, &mut ctx, 3)?;
+    assert_eq!(ctx.instructions_executed(), 3 * INSTRUCTIONS_PER_TICK as u64);
+    Ok(())
+}
+
+#[test]
+pub fn instruction_limit_ends_ticks() -> Result<(), MultiError>
+{
+    // about 200 instructions of counting before each yield: the first tick is cut short, the second ends at the yield
+    let program = compile("start:\nmove r1 0\nloop:\nadd r1 r1 1\nslt r2 r1 50\nbgt r2 0 loop\nyield\nj start\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    let mut seen = Vec::new();
+    execute_until_yields2(&program, &mut ctx, 1, |ctx| seen.push(ctx.ticks()))?;
+    assert_eq!(ctx.ticks(), 1);
+    assert_eq!(seen.len(), INSTRUCTIONS_PER_TICK as usize);
+    assert!(ctx.register_reference(Register{idx:1})? < 50.0);
+
+    execute_until_yields2(&program, &mut ctx, 1, |_| {})?;
+    assert_eq!(ctx.ticks(), 2);
+    assert_eq!(ctx.register_reference(Register{idx:1})?, 50.0);
+    assert_eq!(ctx.instruction_pointer(), 7);
+    assert!(ctx.instructions_executed() < 2 * INSTRUCTIONS_PER_TICK as u64);
+    Ok(())
+}
+
+#[test]
+pub fn parallel_scenarios() -> Result<(), MultiError>
+{
+    fn shareable<T:Send+Sync>(_: &T) {}
+
+    let program = compile("l r0 d0 Pressure\nmul r1 r0 2\ns d1 Setting r1\nyield\n")?;
+    shareable(&program);
+    let run = |program:&CompiledProgram, pressure:f32| -> Result<f32, ExecutionError> {
+        let mut ctx = CPUContext::new_simple(program);
+        ctx.attach_device(0, DeviceStateBuilder::new().set("Pressure", pressure).build())?;
+        ctx.attach_device(1, DeviceState::new())?;
+        execute_until_yields(program, &mut ctx, 1)?;
+        Ok(ctx.device_reference(Device::Regular(1))?["Setting"])
+    };
+
+    let cases: Vec<f32> = (0..2000).map(|i| i as f32).collect();
+    let results = run_scenarios_on(&program, cases.clone(), 8, run).into_iter().collect::<Result<Vec<_>,_>>()?;
+    assert_eq!(results.len(), cases.len());
+    for (pressure, result) in cases.iter().zip(&results) {
+        assert_eq!(*result, pressure * 2.0);
+    }
+    let results2 = run_scenarios(&program, cases, run).into_iter().collect::<Result<Vec<_>,_>>()?;
+    assert_eq!(results2, results);
+    assert!(run_scenarios_on(&program, Vec::<f32>::new(), 4, run).is_empty());
+
+    // once a case panics, the other threads don't start any more
+    let started = AtomicUsize::new(0);
+    let panicked = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        run_scenarios_on(&program, (0..200).collect(), 2, |_, case:u32| {
+            started.fetch_add(1, Ordering::SeqCst);
+            assert!(case != 0, "case 0 fails");
+            if case == 1 {
+                // long enough for case 0's panic to be reported
+                std::thread::sleep(std::time::Duration::from_millis(500));
+            }
+        })
+    }));
+    assert!(panicked.is_err());
+    assert!(started.load(Ordering::SeqCst) < 20);
+    Ok(())
+}
+
+#[test]
+pub fn snapshots() -> Result<(), MultiError>
+{
+    let program = compile(include_str!("tests/snapshot.mips"))?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, (&GAS_SENSOR, DeviceStateBuilder::new().set("Pressure", 90.0).build()))?;
+    ctx.attach_device(1, &VOLUME_PUMP)?;
+    ctx.set_random_seed(7);
+    ctx.script_random(vec![0.5]);
+    ctx.device_reference(Device::SpecialB)?.insert("Setting".to_string(), 3.0);
+    ctx.stack_mut()[1] = 42.0;
+    execute_until_yields(&program, &mut ctx, 3)?;
+
+    let snapshot = ctx.snapshot();
+    assert_eq!(snapshot.to_json(), include_str!("tests/snapshot.json").trim_end());
+    assert_eq!(ContextSnapshot::from_json(&snapshot.to_json())?, snapshot);
+
+    // branch two runs from the same point
+    let mut high = ctx.clone();
+    high.device_reference(Device::Regular(0))?.insert("Pressure".to_string(), 150.0);
+    execute_until_yields(&program, &mut high, 2)?;
+    assert_eq!(high.device_reference(Device::Regular(1))?["On"], 1.0);
+
+    execute_until_yields(&program, &mut ctx, 2)?;
+    assert_eq!(ctx.device_reference(Device::Regular(1))?["On"], 0.0);
+
+    let mut restored = CPUContext::new_simple(&program);
+    restored.restore(&ContextSnapshot::from_json(include_str!("tests/snapshot.json"))?)?;
+    assert_eq!(restored.snapshot(), snapshot);
+    assert_eq!(restored.ticks(), 3);
+    execute_until_yields(&program, &mut restored, 2)?;
+    assert_eq!(restored.snapshot(), ctx.snapshot());
+    assert_eq!(restored.next_random(), ctx.next_random());
+
+    let profile = ChipProfile { registers: 8, ..ChipProfile::default() };
+    let mut small = CPUContext::new(HashMap::new(), HashMap::new(), vec![None; 6], vec![0.0; profile.registers as usize]);
+    let err = small.restore(&snapshot).unwrap_err();
+    assert_eq!(err.message(), "snapshot is of a chip with 18 registers and 6 pins, not 8 and 6");
+    Ok(())
+}
+
+#[test]
+pub fn alias_accessors() -> Result<(), MultiError>
+{
+    let program = compile("alias sensor d0\nalias level r3\nalias flag r4\ndefine limit 5\nl level sensor Setting\nyield\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, DeviceState::new())?;
+    execute_until_yields(&program, &mut ctx, 1)?;
+    ctx.device_by_alias("sensor")?.insert("Setting".to_string(), 7.0);
+    ctx.jump(4);
+    execute_until_yields(&program, &mut ctx, 1)?;
+
+    assert_eq!(ctx.alias_value("level")?, 7.0);
+    assert_eq!(ctx.alias_value("limit")?, 5.0);
+    ctx.set_alias_value("flag", 1.0)?;
+    assert_eq!(ctx.register_reference(Register::new(4))?, 1.0);
+    assert_eq!(ctx.aliases()["sensor"], RegisterOrDevice::Device(Device::new(0)));
+    assert_eq!(ctx.defines()["limit"], 5.0);
+    assert_eq!(Register::parse("r3")?.idx(), 3);
+
+    assert_eq!(ctx.alias_value("sensor").unwrap_err().message(), "sensor is a device (d0) when I need a register");
+    assert_eq!(ctx.device_by_alias("level").unwrap_err().message(), "level is a register (r3) when I need a device");
+    assert_eq!(ctx.alias_value("nothing").unwrap_err().message(), "unable to evaluate nothing");
+    assert_eq!(ctx.set_alias_value("limit", 1.0).unwrap_err().message(), "not a valid LValue: limit");
+    Ok(())
+}
+
+#[test]
+pub fn dump_and_diff() -> Result<(), MultiError>
+{
+    let program = compile(include_str!("tests/snapshot.mips"))?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, DeviceStateBuilder::new().set("Pressure", 90.0).build())?;
+    ctx.attach_device(1, &WALL_LIGHT)?;
+    ctx.script_random(vec![0.25, 0.75]);
+    ctx.stack_mut()[2] = 8.0;
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_eq!(ctx.dump(), "\
+ip 11, tick 1, 11 instructions run, Lenient
+registers:
+  r0 = 90
+  r1 = 0
+  r2 = 0.25
+  r5 total = NaN
+defines:
+  limit = 100
+d0 sensor:
+  Pressure = 90
+d1 pump (StructureWallLight):
+  Lock = 0
+  On = 0
+  Power = 0
+  PrefabHash = 0
+  ReferenceId = 0
+  RequiredPower = 0
+stack (the rest is 0):
+  [0] = 0
+  [1] = 0
+  [2] = 8
+random: seed 0, 0 drawn, 1 scripted");
+
+    let before = ctx.snapshot();
+    ctx.device_by_alias("sensor")?.insert("Pressure".to_string(), 150.0);
+    execute_until_yields(&program, &mut ctx, 1)?;
+    let diff = before.diff(&ctx.snapshot());
+    assert_eq!(diff.to_string(), "\
+r0: 90 -> 150
+r1: 0 -> 1
+r2: 0.25 -> 0.75
+d0 sensor Pressure: 90 -> 150
+d1 pump On: 0 -> 1");
+    assert!(ctx.snapshot().diff(&ctx.snapshot()).is_empty());
+    ctx.expect_values(&ctx.snapshot())?;
+    let mut elsewhere = ctx.snapshot();
+    elsewhere.instruction_pointer += 1;
+    elsewhere.clock.ticks += 5;
+    ctx.expect_values(&elsewhere)?;
+    assert_eq!(ctx.expect_values(&before).unwrap_err().message().lines().count(), 6);
+    Ok(())
+}
+
+#[test]
+pub fn scenarios() -> Result<(), ScenarioFailure>
+{
+    let src = "alias sensor d0\nalias pump d1\nalias on r1\nloop:\nl r0 sensor Pressure\nsgt on r0 100\ns pump On on\nyield\nj loop\n";
+    let ctx = Scenario::compile(src)?
+        .device("sensor", &GAS_SENSOR)
+        .device("pump", &VOLUME_PUMP)
+        .set("sensor", "Pressure", 90.0)
+        .tick()
+        .expect("pump", "On", 0.0)
+        .set("sensor", "Pressure", 150.0)
+        .ticks(2)
+        .expect("pump", "On", 1.0)
+        .expect_value("on", 1.0)
+        .run()?;
+    assert_eq!(ctx.ticks(), 3);
+
+    let failure = Scenario::compile(src)?
+        .device("sensor", &GAS_SENSOR)
+        .device("pump", &VOLUME_PUMP)
+        .set("sensor", "Pressure", 150.0)
+        .tick()
+        .expect("pump", "On", 0.0)
+        .run().unwrap_err();
+    assert_eq!(failure.to_string(), "\
+after tick 1: expected pump On = 0, found 1
+recent instructions:
+  tick 1 line 0: alias sensor d0
+  tick 1 line 1: alias pump d1
+  tick 1 line 2: alias on r1
+  tick 1 line 4: l r0 sensor Pressure
+  tick 1 line 5: sgt on r0 100
+  tick 1 line 6: s pump On on
+  tick 1 line 7: yield");
+
+    let failure = Scenario::compile(src)?.device("pump", &GAS_SENSOR).tick().run().unwrap_err();
+    assert_eq!(failure.message, "line 4 faulted: no device attached to d0");
+    let failure = Scenario::compile(src)?.set("heater", "On", 1.0).run().unwrap_err();
+    assert_eq!(failure.message, "the program has no alias heater for a device");
+    Ok(())
+}
+
+#[test]
+pub fn tolerant_assertions() -> Result<(), MultiError>
+{
+    let program = compile("alias pump d1\nalias want r4\nmove want 1\ns pump On want\ns pump Setting 0.30000001\nmove r3 0.1\nadd r3 r3 0.2\nyield\nmove r5 1\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(1, DeviceState::new())?;
+    execute_until_yields(&program, &mut ctx, 1)?;
+
+    assert_register!(ctx, Register::new(3), 0.3, 1e-6);
+    assert_register!(ctx, Register::new(5), f32::NAN);
+    assert_alias!(ctx, "want", 1.0);
+    assert_device!(ctx, "pump", "Setting", 0.3, Tolerance::relative(0.01));
+    assert_device!(ctx, "d1", "Setting", 0.3);
+    assert_on!(ctx, "pump");
+    assert!(!Tolerance::EXACT.accepts(0.0, f32::NAN));
+    assert!(Tolerance::absolute(0.5).accepts(f32::INFINITY, f32::INFINITY));
+
+    let err = ctx.check_device_field("pump", "Setting", 0.25, 0.01).unwrap_err();
+    assert_eq!(err.message(), "\
+pump (d1) Setting is 0.3, expected within 0.01 0.25
+at line 8: move r5 1
+recent device writes:
+  line 3: d1 On = 1
+  line 4: d1 Setting = 0.3");
+    assert_eq!(ctx.check_device_flag("pump", "On", false).unwrap_err().message().lines().next(),
+               Some("pump (d1) On is 1, expected 0"));
+    assert_eq!(ctx.check_alias("want", 2.0, Tolerance::EXACT).unwrap_err().message().lines().next(),
+               Some("want (r4) is 1, expected exactly 2"));
+    assert_eq!(ctx.check_device_field("pump", "Mode", 0.0, 0.0).unwrap_err().message().lines().next(),
+               Some("pump (d1) has no Mode"));
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "pump (d1) On is 1, expected 0")]
+pub fn assertion_macros_panic()
+{
+    let program = compile("alias pump d1\ns pump On 1\nyield\n").unwrap();
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(1, DeviceState::new()).unwrap();
+    execute_until_yields(&program, &mut ctx, 1).unwrap();
+    assert_off!(ctx, "pump");
+}
+
+#[test]
+pub fn subroutines_in_isolation() -> Result<(), MultiError>
+{
+    let program = compile(include_str!("tests/subroutines.mips"))?;
+
+    let ctx = Subroutine::new(&program, "clamp").value("input", 150.0).call()?;
+    assert_alias!(ctx, "output", 100.0);
+    let ctx = Subroutine::new(&program, "clamp").value("input", 42.0).call()?;
+    assert_alias!(ctx, "output", 42.0);
+    assert_eq!(ctx.instructions_executed(), 5);
+
+    let ctx = Subroutine::new(&program, "clamp").register(Register::new(0), 150.0).until_label("clampDone").call()?;
+    assert_eq!(ctx.instruction_pointer(), program.labels()["clampDone"]);
+    assert_alias!(ctx, "output", 100.0);
+
+    let ctx = Subroutine::new(&program, "main")
+        .device("sensor", DeviceState::new())
+        .set("sensor", "Pressure", 120.0)
+        .until_label("clamp")
+        .call()?;
+    assert_alias!(ctx, "input", 120.0);
+    assert_eq!(ctx.get_ra(), 8.0);
+
+    // the whole program still calls it the usual way
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, DeviceStateBuilder::new().set("Pressure", 120.0).build())?;
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_device!(ctx, "db", "Setting", 100.0);
+    assert_eq!(program.ops()[7].to_string(), "jal clamp");
+    assert_eq!(program.ops()[18].to_string(), "j ra");
+
+    // on a chip with fewer registers, jal and j ra agree that ra is the last one
+    let mut small = CPUContext::new(program.labels(), HashMap::new(), vec![None; 6], vec![0.0; 8]);
+    small.attach_device(0, DeviceStateBuilder::new().set("Pressure", 120.0).build())?;
+    execute_until_yields(&program, &mut small, 1)?;
+    assert_eq!(small.register_reference(Register::new(7))?, 8.0);
+    assert_device!(small, "db", "Setting", 100.0);
+
+    let err = Subroutine::new(&program, "spin").call().unwrap_err();
+    assert_eq!(err.message(), "spin did not return after 100000 instructions");
+    let err = Subroutine::new(&program, "nowhere").call().unwrap_err();
+    assert_eq!(err.message(), "no label 'nowhere'");
+    Ok(())
+}
+
+#[test]
+pub fn input_sources() -> Result<(), MultiError>
+{
+    let program = compile("l r0 d0 Pressure\nl r1 d0 Pressure\nl r2 d1 Temperature\nl r3 d2 Setting\nyield\nj 0\n")?;
+    let mut ctx = CPUContext::new_simple(&program);
+    for pin in 0..3 {
+        ctx.attach_device(pin, DeviceState::new())?;
+    }
+    ctx.attach_input(Device::new(0), "Pressure", InputSource::sequence(vec![100.0, 101.0, 102.0]));
+    ctx.attach_input(Device::new(1), "Temperature", InputSource::over_time(|seconds| 273.0 + seconds));
+    // a sensor that follows what the program last loaded from the first one
+    ctx.attach_input(Device::new(2), "Setting", InputSource::from_state(|ctx| ctx.register_reference(Register::new(1)).unwrap() * 2.0));
+
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_register!(ctx, Register::new(0), 100.0);
+    assert_register!(ctx, Register::new(1), 101.0);
+    assert_register!(ctx, Register::new(2), 273.0);
+    assert_register!(ctx, Register::new(3), 202.0);
+    assert_device!(ctx, "d0", "Pressure", 101.0);
+
+    let mut branch = ctx.clone();
+    execute_until_yields(&program, &mut ctx, 2)?;
+    assert_register!(ctx, Register::new(0), 102.0);
+    assert_register!(ctx, Register::new(1), 102.0);
+    assert_register!(ctx, Register::new(2), 274.0);
+
+    // the clone carries on from the same place in the sequence
+    branch.detach_input(Device::new(1), "Temperature");
+    execute_until_yields(&program, &mut branch, 1)?;
+    assert_register!(branch, Register::new(0), 102.0);
+    assert_register!(branch, Register::new(2), 273.0);
+    Ok(())
+}
+
+#[test]
+pub fn scenario_inputs() -> Result<(), ScenarioFailure>
+{
+    let src = "alias sensor d0\nalias light d1\nloop:\nl r0 sensor SolarIrradiance\nsgt r1 r0 0\ns light On r1\nyield\nj loop\n";
+    Scenario::compile(src)?
+        .device("sensor", &DAYLIGHT_SENSOR)
+        .device("light", &WALL_LIGHT)
+        .input("sensor", "SolarIrradiance", InputSource::per_tick(|tick| if tick % 4 < 2 { 0.0 } else { 500.0 }))
+        .ticks(2)
+        .expect("light", "On", 0.0)
+        .tick()
+        .expect("light", "On", 1.0)
+        .ticks(3)
+        .expect("light", "On", 0.0)
+        .run()?;
+    Ok(())
+}
+
+/// a pump that runs while the sensor reads below 500, wired up with the sensor at 100
+fn pressure_pump(source:&str) -> (CompiledProgram, CPUContext)
+{
+    let program = compile(source).unwrap();
+    let mut ctx = CPUContext::new_simple(&program);
+    ctx.attach_device(0, (&GAS_SENSOR, DeviceStateBuilder::new().set("Pressure", 100.0).build())).unwrap();
+    ctx.attach_device(1, &VOLUME_PUMP).unwrap();
+    (program, ctx)
+}
+
+#[test]
+pub fn snapshots_of_slots_and_faults() -> Result<(), MultiError>
+{
+    let (program, mut ctx) = pressure_pump(include_str!("tests/pressure_pump.mips"));
+    execute_until_yields(&program, &mut ctx, 1)?;
+    let clean = ctx.snapshot();
+    ctx.slot_reference(Device::Regular(0), 0)?.insert("Occupied".to_string(), 1.0);
+    ctx.stick_field(Device::Regular(0), "Pressure", 5.0);
+    ctx.drop_writes(Device::Regular(1), true);
+    ctx.attach_device(2, DeviceState::new())?;
+    ctx.detach_device(2)?;
+
+    let faulty = ctx.snapshot();
+    assert_eq!(ContextSnapshot::from_json(&faulty.to_json())?, faulty);
+    let dump = faulty.to_string();
+    assert!(dump.contains("\n  slot 0: Occupied = 1\n"), "{}", dump);
+    assert!(dump.contains("\nd2: not attached\n  to plug back in: a device with 0 fields\n"), "{}", dump);
+    assert!(dump.contains("\nfaults:\n  d1 pump ignores writes\n  d0[Pressure] stuck at 5\n"), "{}", dump);
+
+    // restoring puts the slots and faults back, and takes them away
+    let (_, mut other) = pressure_pump(include_str!("tests/pressure_pump.mips"));
+    other.restore(&faulty)?;
+    assert_eq!(other.snapshot(), faulty);
+    assert_eq!(other.slot_field("sensor", 0, "Occupied")?, 1.0);
+    other.reattach_device(2)?;
+    ctx.restore(&clean)?;
+    assert_eq!(ctx.snapshot(), clean);
+    assert!(ctx.slot_field("sensor", 0, "Occupied").is_err());
+    assert!(ctx.reattach_device(2).is_err());
+    execute_until_yields(&program, &mut ctx, 1)?;
+    assert_on!(ctx, "pump");
+    Ok(())
+}
+
+#[test]
+pub fn timelines()
+{
+    let src = include_str!("tests/pressure_pump.mips");
+    let leak = Timeline::new()
+        .detach(6, "pump")
+        .set(3, "sensor", "Pressure", 200.0);
+    assert_eq!(leak.events().iter().map(|e| e.to_string()).collect::<Vec<_>>(),
+               vec!["tick 3: sensor Pressure = 200", "tick 6: unplug pump"]);
+
+    // one timeline, reused
+    let scenario = || Scenario::compile(src).unwrap()
+        .device("sensor", &GAS_SENSOR)
+        .device("pump", &VOLUME_PUMP)
+        .set("sensor", "Pressure", 1000.0)
+        .timeline(&leak);
+    scenario()
+        .ticks(3)
+        .expect("pump", "On", 0.0)
+        .tick()
+        .expect("pump", "On", 1.0)
+        .ticks(2)
+        .run().unwrap();
+    let failure = scenario().ticks(10).run().unwrap_err();
+    assert_eq!(failure.tick, 6);
+    assert_eq!(failure.events, vec!["tick 3: sensor Pressure = 200", "tick 6: unplug pump"]);
+    assert!(failure.message.starts_with("line 6 faulted"), "{}", failure.message);
+    assert!(failure.to_string().contains("timeline so far:\n  tick 3: sensor Pressure = 200\n  tick 6: unplug pump\n"),
+            "{}", failure);
+
+    // plugging it back in, straight on the run loop
+    let (program, mut ctx) = pressure_pump(src);
+    let swap = Timeline::new()
+        .detach(2, "d1")
+        .attach(2, "d1", &VOLUME_PUMP)
+        .merge(&Timeline::new().set(4, "sensor", "Pressure", 600.0));
+    swap.execute(&program, &mut ctx, 4).unwrap();
+    assert_on!(ctx, "pump");
+    swap.execute(&program, &mut ctx, 1).unwrap();
+    assert_off!(ctx, "pump");
+
+    // a device the program has no name for is a mistake in the scenario
+    let failure = scenario().timeline(&Timeline::new().detach(1, "filter")).tick().run().unwrap_err();
+    assert_eq!(failure.message, "tick 1: unplug filter: the program has no alias filter for a device");
+
+    // slots stay with the device while it is unplugged, and a new device starts with them empty
+    let slots = Timeline::new()
+        .slot(1, "sensor", 0, "Occupied", 1.0)
+        .detach(2, "sensor")
+        .reattach(2, "sensor")
+        .slot(3, "sensor", 1, "Quantity", 4.0);
+    assert_eq!(slots.events()[0].to_string(), "tick 1: sensor slot 0 Occupied = 1");
+    let (program, mut ctx) = pressure_pump(src);
+    slots.execute(&program, &mut ctx, 3).unwrap();
+    assert_eq!(ctx.slot_field("sensor", 0, "Occupied").unwrap(), 1.0);
+    slots.execute(&program, &mut ctx, 1).unwrap();
+    assert_eq!(ctx.slot_field("d0", 1, "Quantity").unwrap(), 4.0);
+    ctx.attach_device(0, &GAS_SENSOR).unwrap();
+    let err = ctx.slot_field("sensor", 0, "Occupied").unwrap_err().to_string();
+    assert!(err.starts_with("sensor (d0) slot 0 has no Occupied\n"), "{}", err);
+    let mut ctx = CPUContext::new_simple(&program);
+    let err = Timeline::new().slot(0, "pump", 0, "Occupied", 1.0).execute(&program, &mut ctx, 1).unwrap_err();
+    assert_eq!(err.to_string(), "tick 0: pump slot 0 Occupied = 1 failed: no device attached to d1");
+}
+
+#[test]
+pub fn fault_timeline_events()
+{
+    let (program, mut ctx) = pressure_pump(include_str!("tests/pressure_pump.mips"));
+    let faults = Timeline::new()
+        .drop_writes(0, "pump")
+        .keep_writes(2, "pump")
+        .freeze(3, "sensor", "Pressure")
+        .set(3, "sensor", "Pressure", 900.0)
+        .release(5, "sensor", "Pressure")
+        .detach(6, "pump")
+        .reattach(7, "pump");
+
+    faults.execute(&program, &mut ctx, 2).unwrap();
+    assert_off!(ctx, "pump");
+    faults.execute(&program, &mut ctx, 3).unwrap();
+    assert_on!(ctx, "pump");
+    faults.execute(&program, &mut ctx, 1).unwrap();
+    assert_off!(ctx, "pump");
+    let e = faults.execute(&program, &mut ctx, 1).unwrap_err();
+    assert_eq!(e.to_string(), "no device attached to d1");
+    // the pump comes back as it was, and the chip carries on from the faulting line
+    ctx.reattach_device(1).unwrap();
+    assert_eq!(ctx.reattach_device(1).unwrap_err().to_string(), "nothing has been unplugged from d1");
+    execute_until_yields(&program, &mut ctx, 1).unwrap();
+    assert_off!(ctx, "pump");
+}
+
+#[test]
+pub fn fault_injection()
+{
+    let fragile = include_str!("tests/pressure_pump.mips");
+    let careful = include_str!("tests/pressure_pump_careful.mips");
+    let unplugging = FaultInjector::new(11).rate(0.1).kinds(&[FaultKind::Unplug]).device("sensor").device("pump");
+
+    // the same seed breaks things the same way
+    let plan: Vec<String> = unplugging.plan(200).events().iter().map(|e| e.to_string()).collect();
+    assert_eq!(plan, unplugging.plan(200).events().iter().map(|e| e.to_string()).collect::<Vec<_>>());
+    assert!(!plan.is_empty());
+    assert_eq!(plan.iter().filter(|e| e.contains("unplug")).count(), plan.iter().filter(|e| e.contains("back in")).count());
+
+    let (program, mut ctx) = pressure_pump(fragile);
+    let report = unplugging.run(&program, &mut ctx, 200);
+    let failure = report.failure.as_ref().expect("the fragile program should fault");
+    assert!(failure.message.contains("faulted: no device attached to"), "{}", report);
+    let cause = failure.cause.as_ref().unwrap();
+    assert!(matches!(cause.event, TimelineEvent::Detach { .. }), "{}", report);
+    assert!(report.to_string().contains(&format!("\nlikely cause: {}\n", cause)), "{}", report);
+
+    let (program, mut ctx) = pressure_pump(careful);
+    let report = unplugging.run(&program, &mut ctx, 200);
+    assert!(report.survived(), "{}", report);
+    assert_eq!(report.ticks, 200);
+    assert!(!report.injected.is_empty());
+
+    // on a context that has already run, the faults start from where it is, each with its end
+    let (program, mut ctx) = pressure_pump(careful);
+    execute_until_yields(&program, &mut ctx, 1).unwrap();
+    let report = FaultInjector::new(1).rate(1.0).kinds(&[FaultKind::Unplug]).device("pump").run(&program, &mut ctx, 20);
+    assert!(report.survived(), "{}", report);
+    assert_eq!(report.ticks, 21);
+    let injected: Vec<String> = report.injected.iter().map(|e| e.to_string()).collect();
+    assert_eq!(injected[0], "tick 1: unplug pump");
+    for (idx, event) in injected.iter().enumerate() {
+        assert!(event.contains(if idx % 2 == 0 { "unplug" } else { "back in" }), "{}", report);
+    }
+
+    // faults still going when the run stops are ended, so the program works again afterwards
+    let (program, mut ctx) = pressure_pump(careful);
+    let report = FaultInjector::new(2).rate(1.0).longest(50)
+        .kinds(&[FaultKind::Unplug, FaultKind::Freeze, FaultKind::DropWrites])
+        .device("pump")
+        .field("sensor", "Pressure")
+        .run(&program, &mut ctx, 3);
+    assert!(report.survived(), "{}", report);
+    assert!(report.injected.iter().any(|e| e.tick == 3), "{}", report);
+    ctx.device_by_alias("sensor").unwrap().insert("Pressure".to_string(), 900.0);
+    execute_until_yields(&program, &mut ctx, 1).unwrap();
+    assert_off!(ctx, "pump");
+    ctx.device_by_alias("sensor").unwrap().insert("Pressure".to_string(), 100.0);
+    execute_until_yields(&program, &mut ctx, 1).unwrap();
+    assert_on!(ctx, "pump");
+
+    // a fault that can't be injected is the cause of the failure
+    let (program, mut ctx) = pressure_pump(careful);
+    let report = FaultInjector::new(1).rate(1.0).kinds(&[FaultKind::Unplug]).device("filter").run(&program, &mut ctx, 5);
+    let failure = report.failure.as_ref().unwrap();
+    assert_eq!(failure.message, "tick 0: unplug filter failed: the program has no alias filter for a device");
+    assert_eq!(failure.cause.as_ref().unwrap().to_string(), "tick 0: unplug filter");
+    assert_eq!(report.injected.last().unwrap().to_string(), "tick 0: unplug filter");
+
+    // garbage from the sensor gets past a program that doesn't check for it
+    let (program, mut ctx) = pressure_pump(careful);
+    let report = FaultInjector::new(3)
+        .rate(0.2)
+        .kinds(&[FaultKind::Garbage])
+        .field("sensor", "Pressure")
+        .invariant("the pump only runs on a sane reading", |ctx| {
+            ctx.check_device_flag("pump", "On", false).is_ok()
+                || ctx.alias_value("pressure").is_ok_and(|p| (0.0..1.0e6).contains(&p))
+        })
+        .run(&program, &mut ctx, 100);
+    let failure = report.failure.as_ref().expect("garbage should get through");
+    assert_eq!(failure.message, "invariant broken: the pump only runs on a sane reading");
+    assert!(matches!(failure.cause.as_ref().unwrap().event, TimelineEvent::Stuck { value: Some(_), .. }), "{}", report);
+}
+
+#[test]
+pub fn properties()
+{
+    let src = "alias sensor d0\nalias pump d1\nl r0 sensor Pressure\nsgt r1 r0 108\ns pump On r1\nyield\n";
+    let property = || Property::compile(src).unwrap()
+        .device("sensor", &GAS_SENSOR)
+        .device("pump", &VOLUME_PUMP)
+        .input("sensor", "Pressure", 0.0..=200.0)
+        .input("sensor", "Temperature", 250.0..=350.0)
+        .seed(5);
+    let pumping_when_high = |limit:f32| move |ctx:&CPUContext| {
+        let pressure = ctx.device_field("sensor", "Pressure").unwrap();
+        (pressure > limit) == (ctx.device_field("pump", "On").unwrap() != 0.0)
+    };
+
+    property().invariant("pump on above 108", pumping_when_high(108.0)).cases(300).run().unwrap();
+
+    let failure = property().invariant("pump on above 120", pumping_when_high(120.0)).cases(300).run().unwrap_err();
+    assert_eq!(failure.message, "after tick 1: invariant broken: pump on above 120");
+    assert!(failure.case.is_some());
+    let pressure = failure.inputs[0].1;
+    assert_eq!(failure.inputs[0].0, "sensor Pressure");
+    assert!(pressure > 108.0 && pressure <= 109.0, "{}", failure);
+    assert_eq!(failure.inputs[1], ("sensor Temperature".to_string(), 250.0));
+    assert!(failure.to_string().ends_with(&format!("\nreplay with .replay({})", failure.case_seed)), "{}", failure);
+
+    let replayed = property().invariant("pump on above 120", pumping_when_high(120.0)).replay(failure.case_seed).unwrap_err();
+    assert_eq!((replayed.case, &replayed.inputs, &replayed.found), (None, &failure.inputs, &failure.found));
+
+    let failure = property().input("pump", "Setting", 1.0..=0.0).run().unwrap_err();
+    assert_eq!(failure.to_string(), "pump Setting has an empty range 1..=0");
+    let failure = property().input("pump", "Setting", 0.0..=f32::INFINITY).run().unwrap_err();
+    assert_eq!(failure.to_string(), "pump Setting needs a finite range, not 0..=inf");
+
+    // both ends of a range get drawn
+    let top = 1.0 + f32::EPSILON;
+    let failure = property().input("pump", "Setting", 1.0..=top)
+        .invariant("setting below the top of its range", |ctx| ctx.device_field("pump", "Setting").unwrap() < 1.0 + f32::EPSILON)
+        .run().unwrap_err();
+    assert_eq!(failure.inputs[2], ("pump Setting".to_string(), top));
+}
+
+#[test]
+pub fn monitors()
+{
+    let src = "alias pump d0\nalias vent d1\nalias sensor d2\ndefine target 100\nloop:\nl r0 sensor Pressure\nslt r1 r0 target\ns pump On r1\nsgt r2 r0 120\ns vent Open r2\nyield\nj loop\n";
+    let program = compile(src).unwrap();
+    let pressure = |ctx:&CPUContext| ctx.device_field("sensor", "Pressure").unwrap();
+    let on = |ctx:&CPUContext, device, field| ctx.device_field(device, field).is_ok_and(|v| v != 0.0);
+    let requirements = Monitor::new()
+        .always("pump off while the vent is open", move |ctx| !(on(ctx, "pump", "On") && on(ctx, "vent", "Open")))
+        .until("pump on until the pressure reaches target", move |ctx| on(ctx, "pump", "On"), move |ctx| pressure(ctx) >= 100.0)
+        .watch("sensor Pressure")
+        .watch("pump On")
+        .watch("r2");
+
+    // a run loop of our own, where the pump adds 15 kPa a tick
+    let run = |monitor:&mut Monitor, ticks:u32| -> Result<CPUContext, MonitorViolation> {
+        let mut ctx = CPUContext::new_simple(&program);
+        for pin in 0..3 {
+            ctx.attach_device(pin, DeviceStateBuilder::new().set("Pressure", 0.0).build()).unwrap();
+        }
+        for _ in 0..ticks {
+            execute_until_yields(&program, &mut ctx, 1).unwrap();
+            let pumped = if on(&ctx, "pump", "On") { 15.0 } else { 0.0 };
+            *ctx.device_by_alias("sensor").unwrap().get_mut("Pressure").unwrap() += pumped;
+            monitor.observe(&ctx)?;
+        }
+        monitor.finish(&ctx)?;
+        Ok(ctx)
+    };
+
+    let ctx = run(&mut requirements.clone().eventually("pressure reaches target", 10, move |ctx| pressure(ctx) >= 100.0), 20).unwrap();
+    assert_device!(ctx, "sensor", "Pressure", 105.0);
+
+    let mut monitor = requirements.clone().eventually("pressure reaches target", 5, move |ctx| pressure(ctx) >= 100.0);
+    let violation = run(&mut monitor, 20).unwrap_err();
+    assert_eq!(violation.tick, 5);
+    assert_eq!(violation.to_string(), "tick 5: pressure reaches target: had not held by tick 5\n\
+        recent ticks:\n  \
+        tick 1 line 11: sensor Pressure = 15, pump On = 1, r2 = 0\n  \
+        tick 2 line 11: sensor Pressure = 30, pump On = 1, r2 = 0\n  \
+        tick 3 line 11: sensor Pressure = 45, pump On = 1, r2 = 0\n  \
+        tick 4 line 11: sensor Pressure = 60, pump On = 1, r2 = 0\n  \
+        tick 5 line 11: sensor Pressure = 75, pump On = 1, r2 = 0\n\
+        recent device writes:\n  \
+        line 7: d0 On = 1\n  line 9: d1 Open = 0\n  line 7: d0 On = 1\n  line 9: d1 Open = 0\n  \
+        line 7: d0 On = 1\n  line 9: d1 Open = 0\n  line 7: d0 On = 1\n  line 9: d1 Open = 0");
+    assert_eq!(monitor.violation(), Some(&violation));
+
+    // a run that stops before the deadline hasn't shown the pressure gets there
+    let violation = run(&mut requirements.clone().eventually("pressure reaches target", 40, move |ctx| pressure(ctx) >= 100.0), 3).unwrap_err();
+    assert_eq!(violation.message, "had not held when the run ended, before its deadline of tick 40");
+
+    // observing only every few ticks, the property holding after the deadline is still too late
+    let mut ctx = CPUContext::new_simple(&program);
+    for pin in 0..3 {
+        ctx.attach_device(pin, DeviceStateBuilder::new().set("Pressure", 0.0).build()).unwrap();
+    }
+    let mut monitor = Monitor::new().eventually("pressure reaches target", 40, move |ctx| pressure(ctx) >= 100.0);
+    execute_until_yields(&program, &mut ctx, 45).unwrap();
+    *ctx.device_by_alias("sensor").unwrap().get_mut("Pressure").unwrap() = 150.0;
+    let violation = monitor.observe(&ctx).unwrap_err();
+    assert_eq!((violation.tick, violation.message.as_str()), (45, "had not held by tick 40"));
+
+    // in a scenario, with the pressure set by hand, and with the vent opening too soon
+    let scenario = |src:&str| Scenario::compile(src).unwrap()
+        .device("pump", DeviceState::new())
+        .device("vent", DeviceState::new())
+        .device("sensor", DeviceState::new())
+        .monitor(&requirements);
+    let early_vent = src.replace("sgt r2 r0 120", "sgt r2 r0 80");
+    for src in &[src, early_vent.as_str()] {
+        scenario(src).set("sensor", "Pressure", 50.0).ticks(2).set("sensor", "Pressure", 130.0).ticks(2).run().unwrap();
+    }
+    scenario(src).set("sensor", "Pressure", 90.0).ticks(2).run().unwrap();
+    let failure = scenario(&early_vent).set("sensor", "Pressure", 50.0).ticks(2).set("sensor", "Pressure", 90.0).tick().run().unwrap_err();
+    assert_eq!(failure.tick, 3);
+    assert!(failure.message.starts_with("tick 3: pump off while the vent is open: does not hold\nrecent ticks:\n  \
+        tick 1 line 11: sensor Pressure = 50, pump On = 1, r2 = 0\n  \
+        tick 2 line 11: sensor Pressure = 50, pump On = 1, r2 = 0\n  \
+        tick 3 line 11: sensor Pressure = 90, pump On = 1, r2 = 1\n"), "{}", failure);
+}
@@ -0,0 +1,268 @@
+use std::fmt::{Formatter, Error};
+
+use crate::wiring::device_named;
+use crate::{execute_until_yields2, CPUContext, CompiledProgram, Device, DeviceAttachment, ExecutionError, InputSource};
+
+/// Something the game world does to the chip's devices between ticks
+#[derive(Clone,Debug)]
+pub enum TimelineEvent
+{
+    Set { device: String, field: String, value: f32 },
+    /// set a field of one of the device's slots, such as what is in it
+    Slot { device: String, slot: usize, field: String, value: f32 },
+    /// plug a device in, replacing whatever was there
+    Attach { device: String, attachment: DeviceAttachment },
+    /// unplug the device, so the program's next access to it faults
+    Detach { device: String },
+    /// from here on, reads of the field take their values from `source`
+    Input { device: String, field: String, source: InputSource },
+    /// plug back in what the last `Detach` of the device took out, as it was
+    Reattach { device: String },
+    /// reads of the field return `value`, or with `None` the value it has now, until `Release`
+    Stuck { device: String, field: String, value: Option<f32> },
+    /// reads of the field go back to normal
+    Release { device: String, field: String },
+    /// the device ignores the program's writes until `KeepWrites`
+    DropWrites { device: String },
+    KeepWrites { device: String },
+}
+
+impl TimelineEvent
+{
+    pub(crate) fn device(&self) -> &str
+    {
+        match self {
+            TimelineEvent::Set { device, .. } | TimelineEvent::Slot { device, .. } | TimelineEvent::Attach { device, .. }
+            | TimelineEvent::Detach { device } | TimelineEvent::Input { device, .. }
+            | TimelineEvent::Reattach { device } | TimelineEvent::Stuck { device, .. }
+            | TimelineEvent::Release { device, .. } | TimelineEvent::DropWrites { device }
+            | TimelineEvent::KeepWrites { device } => device,
+        }
+    }
+
+    pub(crate) fn apply(&self, program:&CompiledProgram, ctx:&mut CPUContext) -> Result<(), ExecutionError>
+    {
+        let dev = device_named(program, self.device()).map_err(|e| ExecutionError::new(&e))?;
+        let pin = |dev:Device| match dev {
+            Device::Regular(idx) => Ok(idx as usize),
+            Device::SpecialB => Err(ExecutionError::new("db is always attached")),
+        };
+        match self {
+            TimelineEvent::Set { field, value, .. } => { ctx.device_reference(dev)?.insert(field.clone(), *value); },
+            TimelineEvent::Slot { slot, field, value, .. } => { ctx.slot_reference(dev, *slot)?.insert(field.clone(), *value); },
+            TimelineEvent::Attach { attachment, .. } => ctx.attach_device(pin(dev)?, attachment.clone())?,
+            TimelineEvent::Detach { .. } => ctx.detach_device(pin(dev)?)?,
+            TimelineEvent::Input { field, source, .. } => ctx.attach_input(dev, field, source.clone()),
+            TimelineEvent::Reattach { .. } => ctx.reattach_device(pin(dev)?)?,
+            TimelineEvent::Stuck { field, value, .. } => {
+                let value = match value {
+                    Some(value) => *value,
+                    None => match ctx.device_reference(dev)?.get(field) {
+                        Some(&value) => value,
+                        None => return Err(ExecutionError::new(&format!("{} has no {}", dev, field))),
+                    },
+                };
+                ctx.stick_field(dev, field, value);
+            },
+            TimelineEvent::Release { field, .. } => ctx.unstick_field(dev, field),
+            TimelineEvent::DropWrites { .. } => ctx.drop_writes(dev, true),
+            TimelineEvent::KeepWrites { .. } => ctx.drop_writes(dev, false),
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for TimelineEvent
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        match self {
+            TimelineEvent::Set { device, field, value } => write!(f, "{} {} = {}", device, field, value),
+            TimelineEvent::Slot { device, slot, field, value } => write!(f, "{} slot {} {} = {}", device, slot, field, value),
+            TimelineEvent::Attach { device, attachment: DeviceAttachment { kind: Some(kind), .. } } =>
+                write!(f, "plug a {} into {}", kind.prefab, device),
+            TimelineEvent::Attach { device, .. } => write!(f, "plug a device into {}", device),
+            TimelineEvent::Detach { device } => write!(f, "unplug {}", device),
+            TimelineEvent::Input { device, field, .. } => write!(f, "{} {} from an input", device, field),
+            TimelineEvent::Reattach { device } => write!(f, "plug {} back in", device),
+            TimelineEvent::Stuck { device, field, value: Some(value) } => write!(f, "{} {} stuck at {}", device, field, value),
+            TimelineEvent::Stuck { device, field, value: None } => write!(f, "{} {} frozen", device, field),
+            TimelineEvent::Release { device, field } => write!(f, "{} {} reads normally", device, field),
+            TimelineEvent::DropWrites { device } => write!(f, "{} ignores writes", device),
+            TimelineEvent::KeepWrites { device } => write!(f, "{} takes writes again", device),
+        }
+    }
+}
+
+/// An event and the tick it happens at
+#[derive(Clone,Debug)]
+pub struct ScheduledEvent
+{
+    /// the event happens once this many ticks have run, before the next one;
+    /// 0 is before the first tick
+    pub tick: u64,
+    pub event: TimelineEvent,
+}
+
+impl std::fmt::Display for ScheduledEvent
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "tick {}: {}", self.tick, self.event)
+    }
+}
+
+/// Why `Timeline::run` stopped short
+pub(crate) enum TimelineError<'a>
+{
+    Event(&'a ScheduledEvent, ExecutionError),
+    Fault(ExecutionError),
+}
+
+//
+
+/// Events scheduled at exact ticks, such as "at tick 10 the pipe pressure drops to 200;
+/// at tick 25 the filter is unplugged; at tick 30 its cartridge runs out":
+///
+/// ```ignore
+/// let leak = Timeline::new()
+///     .set(10, "sensorPipe", "Pressure", 200.0)
+///     .detach(25, "filter")
+///     .slot(30, "filter", 0, "Quantity", 0.0);
+/// ```
+///
+/// Devices are named as in a `Scenario`, by the program's aliases or as `d0`..`d5`, `db`,
+/// and are looked up when the events are applied, so one timeline can drive several programs.
+/// Events at the same tick happen in the order they were added.
+#[derive(Clone,Debug,Default)]
+pub struct Timeline
+{
+    events: Vec<ScheduledEvent>,
+}
+
+impl Timeline
+{
+    pub fn new() -> Timeline
+    {
+        Timeline { events: Vec::new() }
+    }
+
+    pub fn at(mut self, tick:u64, event:TimelineEvent) -> Timeline
+    {
+        self.add(ScheduledEvent { tick, event });
+        self
+    }
+
+    pub fn set(self, tick:u64, device:&str, field:&str, value:f32) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Set { device: device.to_string(), field: field.to_string(), value })
+    }
+
+    pub fn slot(self, tick:u64, device:&str, slot:usize, field:&str, value:f32) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Slot { device: device.to_string(), slot, field: field.to_string(), value })
+    }
+
+    /// `dev` is anything `CPUContext::attach_device` accepts
+    pub fn attach<D:Into<DeviceAttachment>>(self, tick:u64, device:&str, dev:D) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Attach { device: device.to_string(), attachment: dev.into() })
+    }
+
+    pub fn detach(self, tick:u64, device:&str) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Detach { device: device.to_string() })
+    }
+
+    pub fn input(self, tick:u64, device:&str, field:&str, source:InputSource) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Input { device: device.to_string(), field: field.to_string(), source })
+    }
+
+    pub fn reattach(self, tick:u64, device:&str) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Reattach { device: device.to_string() })
+    }
+
+    /// reads of the field keep returning the value it has at `tick`
+    pub fn freeze(self, tick:u64, device:&str, field:&str) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Stuck { device: device.to_string(), field: field.to_string(), value: None })
+    }
+
+    pub fn stuck_at(self, tick:u64, device:&str, field:&str, value:f32) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Stuck { device: device.to_string(), field: field.to_string(), value: Some(value) })
+    }
+
+    pub fn release(self, tick:u64, device:&str, field:&str) -> Timeline
+    {
+        self.at(tick, TimelineEvent::Release { device: device.to_string(), field: field.to_string() })
+    }
+
+    pub fn drop_writes(self, tick:u64, device:&str) -> Timeline
+    {
+        self.at(tick, TimelineEvent::DropWrites { device: device.to_string() })
+    }
+
+    pub fn keep_writes(self, tick:u64, device:&str) -> Timeline
+    {
+        self.at(tick, TimelineEvent::KeepWrites { device: device.to_string() })
+    }
+
+    /// the events of both, `other`'s after this one's at the same tick
+    pub fn merge(mut self, other:&Timeline) -> Timeline
+    {
+        for event in &other.events {
+            self.add(event.clone());
+        }
+        self
+    }
+
+    /// in the order they happen
+    pub fn events(&self) -> &[ScheduledEvent]
+    {
+        &self.events
+    }
+
+    fn add(&mut self, event:ScheduledEvent)
+    {
+        let idx = self.events.partition_point(|e| e.tick <= event.tick);
+        self.events.insert(idx, event);
+    }
+
+    /// Run the program for `ticks` ticks, as `execute_until_yields` does, applying each event
+    /// scheduled from the current tick on just before the tick it is scheduled for.
+    /// Events scheduled for the tick after the last one run are left for the next call.
+    pub fn execute(&self, program:&CompiledProgram, ctx:&mut CPUContext, ticks:u32) -> Result<(), ExecutionError>
+    {
+        let mut next = self.events.partition_point(|e| e.tick < ctx.ticks());
+        self.run(program, ctx, ticks, &mut next, |_| {}, |_| {}).map_err(|e| match e {
+            TimelineError::Event(event, e) => ExecutionError::new(&format!("{} failed: {}", event, e)),
+            TimelineError::Fault(e) => e,
+        })
+    }
+
+    /// Run `ticks` ticks, applying the events from `next` on as they come due and advancing `next`
+    /// past them.  `each_instruction` is called as by `execute_until_yields2`, and `applied`
+    /// with every event after it has been applied.
+    pub(crate) fn run<F, A>(&self, program:&CompiledProgram, ctx:&mut CPUContext, ticks:u32, next:&mut usize,
+                            mut each_instruction:F, mut applied:A) -> Result<(), TimelineError<'_>>
+        where F:FnMut(&mut CPUContext), A:FnMut(&ScheduledEvent)
+    {
+        let end = ctx.ticks() + ticks as u64;
+        while ctx.ticks() < end {
+            while let Some(event) = self.events.get(*next).filter(|e| e.tick <= ctx.ticks()) {
+                event.event.apply(program, ctx).map_err(|e| TimelineError::Event(event, e))?;
+                applied(event);
+                *next += 1;
+            }
+            let until = self.events.get(*next).map_or(end, |e| e.tick.min(end));
+            let before = ctx.ticks();
+            execute_until_yields2(program, ctx, (until - before) as u32, &mut each_instruction)
+                .map_err(TimelineError::Fault)?;
+            if ctx.ticks() == before {
+                break; // ran off the end of the program
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{CPUContext, CompiledProgram, Device, DeviceAttachment, Op, RegisterOrDevice, Strictness};
+
+/// What `Scenario`, `Subroutine` and `Property` have in common: the program, the devices to plug in
+/// before it starts, and the first mistake made in putting them together, which is reported when
+/// the test runs rather than by each builder call.
+#[derive(Clone)]
+pub(crate) struct Wiring
+{
+    pub program: CompiledProgram,
+    pub strictness: Strictness,
+    devices: Vec<(Device, DeviceAttachment)>,
+    problem: Option<String>,
+}
+
+impl Wiring
+{
+    pub fn new(program:CompiledProgram) -> Wiring
+    {
+        Wiring { program, strictness: Strictness::default(), devices: Vec::new(), problem: None }
+    }
+
+    /// the device `name` names, or `None` after complaining
+    pub fn find_device(&mut self, name:&str) -> Option<Device>
+    {
+        match device_named(&self.program, name) {
+            Ok(dev) => Some(dev),
+            Err(message) => {
+                self.complain(message);
+                None
+            }
+        }
+    }
+
+    pub fn device(&mut self, name:&str, dev:DeviceAttachment)
+    {
+        match self.find_device(name) {
+            Some(Device::SpecialB) => self.complain("db is always attached".to_string()),
+            Some(device) => self.devices.push((device, dev)),
+            None => {},
+        }
+    }
+
+    /// keep `message` unless something has already gone wrong
+    pub fn complain(&mut self, message:String)
+    {
+        self.problem.get_or_insert(message);
+    }
+
+    pub fn problem(&self) -> Option<&str>
+    {
+        self.problem.as_deref()
+    }
+
+    /// A fresh context with the devices plugged in, or the first problem
+    pub fn context(&self) -> Result<CPUContext, String>
+    {
+        if let Some(problem) = &self.problem {
+            return Err(problem.clone());
+        }
+        let mut ctx = CPUContext::new_with_strictness(&self.program, self.strictness);
+        for (device, attachment) in &self.devices {
+            if let Device::Regular(idx) = device {
+                ctx.attach_device(*idx as usize, attachment.clone()).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(ctx)
+    }
+}
+
+/// `d0`..`d5`, `db`, or the one device every `alias name` line in the program agrees on
+pub(crate) fn device_named(program:&CompiledProgram, name:&str) -> Result<Device, String>
+{
+    if let Ok(dev) = Device::parse(name) {
+        return Ok(dev);
+    }
+    let mut found: Vec<Device> = Vec::new();
+    for op in program.ops() {
+        if let Op::Alias(alias) = op {
+            if let (true, RegisterOrDevice::Device(dev)) = (alias.handle == name, alias.d_line) {
+                if !found.contains(&dev) {
+                    found.push(dev);
+                }
+            }
+        }
+    }
+    match found.as_slice() {
+        [dev] => Ok(*dev),
+        [] => Err(format!("the program has no alias {} for a device", name)),
+        _ => Err(format!("{} is an alias of more than one device", name)),
+    }
+}
+
+/// Give a failure type a `Debug` that shows the same as its `Display`,
+/// so a test that returns one prints something readable.
+macro_rules! debug_as_display {
+    ($failure:ty) => {
+        impl std::fmt::Debug for $failure
+        {
+            fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+                write!(f, "{}", self)
+            }
+        }
+    };
+}
+pub(crate) use debug_as_display;
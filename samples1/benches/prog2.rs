@@ -0,0 +1,52 @@
+//! Throughput of the greenhouse controller in prog2.mips over a long run.
+//! `cargo bench` runs a million ticks; pass a different count with `cargo bench -- 50000`.
+
+extern crate stationeers_mips_unittest;
+use stationeers_mips_unittest::*;
+
+use std::time::Instant;
+
+static PROG2:&str = include_str!("../src/prog2.mips");
+
+fn main() -> Result<(), ExecutionError>
+{
+    let ticks: u32 = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(1_000_000);
+
+    let program = compile(PROG2).expect("prog2.mips should compile");
+    let mut ctx = CPUContext::new_simple(&program);
+    for pin in &[0, 1, 3, 4, 5] {
+        ctx.attach_device(*pin, DeviceState::new())?;
+    }
+    // create the sensor fields up front, so the timed loop only updates them
+    let sensors = [(0, "Pressure"), (0, "RatioCarbonDioxide"), (1, "Pressure")];
+    for (pin, field) in &sensors {
+        ctx.device_reference(Device::Regular(*pin))?.insert(field.to_string(), 0.0);
+    }
+
+    let start = Instant::now();
+    for tick in 0..ticks {
+        // sweep the sensors so every branch of the controller gets exercised
+        let gh_pressure = 80.0 + (tick % 60) as f32;
+        let co2 = if tick % 7 < 3 { 0.02 } else { 0.2 };
+        let pipe_pressure = if tick % 11 < 5 { 900.0 } else { 4000.0 };
+        for ((pin, field), value) in sensors.iter().zip(&[gh_pressure, co2, pipe_pressure]) {
+            if let Some(old) = ctx.device_reference(Device::Regular(*pin))?.get_mut(*field) {
+                *old = *value;
+            }
+        }
+
+        execute_until_yields(&program, &mut ctx, 1)?;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let instructions = ctx.instructions_executed();
+    println!("prog2.mips: {} ticks, {} instructions in {:.3}s", ticks, instructions, elapsed);
+    println!("  {:.0} ticks/s, {:.1}M instructions/s, {:.1}ns/instruction",
+             ticks as f64 / elapsed,
+             instructions as f64 / elapsed / 1e6,
+             elapsed * 1e9 / instructions as f64);
+    Ok(())
+}
@@ -4,7 +4,6 @@ fn main()
 }
 
 #[cfg(test)]
-
 mod tests
 {
     extern crate stationeers_mips_unittest;
@@ -33,7 +32,7 @@ mod tests
                 ctx.attach_device(4, DeviceState::new())?;
 
                 let max_yields = 8;
-                ctx = execute_until_yields(&program, ctx, max_yields)?;
+                execute_until_yields(&program, &mut ctx, max_yields)?;
                 if false {
                     ctx.debug_dump();
                 }
@@ -48,142 +47,84 @@ mod tests
     /// the system under various combinations of greenhouse
     /// pressure, CO2 concentration, and pipe pressure.
     #[test]
-    fn test_prog2() ->Result<(), ExecutionError>
+    fn test_prog2() ->Result<(), ScenarioFailure>
     {
-
-        fn set_environment(ctx:&mut CPUContext, gh_pressure:f32, gh_co2:f32, pipe_pressure:f32) ->Result<(), ExecutionError>
-        {
-            let gh_sensor = ctx.device_reference(Device::Regular(0))?;
-            gh_sensor.insert("Pressure".to_string(), gh_pressure);
-            gh_sensor.insert("RatioCarbonDioxide".to_string(), gh_co2);
-
-            let pipe_sensor = ctx.device_reference(Device::Regular(1))?;
-            pipe_sensor.insert("Pressure".to_string(), pipe_pressure);
-
-            Ok(())
-        }
-
-        fn check_pumps(ctx:&mut CPUContext, gh_on:bool, atmo_on:bool, filter_on:bool, vent_pressure:f32) ->Result<(), ExecutionError>
+        /// one tick with the given sensor readings, and the pumps it should leave running
+        fn case(scenario:Scenario, (gh_pressure, gh_co2, pipe_pressure):(f32, f32, f32),
+                (gh_on, atmo_on, filter_on):(bool, bool, bool), vent_pressure:f32) -> Scenario
         {
-            fn blargh(ctx:&mut CPUContext, dev_idx:u8) ->Result<f32,ExecutionError>{
-                let rval = ctx.device_reference(Device::Regular(dev_idx))?.get("On").unwrap();
-                println!("d{}.On = {}", dev_idx, rval);
-                Ok(*rval)
-            }
-
-            assert_eq!(blargh(ctx, 3)?, if gh_on {1_f32} else {0_f32}, "wrong GH pump");
-            assert_eq!(blargh(ctx, 4)?, if atmo_on {1_f32} else {0_f32}, "wrong atmo pump");
-            assert_eq!(blargh(ctx, 5)?, if filter_on {1_f32} else {0_f32}, "wrong filter");
-
+            let on = |b:bool| if b {1.0} else {0.0};
+            let scenario = scenario
+                .set("sensorGH", "Pressure", gh_pressure)
+                .set("sensorGH", "RatioCarbonDioxide", gh_co2)
+                .set("sensorPipe", "Pressure", pipe_pressure)
+                .tick()
+                .expect_value("wantGHPump", on(gh_on))
+                .expect("pumpGH", "On", on(gh_on))
+                .expect("pumpAtmo", "On", on(atmo_on))
+                .expect("filter", "On", on(filter_on));
             if gh_on {
-                assert_eq!(vent_pressure, ctx.get_device_field(3, "PressureExternal")?, "wrong vent pressure");
+                scenario.expect("pumpGH", "PressureExternal", vent_pressure)
+            } else {
+                scenario
             }
-            Ok(())
         }
 
-        let prog1 = compile(PROG2);
-        match prog1 {
-            Err(err) => {
-                println!("compile fail {}", err.message);
-                assert!(false, "failed to compile");
-            },
-            Ok(program) => {
-                let mut ctx: CPUContext = CPUContext::new_simple(&program);
-
-                ctx.attach_device(0, DeviceState::new())?;
-                ctx.attach_device(1, DeviceState::new())?;
-                ctx.attach_device(3, DeviceState::new())?;
-                ctx.attach_device(4, DeviceState::new())?;
-                ctx.attach_device(5, DeviceState::new())?;
-
-                {
-                    set_environment(&mut ctx, 90., 0.02, 900.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, false, true, true, 108.)?;
-                }
-
-                {
-                    set_environment(&mut ctx, 90., 0.02, 4000.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, false, false, true, 108.)?;
-                }
-
-                {
-                    set_environment(&mut ctx, 125., 0.02, 900.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, true, true, true, 108.)?;
-                }
-
-                {
-                    set_environment(&mut ctx, 125., 0.02, 4000.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, true, false, true, 108.)?;
-                }
-
-                //
-
-                {
-                    set_environment(&mut ctx, 90., 0.2, 900.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, false, true, true, 130.)?;
-                }
-
-                {
-                    set_environment(&mut ctx, 90., 0.2, 4000.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, false, false, true, 130.)?;
-                }
-
-                {
-                    set_environment(&mut ctx, 125., 0.2, 900.0)?;
-                    ctx = execute_until_yields2(&program, ctx, 1,
-                                                                    |_ctx| {
-                                                                        /*if ctx.instruction_pointer >16 && ctx.instruction_pointer < 21 {
-                                                                            ctx.debug_dump();
-                                                                        }*/
-                                                                    }
-                    )?;
-                    check_pumps(&mut ctx, false, true, false, -1.)?;
-                }
-
-                {
-                    set_environment(&mut ctx, 125., 0.2, 4000.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, false, false, false, -1.)?;
-                }
-
-                //
-
-                {
-                    set_environment(&mut ctx, 132., 0.2, 4000.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, true, false, false, 130.)?;
-                }
+        let mut scenario = Scenario::compile(PROG2)?;
+        for device in &["sensorGH", "sensorPipe", "pumpGH", "pumpAtmo", "filter"] {
+            scenario = scenario.device(device, DeviceState::new());
+        }
 
-                {
-                    set_environment(&mut ctx, 132., 0.03, 4000.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, true, false, true, 108.)?;
-                }
+        let scenario = case(scenario, (90., 0.02, 900.0), (false, true, true), 108.);
+        let scenario = case(scenario, (90., 0.02, 4000.0), (false, false, true), 108.);
+        let scenario = case(scenario, (125., 0.02, 900.0), (true, true, true), 108.);
+        let scenario = case(scenario, (125., 0.02, 4000.0), (true, false, true), 108.);
 
-                {
-                    set_environment(&mut ctx, 132., 0.2, 200.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, true, true, false, 130.)?;
-                }
+        let scenario = case(scenario, (90., 0.2, 900.0), (false, true, true), 130.);
+        let scenario = case(scenario, (90., 0.2, 4000.0), (false, false, true), 130.);
+        let scenario = case(scenario, (125., 0.2, 900.0), (false, true, false), -1.);
+        let scenario = case(scenario, (125., 0.2, 4000.0), (false, false, false), -1.);
 
-                {
-                    set_environment(&mut ctx, 132., 0.03, 200.0)?;
-                    ctx = execute_until_yields(&program, ctx, 1)?;
-                    check_pumps(&mut ctx, true, true, true, 108.)?;
-                }
+        let scenario = case(scenario, (132., 0.2, 4000.0), (true, false, false), 130.);
+        let scenario = case(scenario, (132., 0.03, 4000.0), (true, false, true), 108.);
+        let scenario = case(scenario, (132., 0.2, 200.0), (true, true, false), 130.);
+        let scenario = case(scenario, (132., 0.03, 200.0), (true, true, true), 108.);
 
+        scenario.run()?;
+        Ok(())
+    }
 
-                //ctx.debug_dump();
-            }
+    /// The same rules as `test_prog2`, checked against random sensor readings
+    /// instead of the twelve combinations above.
+    #[test]
+    fn test_prog2_properties() ->Result<(), PropertyFailure>
+    {
+        let mut property = Property::compile(PROG2)?;
+        for device in &["sensorGH", "sensorPipe", "pumpGH", "pumpAtmo", "filter"] {
+            property = property.device(device, DeviceState::new());
         }
-
-        Ok(())
+        let field = |ctx:&CPUContext, device, field| ctx.device_field(device, field).unwrap();
+        let on = move |ctx:&CPUContext, device| field(ctx, device, "On") != 0.0;
+
+        property
+            .input("sensorGH", "Pressure", 50.0..=200.0)
+            .input("sensorGH", "RatioCarbonDioxide", 0.0..=0.3)
+            .input("sensorPipe", "Pressure", 0.0..=6000.0)
+            .invariant("filter on whenever CO2 is below target", move |ctx| {
+                field(ctx, "sensorGH", "RatioCarbonDioxide") >= 0.1 || on(ctx, "filter")
+            })
+            .invariant("greenhouse pump off at or below target pressure", move |ctx| {
+                field(ctx, "sensorGH", "Pressure") > 108.0 || !on(ctx, "pumpGH")
+            })
+            .invariant("atmosphere pump on exactly when the pipe is below 1000", move |ctx| {
+                on(ctx, "pumpAtmo") == (field(ctx, "sensorPipe", "Pressure") < 1000.0)
+            })
+            .invariant("vent pressure 108 with low CO2, else 130", move |ctx| {
+                let low_co2 = field(ctx, "sensorGH", "RatioCarbonDioxide") < 0.1;
+                field(ctx, "pumpGH", "PressureExternal") == if low_co2 { 108.0 } else { 130.0 }
+            })
+            .cases(500)
+            .run()
     }
 
 }